@@ -1,4 +1,13 @@
-use vm::opcode::instructions::Instr;
+use chumsky::{error::Simple, prelude::end, text::TextParser, Parser};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+};
+use vm::{
+    arithmetic::ArithmeticMode, opcode::instructions::Instr, parsing::describe_error, Reg, VmError,
+    VM,
+};
 
 pub mod parser;
 
@@ -6,90 +15,1079 @@ type Int = i16;
 
 #[derive(Debug, Eq, Clone, PartialEq)]
 pub enum Ast {
-    Let { ident: String, value: Expr },
+    Let {
+        ident: String,
+        value: Expr,
+    },
+    Expr(Expr),
+    /// `print x`, which writes the value with `OUT`.
+    Print(Expr),
+    /// `fn name(a, b) = body`, callable from anywhere in the program. The body only sees its
+    /// parameters.
+    Func {
+        name: String,
+        params: Vec<String>,
+        body: Expr,
+    },
+}
+
+#[derive(Debug, Eq, Clone, Copy, PartialEq)]
+pub enum CmpOp {
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
 }
 
 #[derive(Debug, Eq, Clone, PartialEq)]
 pub enum Expr {
     Int(Int),
+    Var(String),
     Negate(Box<Expr>),
 
     Add(Box<Expr>, Box<Expr>),
     Sub(Box<Expr>, Box<Expr>),
     Mul(Box<Expr>, Box<Expr>),
     Div(Box<Expr>, Box<Expr>),
+    Mod(Box<Expr>, Box<Expr>),
     Pow(Box<Expr>, Box<Expr>),
+
+    BitAnd(Box<Expr>, Box<Expr>),
+    BitOr(Box<Expr>, Box<Expr>),
+    BitXor(Box<Expr>, Box<Expr>),
+    /// Shifts use the low 6 bits of the amount, and `>>` shifts in zeroes, like `SHL`/`SHR`.
+    Shl(Box<Expr>, Box<Expr>),
+    Shr(Box<Expr>, Box<Expr>),
+
+    /// A chain like `a < b <= c`, which holds if every adjacent pair does. Evaluates to 1 or 0.
+    Compare(Box<Expr>, Vec<(CmpOp, Expr)>),
+
+    /// `a && b` and `a || b`, which are 1 or 0 depending on whether the operands are nonzero.
+    /// The right operand is only evaluated if the left doesn't already decide the result.
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+
+    /// `cond ? then : else_`, which is `then` if `cond` is nonzero. Both branches are
+    /// evaluated, unless either calls a function, so that recursion can stop.
+    Select {
+        cond: Box<Expr>,
+        then: Box<Expr>,
+        else_: Box<Expr>,
+    },
+
+    /// `{ x = 2; y = 3; x + y }`, whose bindings are only visible inside the braces.
+    Block {
+        lets: Vec<(String, Expr)>,
+        value: Box<Expr>,
+    },
+
+    /// `f(a, b)`, calling a function defined with `Ast::Func`.
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    UnknownVariable(String),
+    OutOfRegisters,
+    /// A division or modulo whose divisor is always zero, in the statement at this index.
+    DivByZero {
+        statement: usize,
+    },
+    /// The code is too long for a jump target, like the end of a `&&`, to fit in a `LOAD`
+    /// immediate.
+    JumpOutOfRange,
+    UnknownFunction(String),
+    DuplicateFunction(String),
+    WrongArgumentCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::UnknownVariable(name) => write!(f, "unknown variable `{}`", name),
+            CompileError::OutOfRegisters => write!(f, "expression needs more than 256 registers"),
+            CompileError::DivByZero { statement } => {
+                write!(f, "division by zero in statement {}", statement)
+            }
+            CompileError::JumpOutOfRange => write!(f, "program too long to jump across"),
+            CompileError::UnknownFunction(name) => write!(f, "unknown function `{}`", name),
+            CompileError::DuplicateFunction(name) => {
+                write!(f, "function `{}` is defined more than once", name)
+            }
+            CompileError::WrongArgumentCount {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`{}` takes {} arguments but was given {}",
+                name, expected, found
+            ),
+        }
+    }
 }
 
-pub fn compile_expr(expr: Expr, mut next_register: u8) -> Vec<Instr> {
+impl Error for CompileError {}
+
+/// Anything that can go wrong in `run_source`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunError {
+    Parse(Vec<Simple<char>>),
+    Compile(CompileError),
+    Vm(VmError),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Parse(errs) => {
+                let errs: Vec<_> = errs.iter().map(describe_error).collect();
+                write!(f, "{}", errs.join("\n"))
+            }
+            RunError::Compile(err) => write!(f, "{}", err),
+            RunError::Vm(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for RunError {}
+
+impl From<CompileError> for RunError {
+    fn from(err: CompileError) -> Self {
+        RunError::Compile(err)
+    }
+}
+
+impl From<VmError> for RunError {
+    fn from(err: VmError) -> Self {
+        RunError::Vm(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compiled {
+    pub instrs: Vec<Instr>,
+    /// The register holding the value of the final statement, if that was an expression.
+    pub result: Option<u8>,
+}
+
+/// How a comparison is turned into a 1 or 0. The condition of a `?:` always sets the flag
+/// instead, since that's what `CMOV` reads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Comparisons {
+    /// `SEQ`, `SGT` and `SLT`, which write straight to a register. `<=`, `>=` and `!=` need
+    /// another two instructions and a scratch register to invert the result.
+    #[default]
+    SetRegister,
+    /// `EQ`, `GT` or `GTQ` to set the flag, then `GETF` to read it.
+    Flag,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// Variables that already live in these registers, see `compile_program_pinned`.
+    pub pinned: HashMap<String, u8>,
+    pub comparisons: Comparisons,
+}
+
+/// The variables in scope, the registers that are never handed out as scratch space because
+/// something outside the compiled code owns them, how to lower comparisons, and the functions
+/// that can be called.
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    vars: HashMap<String, u8>,
+    reserved: HashSet<u8>,
+    comparisons: Comparisons,
+    /// Each function's index in the program, and how many arguments it takes.
+    funcs: HashMap<String, (Int, usize)>,
+}
+
+impl Scope {
+    fn get(&self, name: &str) -> Result<u8, CompileError> {
+        self.vars
+            .get(name)
+            .copied()
+            .ok_or_else(|| CompileError::UnknownVariable(name.to_string()))
+    }
+
+    /// The first register from `register` up that isn't reserved.
+    fn free(&self, mut register: u8) -> Result<u8, CompileError> {
+        while self.reserved.contains(&register) {
+            register = register
+                .checked_add(1)
+                .ok_or(CompileError::OutOfRegisters)?;
+        }
+        Ok(register)
+    }
+
+    /// The first register above `register` that isn't reserved.
+    fn next(&self, register: u8) -> Result<u8, CompileError> {
+        self.free(
+            register
+                .checked_add(1)
+                .ok_or(CompileError::OutOfRegisters)?,
+        )
+    }
+}
+
+/// Compiles `expr` so that its value ends up in `next_register`, using the registers above it
+/// as scratch space. Jumps are to offsets from the start of the code, see `vm::passes::relocate`
+/// for running it from anywhere else.
+pub fn compile_expr(expr: Expr, next_register: u8) -> Result<Vec<Instr>, CompileError> {
+    if divides_by_zero(&expr) {
+        return Err(CompileError::DivByZero { statement: 0 });
+    }
     let mut results = vec![];
+    compile(&expr, next_register, &Scope::default(), &mut results)?;
+    Ok(results)
+}
+
+/// Compiles a sequence of statements. Each `let` keeps its value in the next free register, and
+/// later statements only use the registers above that.
+pub fn compile_program(program: &[Ast]) -> Result<Compiled, CompileError> {
+    compile_program_pinned(program, &HashMap::new())
+}
+
+/// Like `compile_program`, but with the variables in `pinned` already living in the given
+/// registers, e.g. put there by hand-written assembly. Those registers are only ever read, and
+/// are skipped over when allocating everything else.
+pub fn compile_program_pinned(
+    program: &[Ast],
+    pinned: &HashMap<String, u8>,
+) -> Result<Compiled, CompileError> {
+    let options = CompileOptions {
+        pinned: pinned.clone(),
+        ..Default::default()
+    };
+    compile_program_with(program, &options)
+}
+
+/// Like `compile_program`, with everything that can be configured.
+pub fn compile_program_with(
+    program: &[Ast],
+    options: &CompileOptions,
+) -> Result<Compiled, CompileError> {
+    let mut vars = Scope {
+        vars: options.pinned.clone(),
+        reserved: options.pinned.values().copied().collect(),
+        comparisons: options.comparisons,
+        funcs: HashMap::new(),
+    };
+    let mut next_register = vars.free(0)?;
+    let mut compiled = Compiled {
+        instrs: vec![],
+        result: None,
+    };
+
+    // Functions can be called from before their definition, so they're all found first.
+    let mut funcs = vec![];
+    for stmt in program {
+        if let Ast::Func { name, params, body } = stmt {
+            let index = Int::try_from(funcs.len()).map_err(|_| CompileError::JumpOutOfRange)?;
+            if vars
+                .funcs
+                .insert(name.clone(), (index, params.len()))
+                .is_some()
+            {
+                return Err(CompileError::DuplicateFunction(name.clone()));
+            }
+            funcs.push((params, body));
+        }
+    }
+
+    for (i, stmt) in program.iter().enumerate() {
+        let (Ast::Let { value: expr, .. }
+        | Ast::Expr(expr)
+        | Ast::Print(expr)
+        | Ast::Func { body: expr, .. }) = stmt;
+        if divides_by_zero(expr) {
+            return Err(CompileError::DivByZero { statement: i });
+        }
+    }
+
+    for stmt in program {
+        match stmt {
+            Ast::Let { ident, value } => {
+                let reg = compile(value, next_register, &vars, &mut compiled.instrs)?;
+                if reg == next_register {
+                    next_register = vars.next(next_register)?;
+                }
+                vars.vars.insert(ident.clone(), reg);
+                compiled.result = None;
+            }
+            Ast::Expr(expr) => {
+                let reg = compile(expr, next_register, &vars, &mut compiled.instrs)?;
+                compiled.result = Some(reg);
+            }
+            Ast::Print(expr) => {
+                let reg = compile(expr, next_register, &vars, &mut compiled.instrs)?;
+                compiled.instrs.push(Instr::Out(reg));
+                compiled.result = None;
+            }
+            Ast::Func { .. } => compiled.result = None,
+        }
+    }
+
+    if !funcs.is_empty() {
+        // The functions go after everything else, which jumps over them once it's done.
+        let scratch = vars.next(next_register)?;
+        let to_end = compiled.instrs.len();
+        compiled.instrs.push(Instr::Load(scratch, 0));
+        compiled.instrs.push(Instr::Jump(scratch));
+
+        let scope = Scope {
+            vars: options.pinned.clone(),
+            ..vars
+        };
+        let mut entries = vec![];
+        for (params, body) in funcs {
+            entries.push(offset(&compiled.instrs)?);
+            compile_func(params, body, &scope, &mut compiled.instrs)?;
+        }
+        compiled.instrs[to_end] = Instr::Load(scratch, offset(&compiled.instrs)?);
+        link_calls(&mut compiled.instrs, &entries);
+    }
+
+    Ok(compiled)
+}
+
+/// Emits a function's body. Its arguments are popped into registers of its own, and the result
+/// is pushed for the caller before returning, see `Expr::Call` in `compile`.
+fn compile_func(
+    params: &[String],
+    body: &Expr,
+    scope: &Scope,
+    results: &mut Vec<Instr>,
+) -> Result<(), CompileError> {
+    let mut scope = scope.clone();
+    let mut next_register = scope.free(0)?;
+    let mut registers = vec![];
+    for param in params {
+        scope.vars.insert(param.clone(), next_register);
+        registers.push(next_register);
+        next_register = scope.next(next_register)?;
+    }
+
+    results.extend(registers.iter().rev().map(|&r| Instr::Pop(r)));
+    let result = compile(body, next_register, &scope, results)?;
+    results.push(Instr::Push(result));
+    results.push(Instr::Return);
+    Ok(())
+}
+
+/// Points each `CALL` at its function. Until now the `LOAD` just before it has the function's
+/// index into `entries`, which holds where each one starts.
+fn link_calls(instrs: &mut [Instr], entries: &[Int]) {
+    for i in 1..instrs.len() {
+        if let (Instr::Load(r, index), Instr::Call(target)) = (instrs[i - 1], instrs[i]) {
+            if r == target {
+                instrs[i - 1] = Instr::Load(r, entries[index as usize]);
+            }
+        }
+    }
+}
+
+/// The byte offset just past `instrs`, as a jump target.
+fn offset(instrs: &[Instr]) -> Result<Int, CompileError> {
+    let len: usize = instrs.iter().map(|i| i.to_bytes().len()).sum();
+    Int::try_from(len).map_err(|_| CompileError::JumpOutOfRange)
+}
+
+/// Emits code for `expr` and returns the register holding its value. That is `next_register`,
+/// unless `expr` is a variable, which is read from wherever it already lives.
+fn compile(
+    expr: &Expr,
+    next_register: u8,
+    vars: &Scope,
+    results: &mut Vec<Instr>,
+) -> Result<u8, CompileError> {
+    let next = |register| vars.next(register);
+    let binop = |a: &Expr, b: &Expr, op: fn(u8, u8, u8) -> Instr, results: &mut Vec<Instr>| {
+        let a = compile(a, next_register, vars, results)?;
+        let b = compile(b, next(next_register)?, vars, results)?;
+        results.push(op(a, b, next_register));
+        Ok::<_, CompileError>(())
+    };
 
     match expr {
         Expr::Int(x) => {
-            results.push(Instr::Load(next_register, x));
+            results.push(Instr::Load(next_register, *x));
         }
+        Expr::Var(name) => return vars.get(name),
         Expr::Negate(x) => {
-            results.append(&mut compile_expr(*x, next_register));
-            results.push(Instr::Load(next_register + 1, -1));
-            results.push(Instr::Multiply(
-                next_register,
-                next_register + 1,
-                next_register,
-            ));
-        }
-        Expr::Add(a, b) => {
-            results.append(&mut compile_expr(*a, next_register));
-            next_register += 1;
-            results.append(&mut compile_expr(*b, next_register));
-            results.push(Instr::Add(
-                next_register - 1,
-                next_register,
-                next_register - 1,
-            ))
-        }
-        Expr::Sub(a, b) => {
-            results.append(&mut compile_expr(*a, next_register));
-            next_register += 1;
-            results.append(&mut compile_expr(*b, next_register));
-            results.push(Instr::Subtract(
-                next_register - 1,
-                next_register,
-                next_register - 1,
-            ))
-        }
-        Expr::Mul(a, b) => {
-            results.append(&mut compile_expr(*a, next_register));
-            next_register += 1;
-            results.append(&mut compile_expr(*b, next_register));
-            results.push(Instr::Multiply(
-                next_register - 1,
-                next_register,
-                next_register - 1,
-            ))
-        }
-        Expr::Div(a, b) => {
-            results.append(&mut compile_expr(*a, next_register));
-            next_register += 1;
-            results.append(&mut compile_expr(*b, next_register));
-            results.push(Instr::Divide(
-                next_register - 1,
-                next_register,
-                next_register - 1,
-            ))
-        }
-        Expr::Pow(a, b) => {
-            results.append(&mut compile_expr(*a, next_register));
-            next_register += 1;
-            results.append(&mut compile_expr(*b, next_register));
-            results.push(Instr::Power(
-                next_register - 1,
-                next_register,
-                next_register - 1,
-            ))
-        }
-    }
-
-    results
+            let x = compile(x, next_register, vars, results)?;
+            let minus_one = next(next_register)?;
+            results.push(Instr::Load(minus_one, -1));
+            results.push(Instr::Multiply(x, minus_one, next_register));
+        }
+        Expr::Add(a, b) => binop(a, b, Instr::Add, results)?,
+        Expr::Sub(a, b) => binop(a, b, Instr::Subtract, results)?,
+        Expr::Mul(a, b) => binop(a, b, Instr::Multiply, results)?,
+        Expr::Div(a, b) => binop(a, b, Instr::Divide, results)?,
+        Expr::Mod(a, b) => {
+            binop(a, b, Instr::Divide, results)?;
+            results.push(Instr::ReadRemainder(next_register));
+        }
+        Expr::Pow(a, b) => binop(a, b, Instr::Power, results)?,
+        Expr::BitAnd(a, b) => binop(a, b, Instr::And, results)?,
+        Expr::BitOr(a, b) => binop(a, b, Instr::Or, results)?,
+        Expr::BitXor(a, b) => binop(a, b, Instr::Xor, results)?,
+        Expr::Shl(a, b) => binop(a, b, Instr::Shl, results)?,
+        Expr::Shr(a, b) => binop(a, b, Instr::Shr, results)?,
+        Expr::Compare(first, rest) => compile_chain(first, rest, next_register, vars, results)?,
+        Expr::Select { cond, then, else_ } if calls(then) || calls(else_) => {
+            compile_jumping_select(cond, then, else_, next_register, vars, results)?
+        }
+        Expr::Select { cond, then, else_ } => {
+            // Both branches are computed first, since the comparisons inside them would clobber
+            // the flag, and then the condition sets the flag for CMOV to pick between them.
+            let then = compile(then, next_register, vars, results)?;
+            let else_ = compile(else_, next(next_register)?, vars, results)?;
+            set_flag(cond, next(next(next_register)?)?, vars, results)?;
+            results.push(Instr::ConditionalMove(then, else_, next_register));
+        }
+        Expr::And(a, b) | Expr::Or(a, b) => {
+            // `a` is turned into a 1 or 0 in `next_register`. If that already decides the
+            // result, jump past `b`, otherwise the result is `b` turned into a 1 or 0.
+            let zero = next(next_register)?;
+            let is_true = |x, zero, results: &mut Vec<Instr>| {
+                results.push(Instr::Load(zero, 0));
+                results.push(Instr::SetEqual(x, zero, next_register));
+                results.push(Instr::SetEqual(next_register, zero, next_register));
+            };
+
+            let a = compile(a, next_register, vars, results)?;
+            is_true(a, zero, results);
+            results.push(match expr {
+                Expr::And(..) => Instr::Equal(next_register, zero),
+                _ => Instr::GreaterThan(next_register, zero),
+            });
+            let target = results.len();
+            results.push(Instr::Load(zero, 0));
+            results.push(Instr::JumpIf(zero));
+
+            let b = compile(b, zero, vars, results)?;
+            is_true(b, next(zero)?, results);
+            results[target] = Instr::Load(zero, offset(results)?);
+        }
+        Expr::Block { lets, value } => compile_block(lets, value, next_register, vars, results)?,
+        Expr::Call(name, args) => compile_call(name, args, next_register, vars, results)?,
+    }
+
+    Ok(next_register)
+}
+
+/// A chain of comparisons like `a < b <= c`, which is 1 if every one of them holds. Each operand
+/// is evaluated once, into the register after the previous one. The first comparison's result
+/// lands in `next_register`, and later ones go in the register of their (now used up) left
+/// operand and are ANDed in by multiplying.
+fn compile_chain(
+    first: &Expr,
+    rest: &[(CmpOp, Expr)],
+    next_register: u8,
+    vars: &Scope,
+    results: &mut Vec<Instr>,
+) -> Result<(), CompileError> {
+    let next = |register| vars.next(register);
+    let mut lhs = compile(first, next_register, vars, results)?;
+    let mut slot = next_register;
+    for (i, (op, rhs)) in rest.iter().enumerate() {
+        let prev = slot;
+        slot = next(slot)?;
+        let rhs = compile(rhs, slot, vars, results)?;
+        let dest = if i == 0 { next_register } else { prev };
+        match vars.comparisons {
+            Comparisons::Flag => {
+                compare(*op, lhs, rhs, results);
+                results.push(Instr::GetFlag(dest));
+            }
+            Comparisons::SetRegister => set_compare(*op, lhs, rhs, dest, next(slot)?, results),
+        }
+        if i > 0 {
+            results.push(Instr::Multiply(next_register, prev, next_register));
+        }
+        lhs = rhs;
+    }
+    Ok(())
+}
+
+/// A block's bindings take registers from `next_register` up, like in `compile_program`, so the
+/// value is computed above them and then copied down. A CMOV with the same register as both
+/// sources copies it whatever the flag is.
+fn compile_block(
+    lets: &[(String, Expr)],
+    value: &Expr,
+    next_register: u8,
+    vars: &Scope,
+    results: &mut Vec<Instr>,
+) -> Result<(), CompileError> {
+    let next = |register| vars.next(register);
+    let mut scope = vars.clone();
+    let mut slot = next_register;
+    for (ident, value) in lets {
+        let reg = compile(value, slot, &scope, results)?;
+        if reg == slot {
+            slot = next(slot)?;
+        }
+        scope.vars.insert(ident.clone(), reg);
+    }
+    let value = compile(value, slot, &scope, results)?;
+    if value != next_register {
+        results.push(Instr::ConditionalMove(value, value, next_register));
+    }
+    Ok(())
+}
+
+/// A `?:` that jumps to the branch that's wanted rather than running both, since a branch
+/// that calls a function could recurse forever. A CMOV with the same register as both sources
+/// copies it.
+fn compile_jumping_select(
+    cond: &Expr,
+    then: &Expr,
+    else_: &Expr,
+    next_register: u8,
+    vars: &Scope,
+    results: &mut Vec<Instr>,
+) -> Result<(), CompileError> {
+    set_flag(cond, next_register, vars, results)?;
+    results.push(Instr::Not);
+    let to_else = results.len();
+    results.push(Instr::Load(next_register, 0));
+    results.push(Instr::JumpIf(next_register));
+
+    let then = compile(then, next_register, vars, results)?;
+    results.push(Instr::ConditionalMove(then, then, next_register));
+    let to_end = results.len();
+    let scratch = vars.next(next_register)?;
+    results.push(Instr::Load(scratch, 0));
+    results.push(Instr::Jump(scratch));
+
+    results[to_else] = Instr::Load(next_register, offset(results)?);
+    let else_ = compile(else_, next_register, vars, results)?;
+    results.push(Instr::ConditionalMove(else_, else_, next_register));
+    results[to_end] = Instr::Load(scratch, offset(results)?);
+    Ok(())
+}
+
+/// Calls a function, leaving its result in `next_register`. The function is free to use any
+/// register, so everything below `next_register` is saved on the stack around the call. The
+/// arguments are pushed after it, and the result comes back on top.
+fn compile_call(
+    name: &str,
+    args: &[Expr],
+    next_register: u8,
+    vars: &Scope,
+    results: &mut Vec<Instr>,
+) -> Result<(), CompileError> {
+    let &(index, arity) = vars
+        .funcs
+        .get(name)
+        .ok_or_else(|| CompileError::UnknownFunction(name.to_string()))?;
+    if args.len() != arity {
+        return Err(CompileError::WrongArgumentCount {
+            name: name.to_string(),
+            expected: arity,
+            found: args.len(),
+        });
+    }
+
+    let saved: Vec<u8> = (0..next_register)
+        .filter(|r| !vars.reserved.contains(r))
+        .collect();
+    results.extend(saved.iter().map(|&r| Instr::Push(r)));
+    for arg in args {
+        let arg = compile(arg, next_register, vars, results)?;
+        results.push(Instr::Push(arg));
+    }
+    results.push(Instr::Load(next_register, index));
+    results.push(Instr::Call(next_register));
+    results.push(Instr::Pop(next_register));
+    results.extend(saved.iter().rev().map(|&r| Instr::Pop(r)));
+    Ok(())
+}
+
+/// Sets the flag to whether `cond` is nonzero, using the registers from `slot` up. A single
+/// comparison sets it directly, anything else is compared against zero.
+fn set_flag(
+    cond: &Expr,
+    slot: u8,
+    vars: &Scope,
+    results: &mut Vec<Instr>,
+) -> Result<(), CompileError> {
+    let next = |register| vars.next(register);
+    match cond {
+        Expr::Compare(lhs, rest) if rest.len() == 1 => {
+            let (op, rhs) = &rest[0];
+            let lhs = compile(lhs, slot, vars, results)?;
+            let rhs = compile(rhs, next(slot)?, vars, results)?;
+            compare(*op, lhs, rhs, results);
+        }
+        cond => {
+            let cond = compile(cond, slot, vars, results)?;
+            results.push(Instr::Load(next(slot)?, 0));
+            compare(CmpOp::Ne, cond, next(slot)?, results);
+        }
+    }
+    Ok(())
+}
+
+/// Whether evaluating `expr` might call a function.
+fn calls(expr: &Expr) -> bool {
+    match expr {
+        Expr::Int(_) | Expr::Var(_) => false,
+        Expr::Call(..) => true,
+        Expr::Negate(x) => calls(x),
+        Expr::Add(a, b)
+        | Expr::Sub(a, b)
+        | Expr::Mul(a, b)
+        | Expr::Div(a, b)
+        | Expr::Mod(a, b)
+        | Expr::Pow(a, b)
+        | Expr::BitAnd(a, b)
+        | Expr::BitOr(a, b)
+        | Expr::BitXor(a, b)
+        | Expr::Shl(a, b)
+        | Expr::Shr(a, b)
+        | Expr::And(a, b)
+        | Expr::Or(a, b) => calls(a) || calls(b),
+        Expr::Compare(first, rest) => calls(first) || rest.iter().any(|(_, rhs)| calls(rhs)),
+        Expr::Select { cond, then, else_ } => calls(cond) || calls(then) || calls(else_),
+        Expr::Block { lets, value } => lets.iter().any(|(_, value)| calls(value)) || calls(value),
+    }
+}
+
+/// The highest register `compile_expr(expr, 0)` writes to, saturating at `u8::MAX` for
+/// expressions too deep to compile at all.
+pub fn peak_registers(expr: &Expr) -> u8 {
+    fn peak(expr: &Expr, next_register: usize) -> usize {
+        match expr {
+            Expr::Int(_) | Expr::Var(_) => next_register,
+            Expr::Negate(x) => peak(x, next_register).max(next_register + 1),
+            Expr::Add(a, b)
+            | Expr::Sub(a, b)
+            | Expr::Mul(a, b)
+            | Expr::Div(a, b)
+            | Expr::Mod(a, b)
+            | Expr::Pow(a, b)
+            | Expr::BitAnd(a, b)
+            | Expr::BitOr(a, b)
+            | Expr::BitXor(a, b)
+            | Expr::Shl(a, b)
+            | Expr::Shr(a, b) => peak(a, next_register).max(peak(b, next_register + 1)),
+            Expr::Compare(first, rest) => rest
+                .iter()
+                .enumerate()
+                .map(|(i, (op, rhs))| {
+                    let inverted = matches!(op, CmpOp::Le | CmpOp::Ge | CmpOp::Ne);
+                    peak(rhs, next_register + 1 + i).max(next_register + 1 + i + inverted as usize)
+                })
+                .fold(peak(first, next_register), usize::max),
+            Expr::And(a, b) | Expr::Or(a, b) => peak(a, next_register)
+                .max(peak(b, next_register + 1))
+                .max(next_register + 2),
+            Expr::Select { cond, then, else_ } => {
+                let slot = next_register + 2;
+                let cond = match &**cond {
+                    Expr::Compare(lhs, rest) if rest.len() == 1 => {
+                        peak(lhs, slot).max(peak(&rest[0].1, slot + 1))
+                    }
+                    cond => peak(cond, slot).max(slot + 1),
+                };
+                peak(then, next_register)
+                    .max(peak(else_, next_register + 1))
+                    .max(cond)
+            }
+            Expr::Block { lets, value } => {
+                let mut slot = next_register;
+                let mut highest = next_register;
+                for (_, value) in lets {
+                    highest = highest.max(peak(value, slot));
+                    if !matches!(value, Expr::Var(_)) {
+                        slot += 1;
+                    }
+                }
+                highest.max(peak(value, slot))
+            }
+            Expr::Call(_, args) => args
+                .iter()
+                .map(|arg| peak(arg, next_register))
+                .fold(next_register, usize::max),
+        }
+    }
+
+    peak(expr, 0).min(u8::MAX as usize) as u8
+}
+
+/// Whether `expr` contains a division or modulo whose divisor folds to zero. Divisors that
+/// depend on a variable are left for the VM to catch at runtime.
+fn divides_by_zero(expr: &Expr) -> bool {
+    match expr {
+        Expr::Int(_) | Expr::Var(_) => false,
+        Expr::Negate(x) => divides_by_zero(x),
+        Expr::Div(a, b) | Expr::Mod(a, b) => {
+            fold(b) == Some(0) || divides_by_zero(a) || divides_by_zero(b)
+        }
+        Expr::Add(a, b)
+        | Expr::Sub(a, b)
+        | Expr::Mul(a, b)
+        | Expr::Pow(a, b)
+        | Expr::BitAnd(a, b)
+        | Expr::BitOr(a, b)
+        | Expr::BitXor(a, b)
+        | Expr::Shl(a, b)
+        | Expr::Shr(a, b) => divides_by_zero(a) || divides_by_zero(b),
+        Expr::Compare(first, rest) => {
+            divides_by_zero(first) || rest.iter().any(|(_, rhs)| divides_by_zero(rhs))
+        }
+        // The right operand doesn't run when the left one decides the result.
+        Expr::And(a, b) => divides_by_zero(a) || (fold(a) != Some(0) && divides_by_zero(b)),
+        Expr::Or(a, b) => {
+            divides_by_zero(a) || (fold(a).is_none_or(|a| a == 0) && divides_by_zero(b))
+        }
+        Expr::Select { cond, then, else_ } => {
+            divides_by_zero(cond) || divides_by_zero(then) || divides_by_zero(else_)
+        }
+        Expr::Block { lets, value } => {
+            lets.iter().any(|(_, value)| divides_by_zero(value)) || divides_by_zero(value)
+        }
+        Expr::Call(_, args) => args.iter().any(divides_by_zero),
+    }
+}
+
+/// The value of `expr`, if it's made only of literals. See `eval`.
+fn fold(expr: &Expr) -> Option<Reg> {
+    eval(expr, &HashMap::new())
+}
+
+/// Interprets `expr` directly, with the same arithmetic as the compiled code running on the VM.
+/// This is the reference the compiler is tested against. `None` if it reads a variable missing
+/// from `vars`, or would fault at runtime.
+pub fn eval(expr: &Expr, vars: &HashMap<String, Reg>) -> Option<Reg> {
+    eval_in(
+        Env::new(ArithmeticMode::Checked, &HashMap::new()),
+        expr,
+        vars,
+    )
+}
+
+/// The VM's default limit on nested calls, which `eval` stops at too.
+const MAX_CALL_DEPTH: usize = 1024;
+
+/// Each function's parameters and body, by name.
+type Funcs<'a> = HashMap<&'a str, (&'a [String], &'a Expr)>;
+
+/// What `eval_in` needs besides the variables in scope.
+#[derive(Clone, Copy)]
+struct Env<'a> {
+    mode: ArithmeticMode,
+    funcs: &'a Funcs<'a>,
+    depth: usize,
+}
+
+impl<'a> Env<'a> {
+    fn new(mode: ArithmeticMode, funcs: &'a Funcs<'a>) -> Self {
+        Env {
+            mode,
+            funcs,
+            depth: 0,
+        }
+    }
+}
+
+/// `eval` for a VM running in the given arithmetic mode, with functions to call.
+fn eval_in(env: Env, expr: &Expr, vars: &HashMap<String, Reg>) -> Option<Reg> {
+    let mode = env.mode;
+    let fold = |expr: &Expr| eval_in(env, expr, vars);
+    let binop = |a: &Expr, b: &Expr| Some((fold(a)?, fold(b)?));
+    let nonzero = |b: Reg| (b != 0).then_some(b);
+
+    match expr {
+        Expr::Int(x) => Some(*x as Reg),
+        Expr::Var(name) => vars.get(name).copied(),
+        Expr::Negate(x) => mode.mul(fold(x)?, -1),
+        Expr::Add(a, b) => binop(a, b).and_then(|(a, b)| mode.add(a, b)),
+        Expr::Sub(a, b) => binop(a, b).and_then(|(a, b)| mode.sub(a, b)),
+        Expr::Mul(a, b) => binop(a, b).and_then(|(a, b)| mode.mul(a, b)),
+        Expr::Div(a, b) => binop(a, b).and_then(|(a, b)| mode.div(a, nonzero(b)?)),
+        Expr::Mod(a, b) => binop(a, b).and_then(|(a, b)| mode.rem(a, nonzero(b)?)),
+        Expr::Pow(a, b) => binop(a, b).and_then(|(a, b)| mode.pow(a, b.try_into().ok()?)),
+        Expr::BitAnd(a, b) => binop(a, b).map(|(a, b)| a & b),
+        Expr::BitOr(a, b) => binop(a, b).map(|(a, b)| a | b),
+        Expr::BitXor(a, b) => binop(a, b).map(|(a, b)| a ^ b),
+        Expr::Shl(a, b) => binop(a, b).map(|(a, b)| a << (b & 63)),
+        Expr::Shr(a, b) => binop(a, b).map(|(a, b)| ((a as u64) >> (b & 63)) as Reg),
+        Expr::Compare(first, rest) => {
+            let mut lhs = fold(first)?;
+            let mut holds = true;
+            for (op, rhs) in rest {
+                let rhs = fold(rhs)?;
+                holds &= match op {
+                    CmpOp::Lt => lhs < rhs,
+                    CmpOp::Gt => lhs > rhs,
+                    CmpOp::Le => lhs <= rhs,
+                    CmpOp::Ge => lhs >= rhs,
+                    CmpOp::Eq => lhs == rhs,
+                    CmpOp::Ne => lhs != rhs,
+                };
+                lhs = rhs;
+            }
+            Some(holds as Reg)
+        }
+        Expr::And(a, b) => Some((fold(a)? != 0 && fold(b)? != 0) as Reg),
+        Expr::Or(a, b) => Some((fold(a)? != 0 || fold(b)? != 0) as Reg),
+        // With a call in either branch, only the one that's wanted runs, as in `compile`.
+        Expr::Select { cond, then, else_ } if calls(then) || calls(else_) => {
+            fold(if fold(cond)? != 0 { then } else { else_ })
+        }
+        // Both branches run, so a fault in either faults the whole select.
+        Expr::Select { cond, then, else_ } => {
+            let (then, else_) = binop(then, else_)?;
+            Some(if fold(cond)? != 0 { then } else { else_ })
+        }
+        Expr::Block { lets, value } => {
+            let mut scope = vars.clone();
+            for (ident, value) in lets {
+                let value = eval_in(env, value, &scope)?;
+                scope.insert(ident.clone(), value);
+            }
+            eval_in(env, value, &scope)
+        }
+        Expr::Call(name, args) => eval_call(env, name, args, vars),
+    }
+}
+
+fn eval_call(env: Env, name: &str, args: &[Expr], vars: &HashMap<String, Reg>) -> Option<Reg> {
+    let &(params, body) = env.funcs.get(name)?;
+    if params.len() != args.len() || env.depth >= MAX_CALL_DEPTH {
+        return None;
+    }
+    let args = args
+        .iter()
+        .map(|arg| eval_in(env, arg, vars))
+        .collect::<Option<Vec<_>>>()?;
+    let scope = params.iter().cloned().zip(args).collect();
+    let env = Env {
+        depth: env.depth + 1,
+        ..env
+    };
+    eval_in(env, body, &scope)
+}
+
+/// Replaces every subexpression made only of literals with its value, computed the way a VM in
+/// `mode` would. Anything that would fault at runtime, or whose value doesn't fit in a `LOAD`
+/// immediate, is left for the VM to compute.
+pub fn fold_constants(expr: &Expr, mode: ArithmeticMode) -> Expr {
+    let value = eval_in(Env::new(mode, &HashMap::new()), expr, &HashMap::new())
+        .and_then(|v| Int::try_from(v).ok());
+    if let Some(value) = value {
+        return Expr::Int(value);
+    }
+
+    let fold = |expr: &Expr| Box::new(fold_constants(expr, mode));
+    match expr {
+        Expr::Int(_) | Expr::Var(_) => expr.clone(),
+        Expr::Negate(x) => Expr::Negate(fold(x)),
+        Expr::Add(a, b) => Expr::Add(fold(a), fold(b)),
+        Expr::Sub(a, b) => Expr::Sub(fold(a), fold(b)),
+        Expr::Mul(a, b) => Expr::Mul(fold(a), fold(b)),
+        Expr::Div(a, b) => Expr::Div(fold(a), fold(b)),
+        Expr::Mod(a, b) => Expr::Mod(fold(a), fold(b)),
+        Expr::Pow(a, b) => Expr::Pow(fold(a), fold(b)),
+        Expr::BitAnd(a, b) => Expr::BitAnd(fold(a), fold(b)),
+        Expr::BitOr(a, b) => Expr::BitOr(fold(a), fold(b)),
+        Expr::BitXor(a, b) => Expr::BitXor(fold(a), fold(b)),
+        Expr::Shl(a, b) => Expr::Shl(fold(a), fold(b)),
+        Expr::Shr(a, b) => Expr::Shr(fold(a), fold(b)),
+        Expr::Compare(first, rest) => Expr::Compare(
+            fold(first),
+            rest.iter()
+                .map(|(op, rhs)| (*op, fold_constants(rhs, mode)))
+                .collect(),
+        ),
+        Expr::And(a, b) => Expr::And(fold(a), fold(b)),
+        Expr::Or(a, b) => Expr::Or(fold(a), fold(b)),
+        Expr::Select { cond, then, else_ } => Expr::Select {
+            cond: fold(cond),
+            then: fold(then),
+            else_: fold(else_),
+        },
+        Expr::Block { lets, value } => Expr::Block {
+            lets: lets
+                .iter()
+                .map(|(ident, value)| (ident.clone(), fold_constants(value, mode)))
+                .collect(),
+            value: fold(value),
+        },
+        Expr::Call(name, args) => Expr::Call(
+            name.clone(),
+            args.iter().map(|arg| fold_constants(arg, mode)).collect(),
+        ),
+    }
+}
+
+/// Interprets a whole program, returning the value of its final statement if that's an
+/// expression, like `Compiled::result`.
+pub fn eval_program(program: &[Ast]) -> Option<Reg> {
+    let funcs: Funcs = program
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Ast::Func { name, params, body } => Some((name.as_str(), (&params[..], body))),
+            _ => None,
+        })
+        .collect();
+    let env = Env::new(ArithmeticMode::Checked, &funcs);
+
+    let mut vars = HashMap::new();
+    let mut result = None;
+    for stmt in program {
+        match stmt {
+            Ast::Let { ident, value } => {
+                vars.insert(ident.clone(), eval_in(env, value, &vars)?);
+                result = None;
+            }
+            Ast::Expr(expr) => result = Some(eval_in(env, expr, &vars)?),
+            Ast::Print(expr) => {
+                eval_in(env, expr, &vars)?;
+                result = None;
+            }
+            Ast::Func { .. } => result = None,
+        }
+    }
+    result
+}
+
+/// Something suspicious but legal in a program, see `lint`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lint {
+    /// The condition of a `?:` in the statement at this index is a comparison of constants, so
+    /// only one branch is ever used.
+    ConstantCondition { statement: usize, value: bool },
+    /// Any other comparison of constants.
+    ConstantComparison { statement: usize, value: bool },
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (what, statement, value) = match self {
+            Lint::ConstantCondition { statement, value } => ("condition", statement, value),
+            Lint::ConstantComparison { statement, value } => ("comparison", statement, value),
+        };
+        write!(f, "{} is always {} in statement {}", what, value, statement)
+    }
+}
+
+/// Finds comparisons whose operands are all literals, which always come out the same way.
+pub fn lint(program: &[Ast]) -> Vec<Lint> {
+    let mut lints = vec![];
+    for (statement, stmt) in program.iter().enumerate() {
+        let (Ast::Let { value: expr, .. }
+        | Ast::Expr(expr)
+        | Ast::Print(expr)
+        | Ast::Func { body: expr, .. }) = stmt;
+        lint_expr(expr, statement, &mut lints);
+    }
+    lints
+}
+
+fn lint_expr(expr: &Expr, statement: usize, lints: &mut Vec<Lint>) {
+    match expr {
+        Expr::Int(_) | Expr::Var(_) => {}
+        Expr::Negate(x) => lint_expr(x, statement, lints),
+        Expr::Add(a, b)
+        | Expr::Sub(a, b)
+        | Expr::Mul(a, b)
+        | Expr::Div(a, b)
+        | Expr::Mod(a, b)
+        | Expr::Pow(a, b)
+        | Expr::BitAnd(a, b)
+        | Expr::BitOr(a, b)
+        | Expr::BitXor(a, b)
+        | Expr::Shl(a, b)
+        | Expr::Shr(a, b)
+        | Expr::And(a, b)
+        | Expr::Or(a, b) => {
+            lint_expr(a, statement, lints);
+            lint_expr(b, statement, lints);
+        }
+        Expr::Compare(first, rest) => match fold(expr) {
+            Some(value) => lints.push(Lint::ConstantComparison {
+                statement,
+                value: value != 0,
+            }),
+            None => {
+                lint_expr(first, statement, lints);
+                rest.iter()
+                    .for_each(|(_, rhs)| lint_expr(rhs, statement, lints));
+            }
+        },
+        Expr::Select { cond, then, else_ } => {
+            match (&**cond, fold(cond)) {
+                (Expr::Compare(..), Some(value)) => lints.push(Lint::ConstantCondition {
+                    statement,
+                    value: value != 0,
+                }),
+                _ => lint_expr(cond, statement, lints),
+            }
+            lint_expr(then, statement, lints);
+            lint_expr(else_, statement, lints);
+        }
+        Expr::Block { lets, value } => {
+            lets.iter()
+                .for_each(|(_, value)| lint_expr(value, statement, lints));
+            lint_expr(value, statement, lints);
+        }
+        Expr::Call(_, args) => args.iter().for_each(|arg| lint_expr(arg, statement, lints)),
+    }
+}
+
+/// Parses, compiles and runs a single expression on a fresh VM, returning its value.
+pub fn run_source(src: &str) -> Result<Reg, RunError> {
+    let expr = parser::expr()
+        .padded()
+        .then_ignore(end())
+        .parse(src)
+        .map_err(RunError::Parse)?;
+    let instrs = compile_expr(expr, 0)?;
+
+    let mut vm = VM::with_program(instrs.into_iter().flat_map(Instr::to_bytes).collect());
+    vm.run()?;
+    Ok(vm.registers[0])
+}
+
+/// Sets the VM's comparison flag to `lhs op rhs`.
+fn compare(op: CmpOp, lhs: u8, rhs: u8, results: &mut Vec<Instr>) {
+    match op {
+        CmpOp::Lt => results.push(Instr::GreaterThan(rhs, lhs)),
+        CmpOp::Gt => results.push(Instr::GreaterThan(lhs, rhs)),
+        CmpOp::Le => results.push(Instr::GreaterThanEqual(rhs, lhs)),
+        CmpOp::Ge => results.push(Instr::GreaterThanEqual(lhs, rhs)),
+        CmpOp::Eq => results.push(Instr::Equal(lhs, rhs)),
+        CmpOp::Ne => results.extend([Instr::Equal(lhs, rhs), Instr::Not]),
+    }
+}
+
+/// Writes whether `lhs op rhs` holds to `dest` with the register-writing comparisons, using
+/// `scratch` for the zero to compare against when the result needs inverting.
+fn set_compare(op: CmpOp, lhs: u8, rhs: u8, dest: u8, scratch: u8, results: &mut Vec<Instr>) {
+    let inverted = match op {
+        CmpOp::Lt => return results.push(Instr::SetLessThan(lhs, rhs, dest)),
+        CmpOp::Gt => return results.push(Instr::SetGreaterThan(lhs, rhs, dest)),
+        CmpOp::Eq => return results.push(Instr::SetEqual(lhs, rhs, dest)),
+        CmpOp::Le => Instr::SetGreaterThan(lhs, rhs, dest),
+        CmpOp::Ge => Instr::SetLessThan(lhs, rhs, dest),
+        CmpOp::Ne => Instr::SetEqual(lhs, rhs, dest),
+    };
+    results.extend([
+        inverted,
+        Instr::Load(scratch, 0),
+        Instr::SetEqual(dest, scratch, dest),
+    ]);
 }
 
 #[cfg(test)]
@@ -100,13 +1098,16 @@ mod tests {
 
     macro_rules! compile_eq {
         ($in:expr => $out:expr) => {
-            assert_eq!(compile_expr($in, 0), $out)
+            assert_eq!(compile_expr($in, 0).unwrap(), $out)
         };
     }
 
     macro_rules! compile_str_eq {
         ($in:expr => $out:expr) => {
-            assert_eq!(compile_expr(parser::expr().parse($in).unwrap(), 0), $out)
+            assert_eq!(
+                compile_expr(parser::expr().parse($in).unwrap(), 0).unwrap(),
+                $out
+            )
         };
     }
     #[test]
@@ -138,4 +1139,499 @@ mod tests {
     fn compile_nested_precedence_binop() {
         compile_str_eq!("2 - (3 * 2)" => vec![Load(0, 2), Load(1, 3), Load(2, 2), Multiply(1, 2, 1), Subtract(0, 1, 0)])
     }
+
+    fn run(src: &str) -> Reg {
+        let program = parser::program().parse(src).unwrap();
+        let compiled = compile_program(&program).unwrap();
+
+        let mut vm = vm::VM::with_program(
+            compiled
+                .instrs
+                .into_iter()
+                .flat_map(Instr::to_bytes)
+                .collect(),
+        );
+        vm.run().unwrap();
+        vm.registers[compiled.result.unwrap() as usize]
+    }
+
+    #[test]
+    fn compile_print() {
+        let program = parser::program()
+            .parse("x = 2\nprint x + 3\nprint x")
+            .unwrap();
+        let compiled = compile_program(&program).unwrap();
+        assert_eq!(compiled.result, None);
+        assert_eq!(eval_program(&program), None);
+
+        let mut vm = vm::VM::with_program(
+            compiled
+                .instrs
+                .into_iter()
+                .flat_map(Instr::to_bytes)
+                .collect(),
+        );
+        vm.capture_output();
+        vm.run().unwrap();
+        assert_eq!(vm.take_output(), "5\n2\n");
+    }
+
+    /// Checks that the compiled program computes the same value as `eval_program`.
+    fn assert_compiles_correctly(src: &str) {
+        let program = parser::program().parse(src).unwrap();
+        let expected = eval_program(&program).unwrap_or_else(|| panic!("{} doesn't evaluate", src));
+        assert_eq!(run(src), expected, "{}", src);
+    }
+
+    #[test]
+    fn compiled_matches_eval() {
+        let cases = [
+            "1 + 2 * 3",
+            "(1 + 2) * 3",
+            "2 - 3 - 4",
+            "2 ^ 3 ^ 2",
+            "-2 ^ 2",
+            "--5 - -5",
+            "-(3 * -(2 - 7))",
+            "100 / 7 * 7 + 100 % 7",
+            "-100 / 7 % 4",
+            "x = 6\ny = x * x\ny - x / 4",
+            "x = 3\n0 < x <= 3 != 0",
+            "x = -4\ny = x < 0 ? -x : x\ny * (x == -4 ? 10 : 1)",
+            "a = 2\nb = a ^ a ^ a\n(b - a) % 5 + b / (a + 1)",
+            "a = 3\n{ a = a * 2; b = { a = a + 1; a }; a * 10 + b }",
+            "1 > 0 && 2 > 3",
+            "x = 5\nx > 3 && x < 10 || x == 0",
+            "x = 0\n(x || 7) + (x && 7) * 10 + (-3 && 2) * 100",
+            "a = 2\nb = a && 0 || a\nb ? a || 0 : 9",
+            "fn f(a, b) = a * 10 + b\nx = 3\ny = f(x, f(1, x))\nx + y * 2",
+            "fn fib(n) = n < 2 ? n : fib(n - 1) + fib(n - 2)\nfib(12) - fib(5)",
+            "k = 4\nfn g() = 2\nk * g() + { k = g(); k }",
+        ];
+        for src in cases {
+            assert_compiles_correctly(src);
+        }
+    }
+
+    #[test]
+    fn fold_per_arithmetic_mode() {
+        let parse = |src| parser::expr().parse(src).unwrap();
+        // Overflows an i64 partway through, but comes back into range.
+        let src = "2 ^ 64 - 2 ^ 64 + 1";
+
+        assert_eq!(
+            fold_constants(&parse(src), ArithmeticMode::Checked),
+            parse(src)
+        );
+        assert_eq!(
+            fold_constants(&parse(src), ArithmeticMode::Wrapping),
+            Expr::Int(1)
+        );
+        assert_eq!(
+            fold_constants(&parse("2 ^ 63 + 2 ^ 63 + 7"), ArithmeticMode::Wrapping),
+            Expr::Int(7)
+        );
+        assert_eq!(
+            fold_constants(
+                &parse("2 ^ 63 - (2 ^ 63 + 2 ^ 63) + 7"),
+                ArithmeticMode::Saturating
+            ),
+            Expr::Int(7)
+        );
+        assert_eq!(
+            fold_constants(&parse("x * (1 + 2) + 2 ^ 70"), ArithmeticMode::Checked),
+            parse("x * 3 + 2 ^ 70")
+        );
+
+        // Whatever folds must match what the VM computes from the unfolded code.
+        for mode in [ArithmeticMode::Wrapping, ArithmeticMode::Saturating] {
+            for src in [src, "2 ^ 63 + 2 ^ 63 + 7", "2 ^ 63 - (2 ^ 63 + 2 ^ 63) + 7"] {
+                let Expr::Int(folded) = fold_constants(&parse(src), mode) else {
+                    continue;
+                };
+                let mut vm = vm::VM::with_program(
+                    compile_expr(parse(src), 0)
+                        .unwrap()
+                        .into_iter()
+                        .flat_map(Instr::to_bytes)
+                        .collect(),
+                );
+                vm.set_arithmetic(mode);
+                vm.run().unwrap();
+                assert_eq!(vm.registers[0], folded as Reg, "{} in {:?}", src, mode);
+            }
+        }
+    }
+
+    #[test]
+    fn compile_single_comparison() {
+        let compile = |src, comparisons| {
+            let program = parser::program().parse(src).unwrap();
+            let options = CompileOptions {
+                comparisons,
+                ..Default::default()
+            };
+            compile_program_with(&program, &options).unwrap().instrs
+        };
+
+        assert_eq!(
+            compile("1 < 2", Comparisons::Flag),
+            vec![Load(0, 1), Load(1, 2), GreaterThan(1, 0), GetFlag(0)]
+        );
+        assert_eq!(
+            compile("1 != 2", Comparisons::Flag),
+            vec![Load(0, 1), Load(1, 2), Equal(0, 1), Not, GetFlag(0)]
+        );
+        compile_str_eq!("1 < 2" => vec![Load(0, 1), Load(1, 2), SetLessThan(0, 1, 0)]);
+        compile_str_eq!("1 != 2" => vec![
+            Load(0, 1),
+            Load(1, 2),
+            SetEqual(0, 1, 0),
+            Load(2, 0),
+            SetEqual(0, 2, 0),
+        ]);
+
+        for src in [
+            "1 < 2",
+            "2 < 1",
+            "3 >= 3",
+            "1 >= 3",
+            "1 == 1",
+            "1 != 1",
+            "2 <= 1 <= 3",
+        ] {
+            let run = |comparisons| {
+                let mut vm = vm::VM::with_program(
+                    compile(src, comparisons)
+                        .into_iter()
+                        .flat_map(Instr::to_bytes)
+                        .collect(),
+                );
+                vm.run().unwrap();
+                vm.registers[0]
+            };
+            assert_eq!(
+                run(Comparisons::Flag),
+                run(Comparisons::SetRegister),
+                "{}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn compile_chained_comparison() {
+        let program = parser::program().parse("x = 5\n0 < x < 10").unwrap();
+        let flag = CompileOptions {
+            comparisons: Comparisons::Flag,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            compile_program(&program).unwrap().instrs,
+            vec![
+                Load(0, 5),
+                Load(1, 0),
+                SetLessThan(1, 0, 1),
+                Load(3, 10),
+                SetLessThan(0, 3, 2),
+                Multiply(1, 2, 1),
+            ]
+        );
+        assert_eq!(
+            compile_program_with(&program, &flag).unwrap(),
+            Compiled {
+                instrs: vec![
+                    Load(0, 5),
+                    Load(1, 0),
+                    GreaterThan(0, 1),
+                    GetFlag(1),
+                    Load(3, 10),
+                    GreaterThan(3, 0),
+                    GetFlag(2),
+                    Multiply(1, 2, 1),
+                ],
+                result: Some(1),
+            }
+        );
+    }
+
+    #[test]
+    fn run_comparisons() {
+        assert_eq!(run("x = 5\n0 < x < 10"), 1);
+        assert_eq!(run("x = 10\n0 < x < 10"), 0);
+        assert_eq!(run("x = 0\n0 < x < 10"), 0);
+        assert_eq!(run("x = 3\n0 <= x <= 3 == 1"), 0);
+        assert_eq!(run("x = 3\n0 <= x <= 3 <= 3"), 1);
+        assert_eq!(run("2 + 2 == 4"), 1);
+        assert_eq!(run("2 > 1 != 1"), 0);
+    }
+
+    #[test]
+    fn run_modulo() {
+        compile_str_eq!("7 % 3" => vec![Load(0, 7), Load(1, 3), Divide(0, 1, 0), ReadRemainder(0)]);
+        assert_eq!(run("x = 17\nx % 5 + 1"), 3);
+        assert_eq!(run("-7 % 2"), -1);
+    }
+
+    #[test]
+    fn compile_bitwise() {
+        compile_str_eq!("1 | 2 & 3" => vec![
+            Load(0, 1),
+            Load(1, 2),
+            Load(2, 3),
+            And(1, 2, 1),
+            Or(0, 1, 0),
+        ]);
+        compile_str_eq!("1 << 4 ^^ 3 >> 1" => vec![
+            Load(0, 1),
+            Load(1, 4),
+            Shl(0, 1, 0),
+            Load(1, 3),
+            Load(2, 1),
+            Shr(1, 2, 1),
+            Xor(0, 1, 0),
+        ]);
+        assert_eq!(run("x = 6\nx & 3 | 8"), 10);
+        assert_eq!(run("-1 >> 60"), 15);
+    }
+
+    #[test]
+    fn div_by_zero_at_compile_time() {
+        let compile = |src| compile_program(&parser::program().parse(src).unwrap());
+
+        assert_eq!(
+            compile("1 / 0"),
+            Err(CompileError::DivByZero { statement: 0 })
+        );
+        assert_eq!(
+            compile("x = 4\ny = 5 % (x - x + 2 - 2)\nx / (3 - 3 * 1)"),
+            Err(CompileError::DivByZero { statement: 2 })
+        );
+        assert_eq!(
+            compile("x = 2\n(1 / (2 > 3)) + x"),
+            Err(CompileError::DivByZero { statement: 1 })
+        );
+        assert!(compile("x = 0\n1 / x").is_ok());
+        assert!(compile("1 % 2 / 1").is_ok());
+    }
+
+    #[test]
+    fn compile_short_circuit() {
+        compile_str_eq!("1 > 0 && 2 > 3" => vec![
+            Load(0, 1),
+            Load(1, 0),
+            SetGreaterThan(0, 1, 0),
+            Load(1, 0),
+            SetEqual(0, 1, 0),
+            SetEqual(0, 1, 0),
+            Equal(0, 1),
+            Load(1, 57),
+            JumpIf(1),
+            Load(1, 2),
+            Load(2, 3),
+            SetGreaterThan(1, 2, 1),
+            Load(2, 0),
+            SetEqual(1, 2, 0),
+            SetEqual(0, 2, 0),
+        ]);
+        assert_eq!(run("1 > 0 && 2 > 3"), 0);
+        assert_eq!(run("0 || 2 > 3 || 4"), 1);
+
+        // The right-hand side would fault if it ran.
+        assert_eq!(run("x = 0\nx && 1 / x"), 0);
+        assert_eq!(run("x = 0\n1 || 1 / x"), 1);
+    }
+
+    #[test]
+    fn compile_select() {
+        compile_str_eq!("1 > 0 ? 10 : 20" => vec![
+            Load(0, 10),
+            Load(1, 20),
+            Load(2, 1),
+            Load(3, 0),
+            GreaterThan(2, 3),
+            ConditionalMove(0, 1, 0),
+        ]);
+        assert_eq!(run("1 > 0 ? 10 : 20"), 10);
+        assert_eq!(run("x = 3\ny = 4\nx == y ? x : y * 2"), 8);
+        assert_eq!(run("x = 3\nx - 3 ? 1 : x < 5 ? 2 : 3"), 2);
+        assert_eq!(run("x = 0\n(x < 1 < 2 ? x + 5 : 7) * 2"), 10);
+    }
+
+    #[test]
+    fn lint_constant_comparisons() {
+        let lints = |src| lint(&parser::program().parse(src).unwrap());
+
+        assert_eq!(
+            lints("a = 1\nb = 2\n5 > 3 ? a : b"),
+            vec![Lint::ConstantCondition {
+                statement: 2,
+                value: true
+            }]
+        );
+        assert_eq!(
+            lints("5 > 3 ? 1 : 2")[0].to_string(),
+            "condition is always true in statement 0"
+        );
+        assert_eq!(
+            lints("x = 4\ny = 1 + (2 == 2 + 1)"),
+            vec![Lint::ConstantComparison {
+                statement: 1,
+                value: false
+            }]
+        );
+        assert_eq!(lints("x = 4\nx > 3 ? 1 : 2"), vec![]);
+        assert_eq!(lints("x = 4\n1 < 2 < x"), vec![]);
+    }
+
+    #[test]
+    fn compile_block() {
+        compile_str_eq!("{ x = 2; y = 3; x + y }" => vec![
+            Load(0, 2),
+            Load(1, 3),
+            Add(0, 1, 2),
+            ConditionalMove(2, 2, 0),
+        ]);
+        assert_eq!(run("{ x = 2; y = 3; x + y }"), 5);
+        assert_eq!(run("x = 10\n{ x = 2; y = x * 3; y } + x"), 16);
+        assert_eq!(run("a = 1\nb = { a = 5\n c = a\n c + 1 }\na + b"), 7);
+        assert_eq!(run("{ 4 } * { x = 2; { y = x; y * x } }"), 16);
+
+        let program = parser::program().parse("y = { x = 1; x }\nx").unwrap();
+        assert_eq!(
+            compile_program(&program),
+            Err(CompileError::UnknownVariable("x".to_string()))
+        );
+    }
+
+    #[test]
+    fn pinned_variables() {
+        let compile = |src, reg| {
+            let program = parser::program().parse(src).unwrap();
+            let pinned = HashMap::from([("x".to_string(), reg)]);
+            compile_program_pinned(&program, &pinned).unwrap()
+        };
+
+        let compiled = compile("x + 1", 10);
+        assert_eq!(compiled.instrs, vec![Load(1, 1), Add(10, 1, 0)]);
+        assert_eq!(compiled.result, Some(0));
+
+        let compiled = compile("x * (x + 1)", 1);
+        assert_eq!(
+            compiled.instrs,
+            vec![Load(3, 1), Add(1, 3, 2), Multiply(1, 2, 0)]
+        );
+
+        let src = "y = x + 1\n(1 + (2 + (3 + (4 + (5 + (6 + (7 + (8 + (9 + (10 + y))))))))))";
+        let compiled = compile(src, 0);
+        let mut vm = vm::VM::with_program(
+            compiled
+                .instrs
+                .into_iter()
+                .flat_map(Instr::to_bytes)
+                .collect(),
+        );
+        vm.registers[0] = 7;
+        vm.run().unwrap();
+        assert_eq!(vm.registers[compiled.result.unwrap() as usize], 63);
+        assert_eq!(vm.registers[0], 7);
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(run("fn square(x) = x * x\nsquare(7) + square(2)"), 53);
+        assert_eq!(
+            run("x = fact(5)\nfn fact(n) = n > 1 ? n * fact(n - 1) : 1\nx"),
+            120
+        );
+
+        let compile = |src| compile_program(&parser::program().parse(src).unwrap());
+        assert_eq!(
+            compile("fn f(x) = x\nf(1, 2)"),
+            Err(CompileError::WrongArgumentCount {
+                name: "f".to_string(),
+                expected: 1,
+                found: 2
+            })
+        );
+        assert_eq!(
+            compile("g(1)"),
+            Err(CompileError::UnknownFunction("g".to_string()))
+        );
+        assert_eq!(
+            compile("fn f() = 1\nfn f() = 2"),
+            Err(CompileError::DuplicateFunction("f".to_string()))
+        );
+        // Functions only see their parameters.
+        assert_eq!(
+            compile("y = 1\nfn f(x) = x + y"),
+            Err(CompileError::UnknownVariable("y".to_string()))
+        );
+    }
+
+    #[test]
+    fn run_source_errors() {
+        assert_eq!(run_source("2 + 3 * 4"), Ok(14));
+        assert_eq!(run_source(" { x = 300; x * x } "), Ok(90000));
+        assert_eq!(
+            run_source("1 / 0"),
+            Err(RunError::Compile(CompileError::DivByZero { statement: 0 }))
+        );
+        assert!(matches!(run_source("x = 2"), Err(RunError::Parse(_))));
+        assert_eq!(run_source("2 ^ 70"), Err(RunError::Vm(VmError::Overflow)));
+    }
+
+    #[test]
+    fn unknown_variable() {
+        assert_eq!(
+            compile_expr(Expr::Var("y".to_string()), 0),
+            Err(CompileError::UnknownVariable("y".to_string()))
+        );
+    }
+
+    #[test]
+    fn register_peak() {
+        let cases = [
+            "2",
+            "-2",
+            "2 - (3 * 2)",
+            "1 + (2 + (3 + 4))",
+            "((1 + 2) + 3) + 4",
+            "-(1 * -(2 - 3))",
+            "0 < 1 + (2 * 3) < 10",
+            "1 > 0 ? 10 : 20",
+            "1 ? 2 + (3 + 4) : 5",
+            "1 ? 2 : (1 + (1 + 1)) == 3 ? 4 : 5",
+            "{ x = 2; y = 3; x + (y * 2) }",
+            "5 & 3 | 1 << 4 ^^ 255 >> 2",
+            "-8 >> 1 & 12 == 12",
+            "1 + { x = 2; y = x; z = y + 1; z * (x - 1) }",
+        ];
+        for src in cases {
+            let expr = parser::expr().parse(src).unwrap();
+            let used = compile_expr(expr.clone(), 0)
+                .unwrap()
+                .into_iter()
+                .flat_map(Instr::registers)
+                .max()
+                .unwrap();
+            assert_eq!(peak_registers(&expr), used, "{}", src);
+        }
+
+        assert_eq!(
+            peak_registers(&parser::expr().parse("1 + (2 + (3 + 4))").unwrap()),
+            3
+        );
+    }
+
+    #[test]
+    fn out_of_registers() {
+        let deep = (0..300).fold(Expr::Int(1), |acc, _| {
+            Expr::Add(Box::new(Expr::Int(1)), Box::new(acc))
+        });
+
+        assert_eq!(peak_registers(&deep), u8::MAX);
+        assert_eq!(compile_expr(deep, 0), Err(CompileError::OutOfRegisters));
+    }
 }
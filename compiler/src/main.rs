@@ -1,33 +1,44 @@
 use chumsky::Parser;
-use compiler::{compile_expr, parser};
+use compiler::{compile_expr, compile_program, parser};
 use std::{error::Error, fs, path::PathBuf};
-use vm::opcode::instructions::Instr;
 pub use vm::VM;
+use vm::{opcode::instructions::Instr, parsing::render_errors, passes};
 
 use clap::Parser as ArgParser;
 
 #[derive(ArgParser)]
 struct Args {
-    /// File path to bytecode
+    /// File path to a source file to compile and run.
     /// If ommitted, starts a repl.
-    #[arg(short, long, value_name = "BYTECODE")]
+    #[arg(short, long, value_name = "SOURCE")]
     script: Option<PathBuf>,
 
+    /// Run the script as bytecode instead of compiling it.
     #[arg(short, long)]
     raw_hex: bool,
+
+    /// File to keep REPL history in.
+    /// Defaults to $HALIDE_COMPILER_HISTORY, or halide-compiler.history if that isn't set.
+    #[arg(long, value_name = "PATH")]
+    history: Option<PathBuf>,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
     match args.script {
-        Some(script) => run_bytecode(script)?,
-        None => repl()?,
+        Some(script) => run_bytecode(script, args.raw_hex)?,
+        None => repl(args)?,
     }
     Ok(())
 }
 
-fn repl() -> Result<(), Box<dyn Error>> {
-    let mut rl = rustyline::config()?;
+fn repl(args: Args) -> Result<(), Box<dyn Error>> {
+    let history = rustyline::history_path(
+        args.history.clone(),
+        std::env::var_os("HALIDE_COMPILER_HISTORY"),
+        "halide-compiler.history",
+    );
+    let mut rl = rustyline::config(&history)?;
 
     println!("halide repl v0.0.1");
     let mut input = rl.readline("- ");
@@ -41,9 +52,21 @@ fn repl() -> Result<(), Box<dyn Error>> {
                 }
 
                 match line.as_str() {
-                    ".step" => vm.step(),
-                    ".run" => vm.run(),
-                    ".clear" => vm.program = vec![],
+                    ".step" => {
+                        if let Err(err) = vm.step() {
+                            eprintln!("error: {}", err);
+                        }
+                    }
+                    ".next" => match vm.next_instr() {
+                        Some(Instr::Illegal) => println!("Next: illegal opcode"),
+                        Some(instr) => println!("Next: {}", instr),
+                        None => println!("Next: nothing, end of program"),
+                    },
+                    ".run" => match vm.run() {
+                        Ok(outcome) => println!("Stopped: {:?}", outcome),
+                        Err(err) => eprintln!("error: {}", err),
+                    },
+                    ".clear" => clear(&mut vm),
 
                     ".dbg" => {
                         println!("Full VM state:");
@@ -53,6 +76,17 @@ fn repl() -> Result<(), Box<dyn Error>> {
                         print!("Registers: ");
                         println!("{:?}", vm.registers)
                     }
+                    ".regs" => {
+                        for (i, v) in vm.nonzero_registers() {
+                            println!("${} = {}", i, v);
+                        }
+                    }
+                    cmd if cmd.starts_with(".reg ") => {
+                        match cmd[".reg ".len()..].trim().trim_start_matches('$').parse() {
+                            Ok(r) => println!("{}", vm.register_bases(r)),
+                            Err(_) => eprintln!("usage: .reg <register>"),
+                        }
+                    }
                     ".program" => {
                         print!("Program: ");
                         println!("{:#?}", vm.program)
@@ -61,19 +95,20 @@ fn repl() -> Result<(), Box<dyn Error>> {
                         println!("buh-bye!");
                         std::process::exit(0);
                     }
-                    input => {
-                        let mut hex = parse_input_to_bytes(input);
-
-                        print!("Loading hex: ");
-                        for byte in hex.iter() {
-                            print!("{:#04X} ", byte);
-                            println!();
+                    input => match parse_input_to_bytes(input, vm.program.len()) {
+                        Ok(mut hex) => {
+                            print!("Loading hex: ");
+                            for byte in hex.iter() {
+                                print!("{:#04X} ", byte);
+                                println!();
+                            }
+                            vm.program.append(&mut hex);
                         }
-                        vm.program.append(&mut hex);
-                    }
+                        Err(err) => eprintln!("invalid input: {}", err),
+                    },
                 }
                 rl.add_history_entry(line.as_str())?;
-                rl.save_history("halide-vm.history")?;
+                rl.save_history(&history)?;
 
                 input = rl.readline(">> ");
             }
@@ -88,29 +123,68 @@ fn repl() -> Result<(), Box<dyn Error>> {
     }
 }
 
-fn parse_input_to_bytes(input: &str) -> Vec<u8> {
-    let input = parser::expr().parse(input).unwrap();
+/// Compiles `input` to be appended to a program that's already `offset` bytes long.
+fn parse_input_to_bytes(input: &str, offset: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let input = parser::expr()
+        .parse(input)
+        .map_err(|errs| render_errors(input, &errs))?;
+
+    let mut bytecode = compile_expr(input, 0)?;
+    passes::relocate(&mut bytecode, offset);
 
-    let bytecode = compile_expr(input, 0);
+    Ok(bytecode.into_iter().flat_map(Instr::to_bytes).collect())
+}
 
-    bytecode.into_iter().flat_map(Instr::to_bytes).collect()
+/// Empties the program and resets the VM, so the next snippet runs from a clean slate.
+fn clear(vm: &mut VM) {
+    vm.program.clear();
+    vm.reset();
 }
 
-fn run_bytecode(file: PathBuf) -> Result<(), Box<dyn Error>> {
+/// Compiles and runs `file`, printing the value of its final statement if that's an
+/// expression. With `raw_hex` the file is run as bytecode as it is.
+fn run_bytecode(file: PathBuf, raw_hex: bool) -> Result<(), Box<dyn Error>> {
     let str = fs::read_to_string(file)?;
-    let hex = str.into_bytes();
-    let mut vm = VM::default();
-    vm.program = hex;
-    vm.run();
+    if raw_hex {
+        let mut vm = VM::default();
+        vm.program = str.into_bytes();
+        vm.run()?;
+        return Ok(());
+    }
+
+    let (mut vm, result) = compile_file(&str)?;
+    vm.run()?;
+    if let Some(result) = result {
+        println!("{}", vm.registers[result as usize]);
+    }
     Ok(())
 }
 
+/// A VM loaded with the compiled source file, and the register its result will be in.
+fn compile_file(src: &str) -> Result<(VM, Option<u8>), Box<dyn Error>> {
+    let program = parser::parse_file(src).map_err(|errs| render_errors(src, &errs))?;
+    let compiled = compile_program(&program)?;
+
+    let vm = VM::with_program(
+        compiled
+            .instrs
+            .into_iter()
+            .flat_map(Instr::to_bytes)
+            .collect(),
+    );
+    Ok((vm, compiled.result))
+}
+
 mod rustyline {
     use rustyline::{
         error::ReadlineError, highlight::MatchingBracketHighlighter, history::FileHistory,
         validate::MatchingBracketValidator, Editor,
     };
     use rustyline_derive::{Completer, Helper, Highlighter, Hinter, Validator};
+    use std::{
+        ffi::OsString,
+        path::{Path, PathBuf},
+    };
 
     #[derive(Completer, Helper, Highlighter, Hinter, Validator)]
     pub struct Config {
@@ -120,18 +194,65 @@ mod rustyline {
         highlighting: MatchingBracketHighlighter,
     }
 
-    pub fn config() -> Result<Editor<Config, FileHistory>, ReadlineError> {
+    /// The history file to use: the `--history` flag, then the environment variable, then the
+    /// REPL's default.
+    pub fn history_path(flag: Option<PathBuf>, env: Option<OsString>, default: &str) -> PathBuf {
+        flag.or_else(|| env.map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from(default))
+    }
+
+    pub fn config(history: &Path) -> Result<Editor<Config, FileHistory>, ReadlineError> {
         let config = Config {
             brackets: MatchingBracketValidator::new(),
             highlighting: MatchingBracketHighlighter::new(),
         };
         let mut rl = Editor::new()?;
         rl.set_helper(Some(config));
-        match rl.load_history("halide-vm.history") {
-            Ok(()) => println!("history loaded from halide-vm.history"),
-            Err(err) => eprint!("error loading history from halide-vm.history: {}", err),
+        match rl.load_history(history) {
+            Ok(()) => println!("history loaded from {}", history.display()),
+            Err(err) => eprint!("error loading history from {}: {}", history.display(), err),
         }
 
         Ok(rl)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clear_resets_vm() {
+        let mut vm = VM::with_program(parse_input_to_bytes("(1 + 2) * 3", 0).unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 9);
+
+        clear(&mut vm);
+        assert!(vm.program.is_empty());
+        assert!(vm.nonzero_registers().is_empty());
+        assert_eq!(vm.state(), VM::default().state());
+    }
+
+    #[test]
+    fn compile_and_run_file() {
+        let src = "\
+// The sum and product of two numbers.
+a = 6
+b = 7 // doesn't have to be prime
+
+sum = a + b
+product = a * b
+print sum
+print product
+
+product - sum
+";
+        let (mut vm, result) = compile_file(src).unwrap();
+        vm.capture_output();
+        vm.run().unwrap();
+        assert_eq!(vm.take_output(), "13\n42\n");
+        assert_eq!(vm.registers[result.unwrap() as usize], 29);
+
+        assert!(compile_file("a = 1\nb = a +").is_err());
+    }
+}
@@ -1,13 +1,54 @@
 #![allow(dead_code)]
 use chumsky::prelude::*;
 
-use crate::{Ast, Expr};
+use crate::{Ast, CmpOp, Expr};
 
 pub fn expr() -> impl Parser<char, Expr, Error = Simple<char>> {
     recursive(|expr| {
-        let int = text::int(10).from_str().unwrapped().map(Expr::Int);
+        let int = text::int(10)
+            .validate(|digits: String, span, emit| {
+                digits.parse().unwrap_or_else(|_| {
+                    emit(Simple::custom(
+                        span,
+                        format!("integer {} out of range for i16", digits),
+                    ));
+                    0
+                })
+            })
+            .map(Expr::Int);
+        let var = text::ident().map(Expr::Var);
+        // The `(` has to follow the name directly, so `f (x)` is still two expressions.
+        let call = text::ident()
+            .then(
+                expr.clone()
+                    .padded()
+                    .separated_by(just(','))
+                    .delimited_by(just('('), just(')')),
+            )
+            .map(|(name, args)| Expr::Call(name, args));
+
+        // Only bindings can come before the final expression, since nothing else would have
+        // any effect.
+        let binding = text::ident()
+            .padded()
+            .then_ignore(just('=').then(just('=').not().rewind()).padded())
+            .then(expr.clone())
+            .then_ignore(just(';').or_not())
+            .padded();
+        let block = binding
+            .repeated()
+            .then(expr.clone().padded())
+            .delimited_by(just('{'), just('}'))
+            .map(|(lets, value)| Expr::Block {
+                lets,
+                value: Box::new(value),
+            });
 
-        let atom = int.or(expr.delimited_by(just('('), just(')')));
+        let atom = int
+            .or(call)
+            .or(var)
+            .or(expr.clone().delimited_by(just('('), just(')')))
+            .or(block);
 
         let negated = just('-')
             .padded()
@@ -26,17 +67,21 @@ pub fn expr() -> impl Parser<char, Expr, Error = Simple<char>> {
             )
             .foldl(|rhs, (op, lhs)| op(Box::new(rhs), Box::new(lhs)));
 
+        // The levels from here on are boxed, otherwise the nested parser types get big enough
+        // that compiling this takes minutes.
         let product = expo
             .clone()
             .then(
                 op("*", Expr::Mul as fn(_, _) -> _)
                     .or(op("/", Expr::Div as fn(_, _) -> _))
+                    .or(op("%", Expr::Mod as fn(_, _) -> _))
                     .then(expo.clone())
                     .repeated(),
             )
-            .foldl(|rhs, (op, lhs)| op(Box::new(rhs), Box::new(lhs)));
+            .foldl(|rhs, (op, lhs)| op(Box::new(rhs), Box::new(lhs)))
+            .boxed();
 
-        product
+        let sum = product
             .clone()
             .then(
                 op("+", Expr::Add as fn(_, _) -> _)
@@ -45,6 +90,109 @@ pub fn expr() -> impl Parser<char, Expr, Error = Simple<char>> {
                     .repeated(),
             )
             .foldl(|rhs, (op, lhs)| op(Box::new(rhs), Box::new(lhs)))
+            .boxed();
+
+        // The bitwise operators bind like Rust's: looser than arithmetic, so `x << n + 1` is
+        // `x << (n + 1)`, but tighter than comparisons, so `x & 1 == 1` tests the low bit. From
+        // tightest to loosest it goes `<< >>`, `&`, `^^`, `|`. `^^` is XOR since `^` is power.
+        let shift = sum
+            .clone()
+            .then(
+                just("<<")
+                    .to(Expr::Shl as fn(_, _) -> _)
+                    .or(just(">>").to(Expr::Shr as fn(_, _) -> _))
+                    .padded()
+                    .then(sum.clone())
+                    .repeated(),
+            )
+            .foldl(|rhs, (op, lhs)| op(Box::new(rhs), Box::new(lhs)))
+            .boxed();
+
+        let bit_and = shift
+            .clone()
+            .then(
+                op("&", Expr::BitAnd as fn(_, _) -> _)
+                    .then(shift)
+                    .repeated(),
+            )
+            .foldl(|rhs, (op, lhs)| op(Box::new(rhs), Box::new(lhs)))
+            .boxed();
+
+        let bit_xor = bit_and
+            .clone()
+            .then(
+                just("^^")
+                    .to(Expr::BitXor as fn(_, _) -> _)
+                    .padded()
+                    .then(bit_and)
+                    .repeated(),
+            )
+            .foldl(|rhs, (op, lhs)| op(Box::new(rhs), Box::new(lhs)))
+            .boxed();
+
+        let bit_or = bit_xor
+            .clone()
+            .then(
+                op("|", Expr::BitOr as fn(_, _) -> _)
+                    .then(bit_xor)
+                    .repeated(),
+            )
+            .foldl(|rhs, (op, lhs)| op(Box::new(rhs), Box::new(lhs)))
+            .boxed();
+
+        let cmp_op = choice((
+            just("<=").to(CmpOp::Le),
+            just(">=").to(CmpOp::Ge),
+            just("==").to(CmpOp::Eq),
+            just("!=").to(CmpOp::Ne),
+            just("<").to(CmpOp::Lt),
+            just(">").to(CmpOp::Gt),
+        ))
+        .padded();
+
+        // `a < b < c` is one chain rather than `(a < b) < c`.
+        let comparison = bit_or
+            .clone()
+            .then(cmp_op.then(bit_or).repeated())
+            .map(|(first, rest)| {
+                if rest.is_empty() {
+                    first
+                } else {
+                    Expr::Compare(Box::new(first), rest)
+                }
+            })
+            .boxed();
+
+        // `&&` binds tighter than `||`, and both are looser than everything but `?:`.
+        let and = comparison
+            .clone()
+            .then(just("&&").padded().ignore_then(comparison).repeated())
+            .foldl(|lhs, rhs| Expr::And(Box::new(lhs), Box::new(rhs)))
+            .boxed();
+
+        let or = and
+            .clone()
+            .then(just("||").padded().ignore_then(and).repeated())
+            .foldl(|lhs, rhs| Expr::Or(Box::new(lhs), Box::new(rhs)))
+            .boxed();
+
+        // The branches are whole expressions, so `a ? b : c ? d : e` nests to the right.
+        or.then(
+            just('?')
+                .padded()
+                .ignore_then(expr.clone())
+                .then_ignore(just(':').padded())
+                .then(expr)
+                .or_not(),
+        )
+        .map(|(cond, branches)| match branches {
+            Some((then, else_)) => Expr::Select {
+                cond: Box::new(cond),
+                then: Box::new(then),
+                else_: Box::new(else_),
+            },
+            None => cond,
+        })
     })
 }
 
@@ -58,6 +206,72 @@ fn parse_decl() -> impl Parser<char, Ast, Error = Simple<char>> {
         .map(|(ident, expr)| Ast::Let { ident, value: expr })
 }
 
+/// A function, declaration, `print` or an expression. Unlike `parse_decl().or(..)`, once `x =` has parsed this
+/// is committed to being a declaration, so a bad value is reported where it goes wrong rather
+/// than `x` being taken as an expression statement.
+fn statement() -> impl Parser<char, Ast, Error = Simple<char>> {
+    let print = text::keyword("print")
+        .padded()
+        .ignore_then(expr())
+        .map(Ast::Print);
+
+    // `fn name(a, b) = body`
+    let func = text::keyword("fn")
+        .padded()
+        .ignore_then(text::ident())
+        .then(
+            text::ident()
+                .padded()
+                .separated_by(just(','))
+                .delimited_by(just('('), just(')')),
+        )
+        .then_ignore(just('=').padded())
+        .then(expr())
+        .map(|((name, params), body)| Ast::Func { name, params, body });
+
+    func.or(print).or(text::ident()
+        .padded()
+        .then_ignore(just('=').then(just('=').not().rewind()).padded())
+        .or_not()
+        .then(expr())
+        .map(|(ident, value)| match ident {
+            Some(ident) => Ast::Let { ident, value },
+            None => Ast::Expr(value),
+        }))
+}
+
+/// A sequence of declarations and expressions, optionally separated by `;`.
+///
+/// A statement that doesn't parse is skipped up to the next `;` or newline, so every bad
+/// statement in the input is reported rather than just the first.
+pub fn program() -> impl Parser<char, Vec<Ast>, Error = Simple<char>> {
+    statement()
+        .map(Some)
+        .recover_with(skip_until([';', '\n'], |_| None))
+        .padded()
+        .then_ignore(just(';').or_not())
+        .repeated()
+        .flatten()
+        .then_ignore(end())
+}
+
+/// Parses a whole source file with `program`, after dropping `//` comments.
+pub fn parse_file(src: &str) -> Result<Vec<Ast>, Vec<Simple<char>>> {
+    // Comments are blanked out rather than removed so that error spans still line up with `src`.
+    let mut uncommented = String::with_capacity(src.len());
+    let mut in_comment = false;
+    let mut chars = src.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\n' {
+            in_comment = false;
+        } else if c == '/' && chars.peek() == Some(&'/') {
+            in_comment = true;
+        }
+        uncommented.push(if in_comment { ' ' } else { c });
+    }
+    program().parse(uncommented)
+}
+
 fn multiple_exprs() -> impl Parser<char, Vec<Expr>, Error = Simple<char>> {
     expr().padded().repeated()
 }
@@ -160,4 +374,207 @@ mod tests {
         parse_decl_eq!("x = 2" => Ast::Let { ident: "x".to_string(), value: Expr::Int(2) });
         parse_decl_eq!(" x = 2 + 2 " => Ast::Let { ident: "x".to_string(), value: Expr::Add(Box::new(Expr::Int(2)), Box::new(Expr::Int(2))) })
     }
+
+    #[test]
+    fn parse_comparison() {
+        parse_expr_eq!("1 < 2" => Compare(Box::new(Int(1)), vec![(CmpOp::Lt, Int(2))]));
+        parse_expr_eq!("1 + 1 >= 2" => Compare(Box::new(Add(Box::new(Int(1)), Box::new(Int(1)))), vec![(CmpOp::Ge, Int(2))]));
+    }
+
+    #[test]
+    fn parse_chained_comparison() {
+        parse_expr_eq!(
+            "0 < x < 10" =>
+            Compare(Box::new(Int(0)), vec![(CmpOp::Lt, Var("x".to_string())), (CmpOp::Lt, Int(10))])
+        );
+        parse_expr_eq!(
+            "a <= b == c != d" =>
+            Compare(
+                Box::new(Var("a".to_string())),
+                vec![
+                    (CmpOp::Le, Var("b".to_string())),
+                    (CmpOp::Eq, Var("c".to_string())),
+                    (CmpOp::Ne, Var("d".to_string())),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn parse_program() {
+        assert_eq!(
+            program().parse("x = 2; y = x * 3\ny - 1").unwrap(),
+            vec![
+                Ast::Let {
+                    ident: "x".to_string(),
+                    value: Int(2)
+                },
+                Ast::Let {
+                    ident: "y".to_string(),
+                    value: Mul(Box::new(Var("x".to_string())), Box::new(Int(3)))
+                },
+                Ast::Expr(Sub(Box::new(Var("y".to_string())), Box::new(Int(1)))),
+            ]
+        );
+    }
+
+    #[test]
+    fn report_every_bad_statement() {
+        let errors = program()
+            .parse("x = 2; y = ); z = 3\nw = * 4\nx + z")
+            .unwrap_err();
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].span(), 11..12);
+        assert_eq!(errors[1].span(), 24..25);
+    }
+
+    #[test]
+    fn out_of_range_integer() {
+        let errors = program().parse("x = 1 + 40000").unwrap_err();
+        assert_eq!(
+            vm::parsing::describe_error(&errors[0]),
+            "integer 40000 out of range for i16"
+        );
+        assert_eq!(errors[0].span(), 8..13);
+    }
+
+    #[test]
+    fn parse_bitwise() {
+        parse_expr_eq!("1 | 2 & 3" => BitOr(
+            Box::new(Int(1)),
+            Box::new(BitAnd(Box::new(Int(2)), Box::new(Int(3)))),
+        ));
+        parse_expr_eq!("1 ^^ 2 | 3 ^^ 4" => BitOr(
+            Box::new(BitXor(Box::new(Int(1)), Box::new(Int(2)))),
+            Box::new(BitXor(Box::new(Int(3)), Box::new(Int(4)))),
+        ));
+        parse_expr_eq!("1 << 2 + 3 >> 1" => Shr(
+            Box::new(Shl(
+                Box::new(Int(1)),
+                Box::new(Add(Box::new(Int(2)), Box::new(Int(3)))),
+            )),
+            Box::new(Int(1)),
+        ));
+        parse_expr_eq!("x & 1 == 2 ^ 2" => Compare(
+            Box::new(BitAnd(Box::new(Var("x".to_string())), Box::new(Int(1)))),
+            vec![(CmpOp::Eq, Pow(Box::new(Int(2)), Box::new(Int(2))))],
+        ));
+    }
+
+    #[test]
+    fn parse_select() {
+        parse_expr_eq!("1 > 0 ? 10 : 20" => Select {
+            cond: Box::new(Compare(Box::new(Int(1)), vec![(CmpOp::Gt, Int(0))])),
+            then: Box::new(Int(10)),
+            else_: Box::new(Int(20)),
+        });
+        parse_expr_eq!("a ? 1 : b ? 2 : 3" => Select {
+            cond: Box::new(Var("a".to_string())),
+            then: Box::new(Int(1)),
+            else_: Box::new(Select {
+                cond: Box::new(Var("b".to_string())),
+                then: Box::new(Int(2)),
+                else_: Box::new(Int(3)),
+            }),
+        });
+    }
+
+    #[test]
+    fn parse_logical() {
+        let var = |name: &str| Box::new(Var(name.to_string()));
+        parse_expr_eq!("a && b || c && d" => Or(
+            Box::new(And(var("a"), var("b"))),
+            Box::new(And(var("c"), var("d"))),
+        ));
+        parse_expr_eq!("a & b && c | d" => And(
+            Box::new(BitAnd(var("a"), var("b"))),
+            Box::new(BitOr(var("c"), var("d"))),
+        ));
+        parse_expr_eq!("1 > 0 && 2 > 3" => And(
+            Box::new(Compare(Box::new(Int(1)), vec![(CmpOp::Gt, Int(0))])),
+            Box::new(Compare(Box::new(Int(2)), vec![(CmpOp::Gt, Int(3))])),
+        ));
+    }
+
+    #[test]
+    fn parse_file_with_comments() {
+        let src = "// Squares\n\nx = 3 // the side\ny = x * x\n\nprint y\ny / x\n";
+        assert_eq!(
+            parse_file(src),
+            Ok(vec![
+                Ast::Let {
+                    ident: "x".to_string(),
+                    value: Int(3)
+                },
+                Ast::Let {
+                    ident: "y".to_string(),
+                    value: Mul(
+                        Box::new(Var("x".to_string())),
+                        Box::new(Var("x".to_string()))
+                    )
+                },
+                Ast::Print(Var("y".to_string())),
+                Ast::Expr(Div(
+                    Box::new(Var("y".to_string())),
+                    Box::new(Var("x".to_string()))
+                )),
+            ])
+        );
+
+        // Errors point into the original source, comments and all.
+        let errs = parse_file("// note\nx = )").unwrap_err();
+        assert_eq!(errs[0].span(), 12..13);
+    }
+
+    #[test]
+    fn parse_functions() {
+        let var = |name: &str| Box::new(Var(name.to_string()));
+        assert_eq!(
+            program().parse("fn add(a, b) = a + b\nadd(1, f())"),
+            Ok(vec![
+                Ast::Func {
+                    name: "add".to_string(),
+                    params: vec!["a".to_string(), "b".to_string()],
+                    body: Add(var("a"), var("b")),
+                },
+                Ast::Expr(Call(
+                    "add".to_string(),
+                    vec![Int(1), Call("f".to_string(), vec![])]
+                )),
+            ])
+        );
+        parse_exprs_eq!("f (1)" => vec![Var("f".to_string()), Int(1)]);
+    }
+
+    #[test]
+    fn parse_print() {
+        assert_eq!(
+            program().parse("print 2 + 3"),
+            Ok(vec![Ast::Print(Add(Box::new(Int(2)), Box::new(Int(3))))])
+        );
+        // Only a whole word is the keyword, and `print` on its own is still a variable.
+        assert_eq!(
+            program().parse("printer = 1; print"),
+            Ok(vec![
+                Ast::Let {
+                    ident: "printer".into(),
+                    value: Int(1)
+                },
+                Ast::Expr(Var("print".into()))
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_block() {
+        parse_expr_eq!("{ x = 2; y = 3; x + y }" => Block {
+            lets: vec![("x".to_string(), Int(2)), ("y".to_string(), Int(3))],
+            value: Box::new(Add(Box::new(Var("x".to_string())), Box::new(Var("y".to_string())))),
+        });
+        parse_expr_eq!("{\n  x = 2\n  x == 2\n}" => Block {
+            lets: vec![("x".to_string(), Int(2))],
+            value: Box::new(Compare(Box::new(Var("x".to_string())), vec![(CmpOp::Eq, Int(2))])),
+        });
+    }
 }
@@ -0,0 +1,79 @@
+use crate::Reg;
+
+/// What integer arithmetic does when the result doesn't fit in a register.
+///
+/// Each operation returns `None` where the VM faults with `VmError::Overflow`, which only ever
+/// happens in `Checked` mode. Division by zero is a separate fault, checked before these are
+/// called.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ArithmeticMode {
+    #[default]
+    Checked,
+    Wrapping,
+    Saturating,
+}
+
+impl std::str::FromStr for ArithmeticMode {
+    type Err = ();
+
+    /// Parses the lowercase name of a mode, e.g. `wrapping`.
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "checked" => Ok(ArithmeticMode::Checked),
+            "wrapping" => Ok(ArithmeticMode::Wrapping),
+            "saturating" => Ok(ArithmeticMode::Saturating),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ArithmeticMode {
+    pub fn add(self, a: Reg, b: Reg) -> Option<Reg> {
+        match self {
+            ArithmeticMode::Checked => a.checked_add(b),
+            ArithmeticMode::Wrapping => Some(a.wrapping_add(b)),
+            ArithmeticMode::Saturating => Some(a.saturating_add(b)),
+        }
+    }
+
+    pub fn sub(self, a: Reg, b: Reg) -> Option<Reg> {
+        match self {
+            ArithmeticMode::Checked => a.checked_sub(b),
+            ArithmeticMode::Wrapping => Some(a.wrapping_sub(b)),
+            ArithmeticMode::Saturating => Some(a.saturating_sub(b)),
+        }
+    }
+
+    pub fn mul(self, a: Reg, b: Reg) -> Option<Reg> {
+        match self {
+            ArithmeticMode::Checked => a.checked_mul(b),
+            ArithmeticMode::Wrapping => Some(a.wrapping_mul(b)),
+            ArithmeticMode::Saturating => Some(a.saturating_mul(b)),
+        }
+    }
+
+    /// `b` must not be zero. The only overflow is `Reg::MIN / -1`.
+    pub fn div(self, a: Reg, b: Reg) -> Option<Reg> {
+        match self {
+            ArithmeticMode::Checked => a.checked_div(b),
+            ArithmeticMode::Wrapping => Some(a.wrapping_div(b)),
+            ArithmeticMode::Saturating => Some(a.saturating_div(b)),
+        }
+    }
+
+    /// `b` must not be zero. `Reg::MIN % -1` is 0 unless checked.
+    pub fn rem(self, a: Reg, b: Reg) -> Option<Reg> {
+        match self {
+            ArithmeticMode::Checked => a.checked_rem(b),
+            ArithmeticMode::Wrapping | ArithmeticMode::Saturating => Some(a.wrapping_rem(b)),
+        }
+    }
+
+    pub fn pow(self, a: Reg, exponent: u32) -> Option<Reg> {
+        match self {
+            ArithmeticMode::Checked => a.checked_pow(exponent),
+            ArithmeticMode::Wrapping => Some(a.wrapping_pow(exponent)),
+            ArithmeticMode::Saturating => Some(a.saturating_pow(exponent)),
+        }
+    }
+}
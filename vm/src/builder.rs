@@ -0,0 +1,305 @@
+use crate::opcode::instructions::Instr;
+use crate::parsing::validate_registers;
+
+pub struct ProgramBuilder {
+    instrs: Vec<Instr>,
+    reg_count: usize,
+}
+
+impl Default for ProgramBuilder {
+    fn default() -> Self {
+        Self::with_register_count(256)
+    }
+}
+
+impl ProgramBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds for a VM with only `reg_count` registers, panicking on any instruction that
+    /// references one beyond that.
+    pub fn with_register_count(reg_count: usize) -> Self {
+        Self {
+            instrs: vec![],
+            reg_count,
+        }
+    }
+
+    pub fn push(mut self, instr: Instr) -> Self {
+        if let Some(warning) = validate_registers(&[instr], self.reg_count).first() {
+            panic!("{:?}: {}", instr, warning);
+        }
+        self.instrs.push(instr);
+        self
+    }
+
+    pub fn halt(self) -> Self {
+        self.push(Instr::Halt)
+    }
+
+    pub fn nop(self) -> Self {
+        self.push(Instr::Nop)
+    }
+
+    pub fn load(self, reg: u8, val: i16) -> Self {
+        self.push(Instr::Load(reg, val))
+    }
+
+    pub fn mov(self, dest: u8, src: u8) -> Self {
+        self.push(Instr::Mov(dest, src))
+    }
+
+    pub fn inc(self, reg: u8) -> Self {
+        self.push(Instr::Inc(reg))
+    }
+
+    pub fn dec(self, reg: u8) -> Self {
+        self.push(Instr::Dec(reg))
+    }
+
+    pub fn add(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::Add(a, b, dest))
+    }
+
+    pub fn sub(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::Subtract(a, b, dest))
+    }
+
+    pub fn mul(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::Multiply(a, b, dest))
+    }
+
+    pub fn div(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::Divide(a, b, dest))
+    }
+
+    pub fn modulo(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::Mod(a, b, dest))
+    }
+
+    pub fn pow(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::Power(a, b, dest))
+    }
+
+    pub fn and(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::And(a, b, dest))
+    }
+
+    pub fn or(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::Or(a, b, dest))
+    }
+
+    pub fn xor(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::Xor(a, b, dest))
+    }
+
+    pub fn shl(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::Shl(a, b, dest))
+    }
+
+    pub fn shr(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::Shr(a, b, dest))
+    }
+
+    pub fn clamp(self, reg: u8, lo: u8, hi: u8) -> Self {
+        self.push(Instr::Clamp(reg, lo, hi))
+    }
+
+    pub fn jmp(self, reg: u8) -> Self {
+        self.push(Instr::Jump(reg))
+    }
+
+    pub fn jmpif(self, reg: u8) -> Self {
+        self.push(Instr::JumpIf(reg))
+    }
+
+    pub fn jmpf(self, reg: u8) -> Self {
+        self.push(Instr::JumpForward(reg))
+    }
+
+    pub fn jmpb(self, reg: u8) -> Self {
+        self.push(Instr::JumpBack(reg))
+    }
+
+    pub fn call(self, reg: u8) -> Self {
+        self.push(Instr::Call(reg))
+    }
+
+    pub fn ret(self) -> Self {
+        self.push(Instr::Return)
+    }
+
+    pub fn trap(self) -> Self {
+        self.push(Instr::Trap)
+    }
+
+    pub fn eq(self, a: u8, b: u8) -> Self {
+        self.push(Instr::Equal(a, b))
+    }
+
+    pub fn assert_eq(self, a: u8, b: u8) -> Self {
+        self.push(Instr::AssertEqual(a, b))
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Self {
+        self.push(Instr::Not)
+    }
+
+    pub fn gt(self, a: u8, b: u8) -> Self {
+        self.push(Instr::GreaterThan(a, b))
+    }
+
+    pub fn gtq(self, a: u8, b: u8) -> Self {
+        self.push(Instr::GreaterThanEqual(a, b))
+    }
+
+    pub fn seq(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::SetEqual(a, b, dest))
+    }
+
+    pub fn sgt(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::SetGreaterThan(a, b, dest))
+    }
+
+    pub fn slt(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::SetLessThan(a, b, dest))
+    }
+
+    pub fn cmov(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::ConditionalMove(a, b, dest))
+    }
+
+    pub fn fload(self, reg: u8, val: f32) -> Self {
+        self.push(Instr::FLoad(reg, val))
+    }
+
+    pub fn fadd(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::FAdd(a, b, dest))
+    }
+
+    pub fn fsub(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::FSubtract(a, b, dest))
+    }
+
+    pub fn fmul(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::FMultiply(a, b, dest))
+    }
+
+    pub fn fdiv(self, a: u8, b: u8, dest: u8) -> Self {
+        self.push(Instr::FDivide(a, b, dest))
+    }
+
+    pub fn itof(self, src: u8, dest: u8) -> Self {
+        self.push(Instr::IntToFloat(src, dest))
+    }
+
+    pub fn ftoi(self, src: u8, dest: u8) -> Self {
+        self.push(Instr::FloatToInt(src, dest))
+    }
+
+    pub fn cycles(self, reg: u8) -> Self {
+        self.push(Instr::Cycles(reg))
+    }
+
+    pub fn out(self, reg: u8) -> Self {
+        self.push(Instr::Out(reg))
+    }
+
+    pub fn input(self, reg: u8) -> Self {
+        self.push(Instr::In(reg))
+    }
+
+    pub fn random(self, reg: u8) -> Self {
+        self.push(Instr::Random(reg))
+    }
+
+    pub fn push_reg(self, reg: u8) -> Self {
+        self.push(Instr::Push(reg))
+    }
+
+    pub fn pop(self, reg: u8) -> Self {
+        self.push(Instr::Pop(reg))
+    }
+
+    pub fn peek(self, reg: u8) -> Self {
+        self.push(Instr::Peek(reg))
+    }
+
+    pub fn drop(self) -> Self {
+        self.push(Instr::Drop)
+    }
+
+    pub fn sdup(self) -> Self {
+        self.push(Instr::StackDup)
+    }
+
+    pub fn sswap(self) -> Self {
+        self.push(Instr::StackSwap)
+    }
+
+    pub fn getf(self, reg: u8) -> Self {
+        self.push(Instr::GetFlag(reg))
+    }
+
+    pub fn rdrem(self, reg: u8) -> Self {
+        self.push(Instr::ReadRemainder(reg))
+    }
+
+    pub fn rload(self, index: u8, dest: u8) -> Self {
+        self.push(Instr::RegLoad(index, dest))
+    }
+
+    pub fn loadm(self, dest: u8, addr: u8) -> Self {
+        self.push(Instr::LoadMem(dest, addr))
+    }
+
+    pub fn storem(self, src: u8, addr: u8) -> Self {
+        self.push(Instr::StoreMem(src, addr))
+    }
+
+    pub fn build(self) -> Vec<Instr> {
+        self.instrs
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.instrs.iter().flat_map(|i| i.to_bytes()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_program() {
+        let builder = ProgramBuilder::new()
+            .load(0, 1)
+            .load(1, 2)
+            .add(0, 1, 2)
+            .halt();
+
+        assert_eq!(
+            builder.to_bytes(),
+            vec![1, 0, 0, 1, 1, 1, 0, 2, 2, 0, 1, 2, 0]
+        );
+        assert_eq!(
+            builder.build(),
+            vec![
+                Instr::Load(0, 1),
+                Instr::Load(1, 2),
+                Instr::Add(0, 1, 2),
+                Instr::Halt
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn register_out_of_range() {
+        ProgramBuilder::with_register_count(16)
+            .load(0, 1)
+            .add(0, 16, 2);
+    }
+}
@@ -0,0 +1,274 @@
+use std::{collections::VecDeque, fs, io, path::Path};
+
+use crate::{arithmetic::ArithmeticMode, cost::CostModel, Reg, VM};
+
+const MAGIC: &[u8; 8] = b"HALIDEVM";
+const VERSION: u8 = 6;
+
+impl VM {
+    /// Writes everything needed to carry on running later to `path`: the program, registers,
+    /// `pc`, flag, remainder, both stacks, memory, the counters, `RND`'s state, any recording or
+    /// replay in progress, and settings like the arithmetic mode, cost model and zero register. Breakpoints, coverage, captured output and the decode cache aren't kept.
+    pub fn save_checkpoint(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.checkpoint())
+    }
+
+    /// Restores a VM written by `save_checkpoint`. Fails with `InvalidData` if the file isn't
+    /// a checkpoint, or is from an incompatible version.
+    pub fn load_checkpoint(path: impl AsRef<Path>) -> io::Result<VM> {
+        VM::restore(&fs::read(path)?)
+    }
+
+    fn checkpoint(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+
+        // Everything is big-endian, like immediates in bytecode.
+        for r in self.registers {
+            out.extend(r.to_be_bytes());
+        }
+        for r in self.fregisters {
+            out.extend(r.to_bits().to_be_bytes());
+        }
+        put_u64(&mut out, self.pc as u64);
+        out.extend(self.remainder.to_be_bytes());
+        out.push(self.cmp as u8);
+        out.push(self.trapped as u8);
+        put_u64(&mut out, self.cycles);
+        put_u64(&mut out, self.total_cost);
+        put_u64(&mut out, self.max_call_depth as u64);
+        put_u64(&mut out, self.max_stack as u64);
+        put_u64(&mut out, self.rng);
+        put_values(&mut out, self.recording.as_ref().map(|r| r.iter()));
+        put_values(&mut out, self.replay.as_ref().map(|r| r.iter()));
+        out.push(match self.arithmetic {
+            ArithmeticMode::Checked => 0,
+            ArithmeticMode::Wrapping => 1,
+            ArithmeticMode::Saturating => 2,
+        });
+        out.push(self.zero_register as u8);
+        for w in self.cost_model.weights {
+            put_u64(&mut out, w);
+        }
+        put_u64(&mut out, self.call_stack.len() as u64);
+        for &ret in &self.call_stack {
+            put_u64(&mut out, ret as u64);
+        }
+        put_u64(&mut out, self.stack.len() as u64);
+        for v in &self.stack {
+            out.extend(v.to_be_bytes());
+        }
+        put_u64(&mut out, self.memory.len() as u64);
+        out.extend(&self.memory);
+        put_u64(&mut out, self.program.len() as u64);
+        out.extend(&self.program);
+        out
+    }
+
+    fn restore(bytes: &[u8]) -> io::Result<VM> {
+        let mut r = Reader { bytes };
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a halide checkpoint"));
+        }
+        if r.byte()? != VERSION {
+            return Err(invalid("unsupported checkpoint version"));
+        }
+
+        let mut vm = VM::default();
+        for reg in &mut vm.registers {
+            *reg = r.u64()? as Reg;
+        }
+        for reg in &mut vm.fregisters {
+            *reg = f32::from_bits(r.u32()?);
+        }
+        vm.pc = r.len()?;
+        vm.remainder = r.u64()? as Reg;
+        vm.cmp = r.bool()?;
+        vm.trapped = r.bool()?;
+        vm.cycles = r.u64()?;
+        vm.total_cost = r.u64()?;
+        vm.max_call_depth = r.len()?;
+        vm.max_stack = r.len()?;
+        vm.rng = r.u64()?;
+        vm.recording = r.values()?;
+        vm.replay = r.values()?.map(VecDeque::from);
+        vm.arithmetic = match r.byte()? {
+            0 => ArithmeticMode::Checked,
+            1 => ArithmeticMode::Wrapping,
+            2 => ArithmeticMode::Saturating,
+            _ => return Err(invalid("unknown arithmetic mode")),
+        };
+        vm.zero_register = r.bool()?;
+        let mut cost_model = CostModel::uniform(0);
+        for w in &mut cost_model.weights {
+            *w = r.u64()?;
+        }
+        vm.cost_model = cost_model;
+        for _ in 0..r.len()? {
+            vm.call_stack.push(r.len()?);
+        }
+        for _ in 0..r.len()? {
+            vm.stack.push(r.u64()? as Reg);
+        }
+        let len = r.len()?;
+        vm.memory = r.take(len)?.to_vec();
+        let len = r.len()?;
+        vm.program = r.take(len)?.to_vec();
+
+        if !r.bytes.is_empty() {
+            return Err(invalid("trailing bytes after checkpoint"));
+        }
+        Ok(vm)
+    }
+}
+
+fn put_u64(out: &mut Vec<u8>, v: u64) {
+    out.extend(v.to_be_bytes());
+}
+
+/// A list of values that might not be there, like the recording.
+fn put_values<'a>(out: &mut Vec<u8>, values: Option<impl ExactSizeIterator<Item = &'a Reg>>) {
+    match values {
+        None => out.push(0),
+        Some(values) => {
+            out.push(1);
+            put_u64(out, values.len() as u64);
+            for v in values {
+                out.extend(v.to_be_bytes());
+            }
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if n > self.bytes.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> io::Result<bool> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("expected a boolean")),
+        }
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// A length or offset, which has to fit in a `usize` here.
+    fn len(&mut self) -> io::Result<usize> {
+        usize::try_from(self.u64()?).map_err(|_| invalid("length too large"))
+    }
+
+    /// A list written by `put_values`.
+    fn values(&mut self) -> io::Result<Option<Vec<Reg>>> {
+        if !self.bool()? {
+            return Ok(None);
+        }
+        let mut values = vec![];
+        for _ in 0..self.len()? {
+            values.push(self.u64()? as Reg);
+        }
+        Ok(Some(values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{arithmetic::ArithmeticMode, builder::ProgramBuilder, Recording, VM};
+
+    #[test]
+    fn resume_from_checkpoint() {
+        // Sums 1 to 100 into $0, with some stack, memory and RND traffic along the way.
+        let program = ProgramBuilder::new()
+            .load(1, 1)
+            .load(2, 100)
+            .load(3, 12)
+            .add(0, 1, 0)
+            .random(5)
+            .push_reg(5)
+            .storem(0, 1)
+            .load(4, 1)
+            .add(1, 4, 1)
+            .gtq(2, 1)
+            .jmpif(3)
+            .to_bytes();
+
+        let mut uninterrupted = VM::with_program(program.clone());
+        uninterrupted.set_arithmetic(ArithmeticMode::Wrapping);
+        uninterrupted.seed_random(7);
+        uninterrupted.start_recording();
+        uninterrupted.run().unwrap();
+
+        let mut first = VM::with_program(program);
+        first.set_arithmetic(ArithmeticMode::Wrapping);
+        first.seed_random(7);
+        first.start_recording();
+        first.set_max_stack(1000);
+        first.run_for(150).unwrap();
+        let path = std::env::temp_dir().join("halide-checkpoint-test.bin");
+        first.save_checkpoint(&path).unwrap();
+
+        let mut resumed = VM::load_checkpoint(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(resumed.state(), first.state());
+        assert_eq!(resumed.arithmetic, ArithmeticMode::Wrapping);
+        assert_eq!(resumed.max_stack, 1000);
+        resumed.run().unwrap();
+
+        assert_eq!(resumed.registers[0], 5050);
+        assert_eq!(resumed.state(), uninterrupted.state());
+        assert_eq!(resumed.total_cost(), uninterrupted.total_cost());
+        assert_eq!(resumed.take_recording(), uninterrupted.take_recording());
+    }
+
+    #[test]
+    fn resume_replay_from_checkpoint() {
+        let program = ProgramBuilder::new().random(0).random(1).to_bytes();
+        let recording = Recording {
+            values: vec![10, 20],
+        };
+        let mut first = VM::with_program(program).with_recording(recording);
+        first.run_for(1).unwrap();
+
+        let mut resumed = VM::restore(&first.checkpoint()).unwrap();
+        resumed.run().unwrap();
+        assert_eq!(resumed.registers[..2], [10, 20]);
+    }
+
+    #[test]
+    fn reject_bad_checkpoints() {
+        let bytes = VM::with_program(vec![0]).checkpoint();
+        assert!(VM::restore(&bytes).is_ok());
+        assert!(VM::restore(&bytes[..bytes.len() - 1]).is_err());
+        assert!(VM::restore(b"not a checkpoint").is_err());
+
+        let mut newer = bytes.clone();
+        newer[8] = super::VERSION + 1;
+        let err = VM::restore(&newer).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
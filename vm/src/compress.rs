@@ -0,0 +1,231 @@
+//! A Huffman coding of bytecode. Opcodes and small register numbers make up most of a typical
+//! program, so they end up with codes of a few bits.
+//!
+//! The format is the original length as a big-endian `u32`, then the number of distinct bytes
+//! as a `u16`, then each byte and its code length, sorted by length and then byte. The codes
+//! are canonical, so that's enough to rebuild them. The packed codes follow, most significant
+//! bit first.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+};
+
+/// Compresses `program`, so that `decompress` gives it back exactly. Programs are assumed to
+/// be under 4 GiB, like any the VM will load.
+pub fn compress(program: &[u8]) -> Vec<u8> {
+    let mut counts = [0u64; 256];
+    for &byte in program {
+        counts[byte as usize] += 1;
+    }
+    let table = canonical(code_lengths(&counts));
+
+    let mut out = (program.len() as u32).to_be_bytes().to_vec();
+    out.extend((table.len() as u16).to_be_bytes());
+    for &(byte, len, _) in &table {
+        out.extend([byte, len]);
+    }
+
+    let mut codes = [(0, 0); 256];
+    for &(byte, len, code) in &table {
+        codes[byte as usize] = (len, code);
+    }
+    let mut bits = BitWriter::default();
+    for &byte in program {
+        let (len, code) = codes[byte as usize];
+        bits.write(code, len);
+    }
+    out.extend(bits.finish());
+    out
+}
+
+/// The program `compress` was given, or `None` if `bytes` didn't come from `compress`.
+pub fn decompress(bytes: &[u8]) -> Option<Vec<u8>> {
+    let len = u32::from_be_bytes(bytes.get(..4)?.try_into().unwrap()) as usize;
+    let symbols = u16::from_be_bytes(bytes.get(4..6)?.try_into().unwrap()) as usize;
+    let lengths = bytes.get(6..6 + 2 * symbols)?;
+    let data = &bytes[6 + 2 * symbols..];
+
+    // Every byte takes at least a bit, which also stops a bad length allocating too much.
+    if len > data.len() * 8 {
+        return None;
+    }
+
+    let mut table: Vec<(u8, u8)> = lengths.chunks(2).map(|c| (c[0], c[1])).collect();
+    if table.iter().any(|&(_, len)| len == 0 || len > 64) {
+        return None;
+    }
+    table.sort_by_key(|&(byte, len)| (len, byte));
+    let codes: HashMap<(u8, u64), u8> = canonical(table)
+        .into_iter()
+        .map(|(byte, len, code)| ((len, code), byte))
+        .collect();
+
+    let mut program = Vec::with_capacity(len);
+    let mut bits = BitReader { data, at: 0 };
+    while program.len() < len {
+        let (mut code, mut code_len) = (0u64, 0u8);
+        let byte = loop {
+            if code_len == 64 {
+                return None;
+            }
+            code = (code << 1) | bits.read()? as u64;
+            code_len += 1;
+            if let Some(&byte) = codes.get(&(code_len, code)) {
+                break byte;
+            }
+        };
+        program.push(byte);
+    }
+    Some(program)
+}
+
+/// How many bits each byte in `counts` gets, for the bytes that appear at all.
+fn code_lengths(counts: &[u64; 256]) -> Vec<(u8, u8)> {
+    // Leaves are the bytes, and each merge adds a node with the two it merged as children.
+    let mut parents: Vec<Option<usize>> = vec![];
+    let mut leaves = vec![];
+    let mut heap = BinaryHeap::new();
+    for (byte, &count) in counts.iter().enumerate() {
+        if count > 0 {
+            heap.push(Reverse((count, parents.len())));
+            leaves.push((byte as u8, parents.len()));
+            parents.push(None);
+        }
+    }
+    if leaves.len() == 1 {
+        return vec![(leaves[0].0, 1)];
+    }
+
+    while heap.len() > 1 {
+        let Reverse((a, i)) = heap.pop().unwrap();
+        let Reverse((b, j)) = heap.pop().unwrap();
+        let node = parents.len();
+        parents.push(None);
+        parents[i] = Some(node);
+        parents[j] = Some(node);
+        heap.push(Reverse((a + b, node)));
+    }
+
+    let mut lengths: Vec<(u8, u8)> = leaves
+        .into_iter()
+        .map(|(byte, mut node)| {
+            let mut depth = 0;
+            while let Some(parent) = parents[node] {
+                node = parent;
+                depth += 1;
+            }
+            (byte, depth)
+        })
+        .collect();
+    lengths.sort_by_key(|&(byte, len)| (len, byte));
+    lengths
+}
+
+/// Assigns canonical codes to `lengths`, which is sorted by length and then byte.
+fn canonical(lengths: Vec<(u8, u8)>) -> Vec<(u8, u8, u64)> {
+    let mut code = 0u64;
+    let mut prev_len = lengths.first().map_or(0, |&(_, len)| len);
+    lengths
+        .into_iter()
+        .map(|(byte, len)| {
+            code <<= len - prev_len;
+            prev_len = len;
+            let assigned = code;
+            code += 1;
+            (byte, len, assigned)
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits used in the last byte, from 0 to 7.
+    used: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u64, len: u8) {
+        for i in (0..len).rev() {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            let bit = (code >> i) as u8 & 1;
+            *self.bytes.last_mut().unwrap() |= bit << (7 - self.used);
+            self.used = (self.used + 1) % 8;
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// The next bit to read, counting from the start of `data`.
+    at: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self) -> Option<u8> {
+        let byte = self.data.get(self.at / 8)?;
+        let bit = (byte >> (7 - self.at % 8)) & 1;
+        self.at += 1;
+        Some(bit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ProgramBuilder;
+
+    #[test]
+    fn compress_round_trip() {
+        let programs = [
+            vec![],
+            vec![7],
+            vec![0; 100],
+            (0..=255).collect(),
+            ProgramBuilder::new()
+                .load(0, 10)
+                .load(1, -1)
+                .add(0, 1, 0)
+                .gt(0, 2)
+                .jmpif(3)
+                .halt()
+                .to_bytes(),
+        ];
+        for program in programs {
+            assert_eq!(decompress(&compress(&program)), Some(program));
+        }
+    }
+
+    #[test]
+    fn repetitive_programs_shrink() {
+        let mut builder = ProgramBuilder::new();
+        for i in 0..100 {
+            builder = builder.load(i % 4, 1).add(0, 1, 2).sub(2, 1, 0);
+        }
+        let program = builder.to_bytes();
+
+        let compressed = compress(&program);
+        assert!(
+            compressed.len() < program.len() / 2,
+            "{} bytes compressed to {}",
+            program.len(),
+            compressed.len()
+        );
+        assert_eq!(decompress(&compressed), Some(program));
+    }
+
+    #[test]
+    fn decompress_rejects_garbage() {
+        let compressed = compress(&[1, 2, 3, 3, 3]);
+        assert_eq!(decompress(&compressed[..compressed.len() - 1]), None);
+        assert_eq!(decompress(&[0, 0]), None);
+        assert_eq!(decompress(&[0, 0, 0, 5, 0, 1, 42, 0]), None);
+    }
+}
@@ -0,0 +1,39 @@
+use crate::opcode::Opcode;
+
+/// How much each opcode adds to `VM::total_cost`, a rough measure of work done that's used to
+/// budget expensive instructions more tightly than a plain cycle count would.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CostModel {
+    pub(crate) weights: [u64; 256],
+}
+
+impl Default for CostModel {
+    /// Everything costs 1, except multiplication, division and exponentiation.
+    fn default() -> Self {
+        Self::uniform(1)
+            .with(Opcode::MUL, 3)
+            .with(Opcode::FMUL, 3)
+            .with(Opcode::DIV, 5)
+            .with(Opcode::MOD, 5)
+            .with(Opcode::FDIV, 5)
+            .with(Opcode::POW, 10)
+    }
+}
+
+impl CostModel {
+    /// Every opcode costs `cost`.
+    pub fn uniform(cost: u64) -> Self {
+        Self {
+            weights: [cost; 256],
+        }
+    }
+
+    pub fn with(mut self, opcode: Opcode, cost: u64) -> Self {
+        self.weights[u8::from(opcode) as usize] = cost;
+        self
+    }
+
+    pub fn cost(&self, opcode: Opcode) -> u64 {
+        self.weights[u8::from(opcode) as usize]
+    }
+}
@@ -0,0 +1,100 @@
+//! Inputs that random bytes found to be interesting, and a check that arbitrary bytes can't make
+//! the VM panic.
+
+use crate::{
+    arithmetic::ArithmeticMode,
+    opcode::instructions::{disassemble, disassemble_text},
+    VmError, VM,
+};
+
+fn run(program: &[u8]) -> Result<crate::RunOutcome, VmError> {
+    let mut vm = VM::with_program(program.to_vec());
+    vm.capture_output();
+    vm.provide_input([]);
+    vm.run_for(10_000)
+}
+
+#[test]
+fn operands_past_the_end() {
+    assert_eq!(run(&[1, 0]), Err(VmError::ProgramCounterOutOfBounds(2)));
+    assert_eq!(run(&[2, 0, 0]), Err(VmError::ProgramCounterOutOfBounds(3)));
+}
+
+#[test]
+fn divide_by_zero() {
+    // DIV $0 $1 $2 with every register still 0.
+    assert_eq!(run(&[5, 0, 1, 2]), Err(VmError::DivideByZero));
+}
+
+#[test]
+fn divide_min_by_minus_one() {
+    // $0 = -32768 * 256^6 = i64::MIN, then DIV $0 $1 $3 with $1 = -1.
+    let mut program = vec![1, 0, 0x80, 0x00, 1, 1, 0xFF, 0xFF, 1, 2, 0x01, 0x00];
+    for _ in 0..6 {
+        program.extend([4, 0, 2, 0]);
+    }
+    program.extend([5, 0, 1, 3]);
+    assert_eq!(run(&program), Err(VmError::Overflow));
+}
+
+#[test]
+fn pow_with_bad_exponents() {
+    // POW $0 $1 $2 with $1 = -1.
+    assert_eq!(
+        run(&[1, 1, 0xFF, 0xFF, 6, 0, 1, 2]),
+        Err(VmError::NegativeExponent)
+    );
+    // POW $0 $1 $2 with $0 = 2 and $1 = 32767.
+    assert_eq!(
+        run(&[1, 0, 0, 2, 1, 1, 0x7F, 0xFF, 6, 0, 1, 2]),
+        Err(VmError::Overflow)
+    );
+}
+
+#[test]
+fn illegal_opcodes() {
+    assert_eq!(run(&[255]), Err(VmError::IllegalOpcode(255)));
+    assert_eq!(run(&[200, 1, 2, 3]), Err(VmError::IllegalOpcode(200)));
+}
+
+/// A xorshift generator, so failures reproduce without any extra dependencies.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[test]
+fn random_programs_never_panic() {
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+    for _ in 0..5_000 {
+        // Mostly small bytes, so that most opcodes are real and programs get past the first
+        // instruction.
+        let len = rng.next() % 48;
+        let program: Vec<u8> = (0..len)
+            .map(|_| match rng.next() % 4 {
+                0 => rng.next() as u8,
+                _ => (rng.next() % 48) as u8,
+            })
+            .collect();
+
+        for mode in [
+            ArithmeticMode::Checked,
+            ArithmeticMode::Wrapping,
+            ArithmeticMode::Saturating,
+        ] {
+            let mut vm = VM::with_program(program.clone());
+            vm.capture_output();
+            vm.provide_input([]);
+            vm.set_arithmetic(mode);
+            let _ = vm.run_for(1_000);
+        }
+        disassemble(&program);
+        disassemble_text(&program);
+    }
+}
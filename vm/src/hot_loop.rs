@@ -0,0 +1,253 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{opcode::instructions::Instr, RunOutcome, VmError, VM};
+
+/// The most instructions a loop body can have and still be specialized.
+const MAX_BODY: usize = 64;
+
+/// A loop decoded ahead of time: straight-line register-only code from `start`, closed by the
+/// jump back to it.
+struct HotLoop {
+    start: usize,
+    /// Each instruction with its offset and length, ending with the jump.
+    body: Vec<(usize, Instr, usize)>,
+}
+
+impl HotLoop {
+    /// The loop from `start` to the jump at `jump`, if everything in between is simple enough to
+    /// specialize and none of it has a breakpoint.
+    fn detect(
+        program: &[u8],
+        start: usize,
+        jump: usize,
+        breakpoints: &HashSet<usize>,
+    ) -> Option<HotLoop> {
+        let mut body = vec![];
+        let mut pc = start;
+        while pc < jump && body.len() < MAX_BODY {
+            let (instr, len) = Instr::from_bytes(&program[pc..])?;
+            if !registers_only(instr) || breakpoints.contains(&pc) {
+                return None;
+            }
+            body.push((pc, instr, len));
+            pc += len;
+        }
+
+        let (instr, len) = Instr::from_bytes(&program[jump..])?;
+        let closes = matches!(
+            instr,
+            Instr::Jump(_) | Instr::JumpIf(_) | Instr::JumpBack(_)
+        );
+        if pc != jump || !closes || breakpoints.contains(&jump) {
+            return None;
+        }
+        body.push((jump, instr, len));
+        Some(HotLoop { start, body })
+    }
+}
+
+/// Whether `instr` only reads and writes registers and the flag, and always carries on to the
+/// next instruction.
+fn registers_only(instr: Instr) -> bool {
+    matches!(
+        instr,
+        Instr::Nop
+            | Instr::Load(..)
+            | Instr::Mov(..)
+            | Instr::Inc(..)
+            | Instr::Dec(..)
+            | Instr::Add(..)
+            | Instr::Subtract(..)
+            | Instr::Multiply(..)
+            | Instr::Divide(..)
+            | Instr::Mod(..)
+            | Instr::Power(..)
+            | Instr::And(..)
+            | Instr::Or(..)
+            | Instr::Xor(..)
+            | Instr::Shl(..)
+            | Instr::Shr(..)
+            | Instr::Clamp(..)
+            | Instr::Equal(..)
+            | Instr::Not
+            | Instr::GreaterThan(..)
+            | Instr::GreaterThanEqual(..)
+            | Instr::SetEqual(..)
+            | Instr::SetGreaterThan(..)
+            | Instr::SetLessThan(..)
+            | Instr::ConditionalMove(..)
+    )
+}
+
+impl VM {
+    /// Like `run`, but once a jump backwards closes a short loop of register arithmetic and
+    /// comparisons, the loop body is decoded once and repeated without decoding it again, or
+    /// checking for breakpoints on every instruction. Everything else runs as in `run`, and the
+    /// result is the same either way.
+    pub fn run_specialized(&mut self) -> Result<RunOutcome, VmError> {
+        // Keyed by the loop's start, with `None` for loops that can't be specialized.
+        let mut loops: HashMap<usize, Option<HotLoop>> = HashMap::new();
+        let start = self.pc;
+        loop {
+            if self.pc != start && self.breakpoints.contains(&self.pc) {
+                return Ok(RunOutcome::Breakpoint);
+            }
+            let at = self.pc;
+            if let Some(outcome) = self.execute_once()? {
+                return Ok(outcome);
+            }
+
+            if self.pc < at {
+                let hot = loops.entry(self.pc).or_insert_with(|| {
+                    HotLoop::detect(&self.program, self.pc, at, &self.breakpoints)
+                });
+                if let Some(hot) = hot {
+                    self.run_hot_loop(hot)?;
+                }
+            }
+        }
+    }
+
+    /// Goes around `hot` until its jump isn't taken, leaving `pc` wherever that went.
+    fn run_hot_loop(&mut self, hot: &HotLoop) -> Result<(), VmError> {
+        // The body is the same every time round, so coverage only needs recording once.
+        for &(at, ..) in &hot.body {
+            self.executed.insert(at);
+        }
+        loop {
+            for &(at, instr, len) in &hot.body {
+                self.pc = at;
+                self.cycles += 1;
+                let result = self.execute(instr, len);
+                self.fault_at(at, result)?;
+            }
+            if self.pc != hot.start {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{arithmetic::ArithmeticMode, builder::ProgramBuilder, RunOutcome, VmError, VM};
+
+    /// Runs `program` both ways and checks they end up in exactly the same state.
+    fn assert_same_as_run(program: Vec<u8>, mode: ArithmeticMode) -> VM {
+        let mut generic = VM::with_program(program.clone());
+        generic.set_arithmetic(mode);
+        let mut specialized = VM::with_program(program);
+        specialized.set_arithmetic(mode);
+
+        assert_eq!(specialized.run_specialized(), generic.run());
+        assert_eq!(specialized.state(), generic.state());
+        assert_eq!(specialized.total_cost(), generic.total_cost());
+        assert_eq!(specialized.coverage(), generic.coverage());
+        assert_eq!(specialized.last_error(), generic.last_error());
+        specialized
+    }
+
+    #[test]
+    fn specialized_loop_matches_run() {
+        // Sums 10 down to 1 into $1.
+        let sum = ProgramBuilder::new()
+            .load(0, 10)
+            .load(1, 0)
+            .load(2, 1)
+            .load(3, 0)
+            .load(4, 20)
+            .add(1, 0, 1)
+            .sub(0, 2, 0)
+            .gt(0, 3)
+            .jmpif(4)
+            .to_bytes();
+        let vm = assert_same_as_run(sum, ArithmeticMode::Checked);
+        assert_eq!(vm.registers[1], 55);
+
+        // The same sum closed by a JMPB, whose distance drops to 0 to leave the loop.
+        let sum_back = ProgramBuilder::new()
+            .load(0, 10)
+            .load(1, 0)
+            .load(2, 1)
+            .load(3, 0)
+            .load(4, 17)
+            .load(5, 0)
+            .add(1, 0, 1)
+            .sub(0, 2, 0)
+            .gt(0, 3)
+            .cmov(4, 5, 6)
+            .jmpb(6)
+            .to_bytes();
+        let vm = assert_same_as_run(sum_back, ArithmeticMode::Checked);
+        assert_eq!(vm.registers[1], 55);
+
+        // Doubles $0 until it overflows, which faults partway round the loop unless wrapping.
+        let doubling = ProgramBuilder::new()
+            .load(0, 1)
+            .load(1, 0)
+            .load(2, 12)
+            .add(0, 0, 0)
+            .sgt(0, 1, 3)
+            .eq(0, 1)
+            .not()
+            .jmpif(2)
+            .to_bytes();
+        let vm = assert_same_as_run(doubling.clone(), ArithmeticMode::Checked);
+        assert_eq!(vm.last_error(), Some(&VmError::Overflow));
+        assert_same_as_run(doubling, ArithmeticMode::Wrapping);
+
+        // A loop with a stack operation in it isn't specialized, but still runs.
+        let pushing = ProgramBuilder::new()
+            .load(0, 3)
+            .load(1, 1)
+            .load(2, 0)
+            .load(3, 16)
+            .push_reg(0)
+            .sub(0, 1, 0)
+            .gt(0, 2)
+            .jmpif(3)
+            .to_bytes();
+        assert_same_as_run(pushing, ArithmeticMode::Checked);
+    }
+
+    #[test]
+    fn specialized_loop_stops_at_breakpoints() {
+        let program = ProgramBuilder::new()
+            .load(0, 0)
+            .load(1, 1)
+            .load(2, 12)
+            .add(0, 1, 0)
+            .jmp(2)
+            .to_bytes();
+        let mut vm = VM::with_program(program);
+        // A resumed run never stops where it starts, so with one breakpoint in the loop it
+        // would go round forever. With two, each run stops at the other one.
+        vm.set_breakpoint(12);
+        vm.set_breakpoint(16);
+        for (pc, expected) in [(12, 0), (16, 1), (12, 1), (16, 2)] {
+            assert_eq!(vm.run_specialized(), Ok(RunOutcome::Breakpoint));
+            assert_eq!((vm.state().pc, vm.registers[0]), (pc, expected));
+        }
+    }
+
+    #[test]
+    fn specialized_long_loop() {
+        // Counts $0 up to 1,000,000 in steps of 1, with another add in the body.
+        let program = ProgramBuilder::new()
+            .load(1, 1)
+            .load(2, 1000)
+            .mul(2, 2, 2)
+            .load(4, 16)
+            .add(0, 1, 0)
+            .add(3, 0, 3)
+            .gt(2, 0)
+            .jmpif(4)
+            .to_bytes();
+        let mut vm = VM::with_program(program);
+        vm.set_arithmetic(ArithmeticMode::Wrapping);
+        assert_eq!(vm.run_specialized(), Ok(RunOutcome::FellOffEnd));
+        assert_eq!(vm.registers[0], 1_000_000);
+        assert_eq!(vm.registers[3], 1_000_000 * 1_000_001 / 2);
+        assert_eq!(vm.cycles(), 4 + 4 * 1_000_000);
+    }
+}
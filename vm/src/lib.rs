@@ -1,27 +1,388 @@
 #![allow(dead_code)]
 
+pub mod arithmetic;
+pub mod builder;
+mod checkpoint;
+pub mod compress;
+pub mod cost;
+#[cfg(test)]
+mod fuzz;
+mod hot_loop;
 pub mod opcode;
 pub mod parsing;
+pub mod passes;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use arithmetic::ArithmeticMode;
+use cost::CostModel;
+use opcode::{
+    instructions::{disassemble, Instr},
+    Opcode,
+};
+
+/// The type of an integer register.
+pub type Reg = i64;
+
+/// Where `OUT` writes to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Output {
+    #[default]
+    Stdout,
+    Captured(String),
+}
+
+/// Where `IN` reads from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Input {
+    /// A number per line.
+    #[default]
+    Stdin,
+    Queued(VecDeque<Reg>),
+}
+
+/// Every value `IN` and `RND` produced during a run, in order, so that the run can be repeated
+/// exactly. See `VM::start_recording` and `VM::with_recording`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recording {
+    pub values: Vec<Reg>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    IllegalOpcode(u8),
+    /// Execution needed a byte past the end of the program, e.g. an instruction's operands.
+    ProgramCounterOutOfBounds(usize),
+    DivideByZero,
+    Overflow,
+    NegativeExponent,
+    CycleLimitExceeded(u64),
+    /// A `CALL` would have nested deeper than the VM's maximum call depth.
+    CallStackOverflow,
+    /// A `RET` with no `CALL` to return from.
+    CallStackUnderflow,
+    /// The jump at byte offset `pc` tried to go to `target`, which is outside the program.
+    BadJumpTarget {
+        target: Reg,
+        pc: usize,
+    },
+    /// A `POP` or `PEEK` with nothing on the stack.
+    StackUnderflow,
+    /// A `PUSH` or `SDUP` would have grown the stack past the VM's maximum stack size.
+    StackOverflow,
+    /// The `ASSERTEQ` at byte offset `pc` compared two unequal registers.
+    AssertionFailed {
+        pc: usize,
+    },
+    /// A `CLAMP` whose lower bound is above its upper bound.
+    EmptyClampRange {
+        lo: Reg,
+        hi: Reg,
+    },
+    /// A write to the program at byte offset `at`, which the VM's `CodeWritePolicy` rejects.
+    SelfModifyingCode {
+        at: usize,
+    },
+    /// A write of `len` bytes to the program at byte offset `at` that would run past its end.
+    CodeWriteOutOfBounds {
+        at: usize,
+        len: usize,
+    },
+    /// `load_program` was given `len` bytes, more than the VM's maximum program size.
+    ProgramTooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// A `LOADM` or `STOREM` of an address outside the VM's memory.
+    MemoryOutOfBounds(Reg),
+    /// An `IN` with no input left, or with input that isn't a number.
+    NoInput,
+    /// An `IN` or `RND` after every value in the recording being replayed was used up.
+    RecordingExhausted,
+}
+
+impl std::fmt::Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmError::IllegalOpcode(byte) => write!(f, "illegal opcode {:#04X}", byte),
+            VmError::ProgramCounterOutOfBounds(pc) => {
+                write!(f, "program counter {} is past the end of the program", pc)
+            }
+            VmError::DivideByZero => write!(f, "division by zero"),
+            VmError::Overflow => write!(f, "arithmetic overflow"),
+            VmError::NegativeExponent => write!(f, "negative exponent"),
+            VmError::CycleLimitExceeded(limit) => {
+                write!(f, "cycle limit of {} instructions exceeded", limit)
+            }
+            VmError::CallStackOverflow => write!(f, "call stack overflow"),
+            VmError::CallStackUnderflow => write!(f, "return with an empty call stack"),
+            VmError::BadJumpTarget { target, pc } => {
+                write!(f, "jump at {} to {}, outside the program", pc, target)
+            }
+            VmError::StackUnderflow => write!(f, "pop from an empty stack"),
+            VmError::StackOverflow => write!(f, "stack overflow"),
+            VmError::AssertionFailed { pc } => write!(f, "assertion at {} failed", pc),
+            VmError::EmptyClampRange { lo, hi } => {
+                write!(f, "can't clamp to {}..={}, the range is empty", lo, hi)
+            }
+            VmError::SelfModifyingCode { at } => {
+                write!(f, "write to the program at {} while it's running", at)
+            }
+            VmError::CodeWriteOutOfBounds { at, len } => {
+                write!(f, "write of {} bytes at {} runs past the program", len, at)
+            }
+            VmError::ProgramTooLarge { len, max } => {
+                write!(f, "program of {} bytes is over the limit of {}", len, max)
+            }
+            VmError::MemoryOutOfBounds(addr) => {
+                write!(f, "memory address {} is out of bounds", addr)
+            }
+            VmError::NoInput => write!(f, "no number to read for IN"),
+            VmError::RecordingExhausted => write!(f, "the replayed recording ran out"),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// An environment variable read by `VM::from_env` that has a value it can't use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvError {
+    pub var: &'static str,
+    pub value: String,
+    pub expected: &'static str,
+}
+
+impl std::fmt::Display for EnvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is set to `{}`, expected {}",
+            self.var, self.value, self.expected
+        )
+    }
+}
+
+impl std::error::Error for EnvError {}
+
+/// What `VM::write_code` does with a write into the program.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CodeWritePolicy {
+    /// Fail with `VmError::SelfModifyingCode`, leaving the program as it was.
+    #[default]
+    Reject,
+    /// Make the write, and invalidate the decode cache so the new code is what runs.
+    Invalidate,
+}
+
+/// How a run ended without faulting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// Executed a `HLT`.
+    Halted,
+    /// Ran out of instructions, i.e. `pc` moved past the end of the program.
+    FellOffEnd,
+    /// Used up the cycles it was given, see `VM::run_for`.
+    LimitReached,
+    /// Reached a breakpoint set with `VM::set_breakpoint`.
+    Breakpoint,
+    /// Executed a `TRAP`. `pc` is left on it, and running again carries on after it.
+    Trap,
+}
+
+/// What executing a single instruction did, see `VM::steps`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// There may be more to run.
+    Continued,
+    /// The instruction was a `HLT`.
+    Halted,
+}
+
+/// Iterator returned by `VM::steps`.
+pub struct Steps<'a> {
+    vm: &'a mut VM,
+    done: bool,
+}
+
+impl Iterator for Steps<'_> {
+    type Item = Result<(VmState, StepOutcome), VmError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let outcome = match self.vm.execute_once() {
+            Ok(None) => StepOutcome::Continued,
+            Ok(Some(RunOutcome::Halted)) => StepOutcome::Halted,
+            Ok(Some(_)) => {
+                self.done = true;
+                return None;
+            }
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err));
+            }
+        };
+        self.done = outcome == StepOutcome::Halted;
+        Some(Ok((self.vm.state(), outcome)))
+    }
+}
+
+/// Resource limits for `VM::run_untrusted`. The depth and stack limits only ever tighten the
+/// VM's own, see `set_max_call_depth` and `set_max_stack`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Budget {
+    pub max_cycles: u64,
+    pub max_call_depth: usize,
+    /// How many values the `PUSH` stack can hold.
+    pub max_stack: usize,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self {
+            max_cycles: 1_000_000,
+            max_call_depth: 1024,
+            max_stack: 64 * 1024,
+        }
+    }
+}
+
+/// A program decoded ahead of time, indexed by the byte offset each instruction starts at, so
+/// that jump targets translate straight to instructions. See `VM::run_compiled`.
+struct Predecoded {
+    instrs: Vec<Option<(Instr, usize)>>,
+}
+
+impl Predecoded {
+    fn new(program: &[u8]) -> Self {
+        let mut instrs = vec![None; program.len()];
+        let mut pc = 0;
+        // Illegal and truncated instructions are left for `decode_at` to report.
+        while let Some((instr, len)) = Instr::from_bytes(&program[pc..]) {
+            if instr != Instr::Illegal {
+                instrs[pc] = Some((instr, len));
+            }
+            pc += len;
+        }
+        Self { instrs }
+    }
+
+    fn at(&self, pc: usize) -> Option<(Instr, usize)> {
+        self.instrs.get(pc).copied().flatten()
+    }
+}
+
+/// Checks that `program` decodes, start to finish, into complete legal instructions.
+fn verify(program: &[u8]) -> Result<(), VmError> {
+    let mut pc = 0;
+    while pc < program.len() {
+        match Instr::from_bytes(&program[pc..]) {
+            Some((Instr::Illegal, _)) => return Err(VmError::IllegalOpcode(program[pc])),
+            Some((_, len)) => pc += len,
+            None => return Err(VmError::ProgramCounterOutOfBounds(program.len())),
+        }
+    }
+    Ok(())
+}
 
-use opcode::Opcode;
+/// A copy of the VM's execution state, independent of how `VM` lays out its fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmState {
+    pub registers: [Reg; 256],
+    pub fregisters: [f32; 256],
+    pub pc: usize,
+    pub remainder: Reg,
+    pub cmp: bool,
+    pub cycles: u64,
+    pub stack: Vec<Reg>,
+    pub call_stack: Vec<usize>,
+    pub memory: Vec<u8>,
+}
 
 #[derive(Debug)]
 pub struct VM {
-    pub registers: [i32; 256],
+    pub registers: [Reg; 256],
+    pub fregisters: [f32; 256],
     pc: usize,
     pub program: Vec<u8>,
-    remainder: u32,
+    remainder: Reg,
     cmp: bool,
+    cycles: u64,
+    cost_model: CostModel,
+    total_cost: u64,
+    output: Output,
+    input: Input,
+    /// The xorshift state behind `RND`, never zero.
+    rng: u64,
+    /// Values from `IN` and `RND` so far, if recording.
+    recording: Option<Vec<Reg>>,
+    /// Values for `IN` and `RND` to produce instead, if replaying.
+    replay: Option<VecDeque<Reg>>,
+    /// Return addresses of the `CALL`s that haven't returned yet.
+    call_stack: Vec<usize>,
+    /// Values saved with `PUSH`, separate from the return addresses.
+    stack: Vec<Reg>,
+    /// Bytes for `LOADM` and `STOREM`, separate from the program.
+    memory: Vec<u8>,
+    max_call_depth: usize,
+    max_stack: usize,
+    max_program_size: usize,
+    /// Whether `$0` always reads as 0 and ignores writes, see `with_zero_register`.
+    zero_register: bool,
+    code_writes: CodeWritePolicy,
+    arithmetic: ArithmeticMode,
+    breakpoints: HashSet<usize>,
+    /// Byte offsets of every instruction that has started executing, see `coverage`.
+    executed: HashSet<usize>,
+    /// Set when the last instruction stopped at a `TRAP`, so that executing it again steps over
+    /// it rather than trapping forever.
+    trapped: bool,
+    last_error: Option<VmError>,
+    /// Instructions decoded so far by offset, if enabled with `with_decode_cache`.
+    decode_cache: Option<HashMap<usize, (Instr, usize)>>,
+}
+
+/// A seed for `RND` from the clock, so runs differ unless seeded with `VM::seed_random`.
+fn random_seed() -> u64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64);
+    nanos.max(1)
 }
 
 impl Default for VM {
     fn default() -> Self {
         Self {
             registers: [0; 256],
+            fregisters: [0.0; 256],
             pc: Default::default(),
             program: Default::default(),
             remainder: Default::default(),
             cmp: Default::default(),
+            cycles: Default::default(),
+            cost_model: Default::default(),
+            total_cost: Default::default(),
+            output: Default::default(),
+            input: Default::default(),
+            rng: random_seed(),
+            recording: None,
+            replay: None,
+            call_stack: Default::default(),
+            stack: Default::default(),
+            memory: vec![0; 64 * 1024],
+            max_call_depth: 1024,
+            max_stack: usize::MAX,
+            max_program_size: 16 * 1024 * 1024,
+            zero_register: false,
+            code_writes: Default::default(),
+            arithmetic: Default::default(),
+            breakpoints: Default::default(),
+            executed: Default::default(),
+            last_error: Default::default(),
+            decode_cache: Default::default(),
+            trapped: false,
         }
     }
 }
@@ -34,125 +395,917 @@ impl VM {
         }
     }
 
-    pub fn run(&mut self) {
-        let mut done = false;
-        while !done {
-            done = self.execute_once()
+    /// An empty VM configured from the environment, with defaults for anything unset:
+    ///
+    /// - `HALIDE_ARITHMETIC`: `checked`, `wrapping` or `saturating`, see `set_arithmetic`.
+    /// - `HALIDE_MAX_CALL_DEPTH`: a positive number, see `set_max_call_depth`.
+    /// - `HALIDE_MAX_STACK`: a positive number, see `set_max_stack`.
+    /// - `HALIDE_MAX_PROGRAM_SIZE`: a positive number of bytes, see `set_max_program_size`.
+    ///
+    /// There's no register count to configure, the register file is always 256 wide.
+    pub fn from_env() -> Result<VM, EnvError> {
+        Self::from_vars(|var| std::env::var(var).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<VM, EnvError> {
+        let mut vm = VM::default();
+        if let Some(value) = var("HALIDE_ARITHMETIC") {
+            vm.arithmetic = value.trim().parse().map_err(|()| EnvError {
+                var: "HALIDE_ARITHMETIC",
+                value,
+                expected: "`checked`, `wrapping` or `saturating`",
+            })?;
+        }
+        let limits = [
+            ("HALIDE_MAX_CALL_DEPTH", &mut vm.max_call_depth),
+            ("HALIDE_MAX_STACK", &mut vm.max_stack),
+            ("HALIDE_MAX_PROGRAM_SIZE", &mut vm.max_program_size),
+        ];
+        for (name, limit) in limits {
+            if let Some(value) = var(name) {
+                *limit = match value.trim().parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => {
+                        return Err(EnvError {
+                            var: name,
+                            value,
+                            expected: "a positive number",
+                        })
+                    }
+                };
+            }
+        }
+        Ok(vm)
+    }
+
+    /// Weighs each instruction executed by `model` when adding up `total_cost`.
+    pub fn with_cost_model(mut self, model: CostModel) -> Self {
+        self.cost_model = model;
+        self
+    }
+
+    /// Replays `recording`: `IN` and `RND` produce its values in order, instead of reading
+    /// input or generating them, and fault once it runs out.
+    pub fn with_recording(mut self, recording: Recording) -> Self {
+        self.replay = Some(recording.values.into());
+        self
+    }
+
+    /// Hardwires `$0` to zero, so that every instruction reads it as 0 and writes to it are
+    /// ignored. Off by default, since programs like the compiler's use `$0` for storage.
+    pub fn with_zero_register(mut self, enabled: bool) -> Self {
+        self.zero_register = enabled;
+        self
+    }
+
+    /// Remembers each instruction the first time it's decoded, so loops only decode their body
+    /// once. The cache isn't told about changes to `program`: call `invalidate_decode_cache`
+    /// after modifying it.
+    pub fn with_decode_cache(mut self) -> Self {
+        self.decode_cache = Some(HashMap::new());
+        self
+    }
+
+    pub fn invalidate_decode_cache(&mut self) {
+        if let Some(cache) = &mut self.decode_cache {
+            cache.clear();
+        }
+    }
+
+    /// The offsets in the decode cache, in order. Empty if it isn't enabled.
+    pub fn decode_cached(&self) -> Vec<usize> {
+        let mut offsets: Vec<usize> = self
+            .decode_cache
+            .iter()
+            .flat_map(|c| c.keys().copied())
+            .collect();
+        offsets.sort_unstable();
+        offsets
+    }
+
+    /// Puts the VM back how it was before anything ran, keeping the program, breakpoints and
+    /// settings like the arithmetic mode.
+    pub fn reset(&mut self) {
+        self.registers = [0; 256];
+        self.fregisters = [0.0; 256];
+        self.pc = 0;
+        self.remainder = 0;
+        self.cmp = false;
+        self.cycles = 0;
+        self.total_cost = 0;
+        self.call_stack.clear();
+        self.stack.clear();
+        self.memory.fill(0);
+        self.executed.clear();
+        self.trapped = false;
+        self.last_error = None;
+    }
+
+    /// Limits how many `CALL`s can be nested before execution fails with `CallStackOverflow`.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Limits how many values `PUSH` and `SDUP` can put on the stack before execution fails
+    /// with `StackOverflow`. There's no limit by default.
+    pub fn set_max_stack(&mut self, values: usize) {
+        self.max_stack = values;
+    }
+
+    /// Limits how many bytes `load_program` accepts. The default is 16 MiB.
+    pub fn set_max_program_size(&mut self, bytes: usize) {
+        self.max_program_size = bytes;
+    }
+
+    /// Sets what `write_code` does. The default is to reject every write.
+    pub fn set_code_write_policy(&mut self, policy: CodeWritePolicy) {
+        self.code_writes = policy;
+    }
+
+    /// Overwrites the program with `bytes` from byte offset `at`, for a host that stores into
+    /// the code region while the VM is in use. What happens is up to the `CodeWritePolicy`.
+    /// Writing past the end of the program fails with `CodeWriteOutOfBounds`.
+    ///
+    /// No instruction writes to the program: `STOREM` writes to memory, which is separate, so
+    /// this is only reachable from the host.
+    pub fn write_code(&mut self, at: usize, bytes: &[u8]) -> Result<(), VmError> {
+        let end = at
+            .checked_add(bytes.len())
+            .filter(|&end| end <= self.program.len())
+            .ok_or(VmError::CodeWriteOutOfBounds {
+                at,
+                len: bytes.len(),
+            })?;
+        match self.code_writes {
+            CodeWritePolicy::Reject => Err(VmError::SelfModifyingCode { at }),
+            CodeWritePolicy::Invalidate => {
+                self.program[at..end].copy_from_slice(bytes);
+                self.invalidate_decode_cache();
+                Ok(())
+            }
+        }
+    }
+
+    /// Replaces the program and resets the VM to run it from the start, failing with
+    /// `ProgramTooLarge` if it's over the maximum program size. Unlike `with_program`, this is
+    /// meant for programs from somewhere untrusted.
+    pub fn load_program(&mut self, program: Vec<u8>) -> Result<(), VmError> {
+        if program.len() > self.max_program_size {
+            return Err(VmError::ProgramTooLarge {
+                len: program.len(),
+                max: self.max_program_size,
+            });
+        }
+        self.program = program;
+        self.reset();
+        self.invalidate_decode_cache();
+        Ok(())
+    }
+
+    /// Sets how ADD, SUB, MUL, DIV and POW handle overflow. The default is to fault.
+    pub fn set_arithmetic(&mut self, mode: ArithmeticMode) {
+        self.arithmetic = mode;
+    }
+
+    /// Collects everything written by `OUT` instead of printing it, see `take_output`.
+    pub fn capture_output(&mut self) {
+        self.output = Output::Captured(String::new());
+    }
+
+    /// Returns the output captured so far, leaving the buffer empty.
+    pub fn take_output(&mut self) -> String {
+        match &mut self.output {
+            Output::Captured(buf) => std::mem::take(buf),
+            Output::Stdout => String::new(),
+        }
+    }
+
+    /// Reads `IN` values from `values` instead of standard input. Once they run out, `IN`
+    /// faults.
+    pub fn provide_input(&mut self, values: impl IntoIterator<Item = Reg>) {
+        self.input = Input::Queued(values.into_iter().collect());
+    }
+
+    /// Makes `RND` generate the same numbers every time. By default it's seeded from the clock.
+    pub fn seed_random(&mut self, seed: u64) {
+        self.rng = seed.max(1);
+    }
+
+    /// Logs every value `IN` and `RND` produce from now on, for `take_recording`.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(vec![]);
+    }
+
+    /// Returns what's been recorded so far, leaving the log empty. Empty if not recording.
+    pub fn take_recording(&mut self) -> Recording {
+        Recording {
+            values: self
+                .recording
+                .as_mut()
+                .map(std::mem::take)
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn run(&mut self) -> Result<RunOutcome, VmError> {
+        self.run_until(u64::MAX)
+    }
+
+    /// Like `run`, but decodes the whole program up front instead of as it goes. A jump into the
+    /// middle of an instruction is decoded from there, just as `run` would.
+    pub fn run_compiled(&mut self) -> Result<RunOutcome, VmError> {
+        let predecoded = Predecoded::new(&self.program);
+        let start = self.pc;
+        loop {
+            if self.pc != start && self.breakpoints.contains(&self.pc) {
+                return Ok(RunOutcome::Breakpoint);
+            }
+            if let Some(outcome) = self.execute_predecoded_once(&predecoded)? {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    /// Like `run`, but stops with `LimitReached` after executing at most `cycles` instructions.
+    pub fn run_for(&mut self, cycles: u64) -> Result<RunOutcome, VmError> {
+        self.run_until(self.cycles.saturating_add(cycles))
+    }
+
+    /// Stops a run just before the instruction at byte offset `pc` executes. The instruction a
+    /// run starts on never stops it, so running again continues past the breakpoint.
+    pub fn set_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn clear_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.remove(&pc);
+    }
+
+    /// Runs until the total cycle count reaches `max_cycles`, or the program stops sooner.
+    fn run_until(&mut self, max_cycles: u64) -> Result<RunOutcome, VmError> {
+        let start = self.pc;
+        loop {
+            if self.cycles >= max_cycles {
+                return Ok(RunOutcome::LimitReached);
+            }
+            if self.pc != start && self.breakpoints.contains(&self.pc) {
+                return Ok(RunOutcome::Breakpoint);
+            }
+            if let Some(outcome) = self.execute_once()? {
+                return Ok(outcome);
+            }
         }
     }
 
-    pub fn step(&mut self) {
-        self.execute_once();
+    /// Executes a single instruction, returning how the program stopped if it did. Faults are
+    /// returned like in `run`, rather than taking the process down.
+    pub fn step(&mut self) -> Result<Option<RunOutcome>, VmError> {
+        self.execute_once()
+    }
+
+    /// Executes the program one instruction at a time, yielding the state after each. Ends after
+    /// a `HLT`, an error, or once there are no instructions left.
+    pub fn steps(&mut self) -> Steps<'_> {
+        Steps {
+            vm: self,
+            done: false,
+        }
+    }
+
+    /// Runs bytecode that can't be trusted to be well formed or to terminate.
+    ///
+    /// The whole program is first checked to decode into legal instructions, and every
+    /// instruction is then checked again as it executes, so that anything which would otherwise
+    /// panic becomes an error instead. Execution stops with `CycleLimitExceeded` once the
+    /// budget's cycles are used up, counting from the cycles already run, and with `CallStackOverflow` or `StackOverflow` if either
+    /// stack grows past its limit. Registers are indexed by a byte, so there's no register
+    /// access that can be out of bounds.
+    pub fn run_untrusted(&mut self, budget: Budget) -> Result<RunOutcome, VmError> {
+        verify(&self.program)?;
+
+        let limits = (self.max_call_depth, self.max_stack);
+        self.max_call_depth = self.max_call_depth.min(budget.max_call_depth);
+        self.max_stack = self.max_stack.min(budget.max_stack);
+        let outcome = self.run_for(budget.max_cycles);
+        (self.max_call_depth, self.max_stack) = limits;
+
+        match outcome? {
+            RunOutcome::LimitReached => Err(VmError::CycleLimitExceeded(budget.max_cycles)),
+            outcome => Ok(outcome),
+        }
+    }
+
+    pub fn state(&self) -> VmState {
+        VmState {
+            registers: self.registers,
+            fregisters: self.fregisters,
+            pc: self.pc,
+            remainder: self.remainder,
+            cmp: self.cmp,
+            cycles: self.cycles,
+            stack: self.stack.clone(),
+            call_stack: self.call_stack.clone(),
+            memory: self.memory.clone(),
+        }
+    }
+
+    pub fn nonzero_registers(&self) -> Vec<(usize, Reg)> {
+        self.registers
+            .iter()
+            .enumerate()
+            .filter(|(_, &v)| v != 0)
+            .map(|(i, &v)| (i, v))
+            .collect()
+    }
+
+    /// The nonzero registers as 64-bit hex, one `$n = 0x...` per line.
+    pub fn registers_hex(&self) -> String {
+        self.nonzero_registers()
+            .into_iter()
+            .map(|(i, v)| format!("${} = {:#018X}\n", i, v))
+            .collect()
+    }
+
+    /// One register in decimal, hex and binary, e.g. `$3 = 10  0x000000000000000A  0b00...01010`.
+    pub fn register_bases(&self, register: u8) -> String {
+        let v = self.registers[register as usize];
+        format!("${} = {}  {:#018X}  {:#066b}", register, v, v, v)
+    }
+
+    /// The instruction at `pc`, without executing it. `None` once there is no complete
+    /// instruction left to run.
+    pub fn next_instr(&self) -> Option<Instr> {
+        let rest = self.program.get(self.pc..)?;
+        Instr::from_bytes(rest).map(|(instr, _)| instr)
+    }
+
+    /// Disassembles up to `context` instructions either side of the one at `pc`, one per line
+    /// with its byte offset, marking the current instruction with an arrow.
+    pub fn listing(&self, context: usize) -> String {
+        let instrs = disassemble(&self.program);
+        let current = instrs
+            .iter()
+            .position(|&(offset, _)| offset >= self.pc)
+            .unwrap_or(instrs.len());
+        let start = current.saturating_sub(context);
+        let end = (current + context + 1).min(instrs.len());
+
+        instrs[start..end]
+            .iter()
+            .map(|&(offset, instr)| {
+                let marker = if offset == self.pc { "->" } else { "  " };
+                format!("{} {:04}  {}\n", marker, offset, instr)
+            })
+            .collect()
+    }
+
+    /// Byte offsets of the instructions that have been executed at least once, in order.
+    pub fn coverage(&self) -> Vec<usize> {
+        let mut offsets: Vec<usize> = self.executed.iter().copied().collect();
+        offsets.sort_unstable();
+        offsets
+    }
+
+    /// Byte offsets of the instructions in the program that have never been executed.
+    pub fn uncovered(&self) -> Vec<usize> {
+        disassemble(&self.program)
+            .into_iter()
+            .map(|(offset, _)| offset)
+            .filter(|offset| !self.executed.contains(offset))
+            .collect()
+    }
+
+    /// The byte offset of the next instruction to run.
+    ///
+    /// ```
+    /// use vm::{builder::ProgramBuilder, VM};
+    ///
+    /// // Jumps over the first HLT to the second.
+    /// let program = ProgramBuilder::new().load(0, 7).jmp(0).halt().halt().to_bytes();
+    /// let mut vm = VM::with_program(program);
+    /// vm.step().unwrap();
+    /// vm.step().unwrap();
+    /// assert_eq!(vm.pc(), 7);
+    /// ```
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The remainder left by the last `DIV`, as read by `RDREM`.
+    pub fn remainder(&self) -> Reg {
+        self.remainder
+    }
+
+    /// The comparison flag, set by `EQ`, `GT` and friends and read by `JMPIF`, `CMOV` and `GETF`.
+    pub fn cmp(&self) -> bool {
+        self.cmp
     }
 
-    fn execute_once(&mut self) -> bool {
+    pub fn set_cmp(&mut self, cmp: bool) {
+        self.cmp = cmp;
+    }
+
+    /// Number of instructions executed so far, including the current one while it runs.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Sum of the costs of the instructions executed so far, see `with_cost_model`.
+    pub fn total_cost(&self) -> u64 {
+        self.total_cost
+    }
+
+    /// The most recent fault, kept until the next one or a `reset`.
+    pub fn last_error(&self) -> Option<&VmError> {
+        self.last_error.as_ref()
+    }
+
+    /// Executes one instruction, returning how the program ended if this was its last.
+    fn execute_once(&mut self) -> Result<Option<RunOutcome>, VmError> {
         if self.pc >= self.program.len() {
-            return true;
+            return Ok(Some(RunOutcome::FellOffEnd));
+        }
+
+        let at = self.pc;
+        self.cycles += 1;
+        self.executed.insert(at);
+        let result = self
+            .decode_cached_at(at)
+            .and_then(|(instr, len)| self.execute(instr, len));
+        self.fault_at(at, result)
+    }
+
+    /// Like `execute_once`, but taking the instruction from `predecoded` when it has one.
+    fn execute_predecoded_once(
+        &mut self,
+        predecoded: &Predecoded,
+    ) -> Result<Option<RunOutcome>, VmError> {
+        if self.pc >= self.program.len() {
+            return Ok(Some(RunOutcome::FellOffEnd));
+        }
+
+        let at = self.pc;
+        self.cycles += 1;
+        self.executed.insert(at);
+        let result = match predecoded.at(at) {
+            Some(decoded) => Ok(decoded),
+            None => self.decode_at(at),
         }
+        .and_then(|(instr, len)| self.execute(instr, len));
+        self.fault_at(at, result)
+    }
+
+    /// Leaves `pc` on the instruction at `at` if it faulted, so that it can be inspected and
+    /// retried, and remembers the error for `last_error`.
+    fn fault_at(
+        &mut self,
+        at: usize,
+        result: Result<Option<RunOutcome>, VmError>,
+    ) -> Result<Option<RunOutcome>, VmError> {
+        if let Err(err) = &result {
+            self.pc = at;
+            self.last_error = Some(err.clone());
+        }
+        result
+    }
+
+    /// `decode_at`, going through the decode cache if it's enabled.
+    fn decode_cached_at(&mut self, pc: usize) -> Result<(Instr, usize), VmError> {
+        if let Some(&decoded) = self.decode_cache.as_ref().and_then(|c| c.get(&pc)) {
+            return Ok(decoded);
+        }
+        let decoded = self.decode_at(pc)?;
+        if let Some(cache) = &mut self.decode_cache {
+            cache.insert(pc, decoded);
+        }
+        Ok(decoded)
+    }
+
+    /// Decodes the instruction at `pc`, returning it and its length in bytes.
+    fn decode_at(&self, pc: usize) -> Result<(Instr, usize), VmError> {
+        match Instr::from_bytes(&self.program[pc..]) {
+            Some((Instr::Illegal, _)) => Err(VmError::IllegalOpcode(self.program[pc])),
+            Some(decoded) => Ok(decoded),
+            None => Err(VmError::ProgramCounterOutOfBounds(self.program.len())),
+        }
+    }
+
+    /// Executes `instr`, which is the `len` byte instruction at `pc`.
+    fn execute(&mut self, instr: Instr, len: usize) -> Result<Option<RunOutcome>, VmError> {
+        // Zeroing `$0` before covers anything the host wrote to it, and after discards whatever
+        // the instruction wrote.
+        if self.zero_register {
+            self.registers[0] = 0;
+        }
+        let result = self.execute_instr(instr, len);
+        if self.zero_register {
+            self.registers[0] = 0;
+        }
+        result
+    }
+
+    fn execute_instr(&mut self, instr: Instr, len: usize) -> Result<Option<RunOutcome>, VmError> {
+        let at = self.pc;
+        self.pc += len;
+        self.total_cost += self.cost_model.cost(instr.opcode());
 
-        match self.decode_opcode() {
-            Opcode::JMP => {
-                let target = self.registers[self.next_byte() as usize];
-                self.pc = target as usize;
+        // Jumping to just past the end is allowed, and ends the program like falling off it.
+        let in_bounds = |vm: &Self, target: Reg| {
+            usize::try_from(target)
+                .ok()
+                .filter(|&target| target <= vm.program.len())
+                .ok_or(VmError::BadJumpTarget { target, pc: at })
+        };
+        let jump_target = |vm: &Self, r: u8| in_bounds(vm, vm.registers[r as usize]);
+        // Relative jumps count from the instruction after them.
+        let relative_target =
+            |vm: &Self, offset: Reg| in_bounds(vm, (vm.pc as Reg).saturating_add(offset));
+
+        // No wildcard arm: adding an instruction doesn't compile until it is handled here.
+        match instr {
+            Instr::Jump(r) => {
+                self.pc = jump_target(self, r)?;
             }
-            Opcode::JMPIF => {
+            Instr::JumpIf(r) => {
                 if self.cmp {
-                    let target = self.registers[self.next_byte() as usize];
-                    self.pc = target as usize;
+                    self.pc = jump_target(self, r)?;
                 }
             }
 
-            Opcode::LOAD => {
-                let dest = self.next_byte() as usize;
-                let val = self.next_value();
+            Instr::JumpForward(r) => {
+                self.pc = relative_target(self, self.registers[r as usize])?;
+            }
+            Instr::JumpBack(r) => {
+                let offset = self.registers[r as usize].saturating_neg();
+                self.pc = relative_target(self, offset)?;
+            }
 
-                self.registers[dest] = val;
+            Instr::Call(r) => {
+                let target = jump_target(self, r)?;
+                if self.call_stack.len() >= self.max_call_depth {
+                    return Err(VmError::CallStackOverflow);
+                }
+                self.call_stack.push(self.pc);
+                self.pc = target;
+            }
+            Instr::Return => {
+                self.pc = self.call_stack.pop().ok_or(VmError::CallStackUnderflow)?;
             }
 
-            Opcode::ADD => {
-                let rhs = self.registers[self.next_byte() as usize];
-                let lhs = self.registers[self.next_byte() as usize];
+            Instr::Trap => {
+                self.trapped = !self.trapped;
+                if self.trapped {
+                    self.pc = at;
+                    return Ok(Some(RunOutcome::Trap));
+                }
+                // Carrying on past it, which was already counted when it stopped the run.
+                self.cycles -= 1;
+                self.total_cost = self
+                    .total_cost
+                    .saturating_sub(self.cost_model.cost(Opcode::TRAP));
+            }
 
-                let dest = self.next_byte() as usize;
+            Instr::Push(r) => {
+                if self.stack.len() >= self.max_stack {
+                    return Err(VmError::StackOverflow);
+                }
+                let value = self.registers[r as usize];
+                self.stack.push(value);
+            }
+            Instr::Pop(dest) => {
+                self.registers[dest as usize] = self.stack.pop().ok_or(VmError::StackUnderflow)?;
+            }
+            Instr::Peek(dest) => {
+                self.registers[dest as usize] =
+                    *self.stack.last().ok_or(VmError::StackUnderflow)?;
+            }
+
+            Instr::Drop => {
+                self.stack.pop().ok_or(VmError::StackUnderflow)?;
+            }
+            Instr::StackDup => {
+                let top = *self.stack.last().ok_or(VmError::StackUnderflow)?;
+                if self.stack.len() >= self.max_stack {
+                    return Err(VmError::StackOverflow);
+                }
+                self.stack.push(top);
+            }
+            Instr::StackSwap => {
+                let len = self.stack.len();
+                if len < 2 {
+                    return Err(VmError::StackUnderflow);
+                }
+                self.stack.swap(len - 1, len - 2);
+            }
 
-                self.registers[dest] = rhs + lhs;
+            Instr::Load(dest, val) => {
+                self.registers[dest as usize] = val as Reg;
             }
-            Opcode::SUB => {
-                let rhs = self.registers[self.next_byte() as usize];
-                let lhs = self.registers[self.next_byte() as usize];
 
-                let dest = self.next_byte() as usize;
+            Instr::Add(a, b, dest) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
 
-                self.registers[dest] = rhs - lhs;
+                self.registers[dest as usize] =
+                    self.arithmetic.add(rhs, lhs).ok_or(VmError::Overflow)?;
+            }
+            Instr::Mov(dest, src) => {
+                self.registers[dest as usize] = self.registers[src as usize];
+            }
+            Instr::Inc(r) => {
+                let value = self.registers[r as usize];
+                self.registers[r as usize] =
+                    self.arithmetic.add(value, 1).ok_or(VmError::Overflow)?;
             }
-            Opcode::MUL => {
-                let rhs = self.registers[self.next_byte() as usize];
-                let lhs = self.registers[self.next_byte() as usize];
+            Instr::Dec(r) => {
+                let value = self.registers[r as usize];
+                self.registers[r as usize] =
+                    self.arithmetic.sub(value, 1).ok_or(VmError::Overflow)?;
+            }
+            Instr::Subtract(a, b, dest) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
 
-                let dest = self.next_byte() as usize;
+                self.registers[dest as usize] =
+                    self.arithmetic.sub(rhs, lhs).ok_or(VmError::Overflow)?;
+            }
+            Instr::Multiply(a, b, dest) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
 
-                self.registers[dest] = rhs * lhs;
+                self.registers[dest as usize] =
+                    self.arithmetic.mul(rhs, lhs).ok_or(VmError::Overflow)?;
             }
-            Opcode::DIV => {
-                let rhs = self.registers[self.next_byte() as usize];
-                let lhs = self.registers[self.next_byte() as usize];
+            Instr::Divide(a, b, dest) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
 
-                let dest = self.next_byte() as usize;
+                if lhs == 0 {
+                    return Err(VmError::DivideByZero);
+                }
+                self.registers[dest as usize] =
+                    self.arithmetic.div(rhs, lhs).ok_or(VmError::Overflow)?;
+                self.remainder = self.arithmetic.rem(rhs, lhs).ok_or(VmError::Overflow)?;
+            }
+            Instr::Mod(a, b, dest) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
 
-                self.registers[dest] = rhs / lhs;
-                self.remainder = (rhs % lhs) as u32;
+                if lhs == 0 {
+                    return Err(VmError::DivideByZero);
+                }
+                self.registers[dest as usize] =
+                    self.arithmetic.rem(rhs, lhs).ok_or(VmError::Overflow)?;
             }
 
-            Opcode::HLT => {
+            Instr::Nop => {}
+            Instr::Halt => {
                 eprintln!("Halting");
-                return true;
+                return Ok(Some(RunOutcome::Halted));
             }
-            Opcode::IGL => panic!("Illegal opcode encountered"),
-            Opcode::EQ => {
-                let rhs = self.registers[self.next_byte() as usize];
-                let lhs = self.registers[self.next_byte() as usize];
+            Instr::Illegal => return Err(VmError::IllegalOpcode(Opcode::IGL.into())),
+            Instr::Equal(a, b) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
 
                 self.cmp = rhs == lhs;
             }
-            Opcode::NOT => {
+            Instr::AssertEqual(a, b) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
+
+                if rhs != lhs {
+                    return Err(VmError::AssertionFailed { pc: at });
+                }
+            }
+            Instr::Not => {
                 self.cmp = !self.cmp;
             }
-            Opcode::GT => {
-                let rhs = self.registers[self.next_byte() as usize];
-                let lhs = self.registers[self.next_byte() as usize];
+            Instr::GreaterThan(a, b) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
 
                 self.cmp = rhs > lhs;
             }
-            Opcode::GTQ => {
-                let rhs = self.registers[self.next_byte() as usize];
-                let lhs = self.registers[self.next_byte() as usize];
+            Instr::GreaterThanEqual(a, b) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
 
                 self.cmp = rhs >= lhs;
             }
-            Opcode::POW => {
-                let rhs = self.registers[self.next_byte() as usize];
-                let lhs = self.registers[self.next_byte() as usize];
+            Instr::SetEqual(a, b, dest) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
 
-                let dest = self.next_byte() as usize;
+                self.registers[dest as usize] = (rhs == lhs) as Reg;
+            }
+            Instr::SetGreaterThan(a, b, dest) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
+
+                self.registers[dest as usize] = (rhs > lhs) as Reg;
+            }
+            Instr::SetLessThan(a, b, dest) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
 
-                self.registers[dest] = rhs.pow(lhs.try_into().unwrap());
+                self.registers[dest as usize] = (rhs < lhs) as Reg;
+            }
+            Instr::ConditionalMove(a, b, dest) => {
+                let a = self.registers[a as usize];
+                let b = self.registers[b as usize];
+
+                self.registers[dest as usize] = if self.cmp { a } else { b };
+            }
+            Instr::Power(a, b, dest) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
+
+                let exponent = u32::try_from(lhs).map_err(|_| VmError::NegativeExponent)?;
+                self.registers[dest as usize] = self
+                    .arithmetic
+                    .pow(rhs, exponent)
+                    .ok_or(VmError::Overflow)?;
+            }
+
+            Instr::And(a, b, dest) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
+
+                self.registers[dest as usize] = rhs & lhs;
+            }
+            Instr::Or(a, b, dest) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
+
+                self.registers[dest as usize] = rhs | lhs;
+            }
+            Instr::Xor(a, b, dest) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
+
+                self.registers[dest as usize] = rhs ^ lhs;
+            }
+            // Only the low 6 bits of the shift amount are used, so shifts never overflow. SHR
+            // is a logical shift: the sign bit isn't copied down.
+            Instr::Shl(a, b, dest) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
+
+                self.registers[dest as usize] = rhs << (lhs & 63);
+            }
+            Instr::Shr(a, b, dest) => {
+                let rhs = self.registers[a as usize];
+                let lhs = self.registers[b as usize];
+
+                self.registers[dest as usize] = ((rhs as u64) >> (lhs & 63)) as Reg;
+            }
+            Instr::Clamp(reg, lo, hi) => {
+                let lo = self.registers[lo as usize];
+                let hi = self.registers[hi as usize];
+                if lo > hi {
+                    return Err(VmError::EmptyClampRange { lo, hi });
+                }
+
+                self.registers[reg as usize] = self.registers[reg as usize].clamp(lo, hi);
+            }
+
+            Instr::FLoad(dest, val) => {
+                self.fregisters[dest as usize] = val;
+            }
+            Instr::FAdd(a, b, dest) => {
+                let rhs = self.fregisters[a as usize];
+                let lhs = self.fregisters[b as usize];
+
+                self.fregisters[dest as usize] = rhs + lhs;
+            }
+            Instr::FSubtract(a, b, dest) => {
+                let rhs = self.fregisters[a as usize];
+                let lhs = self.fregisters[b as usize];
+
+                self.fregisters[dest as usize] = rhs - lhs;
+            }
+            Instr::FMultiply(a, b, dest) => {
+                let rhs = self.fregisters[a as usize];
+                let lhs = self.fregisters[b as usize];
+
+                self.fregisters[dest as usize] = rhs * lhs;
+            }
+            Instr::FDivide(a, b, dest) => {
+                let rhs = self.fregisters[a as usize];
+                let lhs = self.fregisters[b as usize];
+
+                // Division by zero follows IEEE 754 and yields inf or NaN.
+                self.fregisters[dest as usize] = rhs / lhs;
+            }
+            Instr::IntToFloat(src, dest) => {
+                self.fregisters[dest as usize] = self.registers[src as usize] as f32;
+            }
+            Instr::FloatToInt(src, dest) => {
+                // Truncates towards zero, saturating out of range values and mapping NaN to 0.
+                self.registers[dest as usize] = self.fregisters[src as usize] as Reg;
+            }
+
+            Instr::GetFlag(dest) => {
+                self.registers[dest as usize] = self.cmp as Reg;
+            }
+            Instr::Out(r) => {
+                let val = self.registers[r as usize];
+
+                match &mut self.output {
+                    Output::Stdout => println!("{}", val),
+                    Output::Captured(buf) => buf.push_str(&format!("{}\n", val)),
+                }
+            }
+            Instr::In(dest) => {
+                self.registers[dest as usize] = self.nondeterministic(Self::read_input)?;
+            }
+            Instr::Random(dest) => {
+                self.registers[dest as usize] = self.nondeterministic(|vm| Ok(vm.next_random()))?;
+            }
+            Instr::LoadMem(dest, addr) => {
+                let addr = self.memory_address(addr)?;
+                self.registers[dest as usize] = self.memory[addr] as Reg;
+            }
+            Instr::StoreMem(src, addr) => {
+                let addr = self.memory_address(addr)?;
+                self.memory[addr] = self.registers[src as usize] as u8;
+            }
+            Instr::ReadRemainder(dest) => {
+                self.registers[dest as usize] = self.remainder;
+            }
+            Instr::RegLoad(index, dest) => {
+                let index = self.registers[index as usize];
+
+                // Out of range indices wrap around rather than fault, so a table lookup can't
+                // escape the register file.
+                self.registers[dest as usize] = self.registers[index as u8 as usize];
+            }
+            Instr::Cycles(dest) => {
+                self.registers[dest as usize] = self.cycles as Reg;
             }
         }
-        false
+        Ok(None)
     }
 
-    fn next_byte(&mut self) -> u8 {
-        let byte = self.program[self.pc];
-        self.pc += 1;
-        byte
+    /// The address in register `r`, if it's inside memory.
+    fn memory_address(&self, r: u8) -> Result<usize, VmError> {
+        let addr = self.registers[r as usize];
+        usize::try_from(addr)
+            .ok()
+            .filter(|&addr| addr < self.memory.len())
+            .ok_or(VmError::MemoryOutOfBounds(addr))
+    }
+
+    /// A value for `IN` or `RND`, taken from the recording being replayed if there is one and
+    /// from `live` otherwise, and logged if recording.
+    fn nondeterministic(
+        &mut self,
+        live: impl FnOnce(&mut Self) -> Result<Reg, VmError>,
+    ) -> Result<Reg, VmError> {
+        let value = match &mut self.replay {
+            Some(replay) => replay.pop_front().ok_or(VmError::RecordingExhausted)?,
+            None => live(self)?,
+        };
+        if let Some(recording) = &mut self.recording {
+            recording.push(value);
+        }
+        Ok(value)
     }
 
-    fn next_value(&mut self) -> i32 {
-        (((self.next_byte() as u16) << 8) | self.next_byte() as u16) as i16 as i32
+    fn read_input(&mut self) -> Result<Reg, VmError> {
+        match &mut self.input {
+            Input::Stdin => {
+                let mut line = String::new();
+                std::io::stdin()
+                    .read_line(&mut line)
+                    .map_err(|_| VmError::NoInput)?;
+                line.trim().parse().map_err(|_| VmError::NoInput)
+            }
+            Input::Queued(values) => values.pop_front().ok_or(VmError::NoInput),
+        }
+    }
+
+    /// xorshift64*.
+    fn next_random(&mut self) -> Reg {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D) as Reg
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        Opcode::from(self.next_byte())
+    fn next_byte(&mut self) -> Result<u8, VmError> {
+        let byte = *self
+            .program
+            .get(self.pc)
+            .ok_or(VmError::ProgramCounterOutOfBounds(self.pc))?;
+        self.pc += 1;
+        Ok(byte)
+    }
+
+    /// Reads a big-endian 16-bit immediate and sign-extends it.
+    fn next_value(&mut self) -> Result<Reg, VmError> {
+        Ok(i16::from_be_bytes([self.next_byte()?, self.next_byte()?]) as Reg)
     }
 }
 
@@ -160,194 +1313,932 @@ impl VM {
 #[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
+    use crate::builder::ProgramBuilder;
+
+    /// Assembles `src`, so that tests can be written in assembly rather than bytes.
+    fn asm(src: &str) -> Vec<u8> {
+        parsing::assemble_bytes(src).unwrap_or_else(|errs| {
+            let errs: Vec<_> = errs.iter().map(parsing::describe_error).collect();
+            panic!("can't assemble {:?}: {}", src, errs.join(", "))
+        })
+    }
+
+    #[test]
+    fn init_vm() {
+        let vm = VM::default();
+
+        assert_eq!(vm.registers, [0; 256]);
+    }
+
+    #[test]
+    fn report_state() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 7)
+                .load(1, 2)
+                .div(0, 1, 2)
+                .gt(0, 1)
+                .push_reg(2)
+                .storem(0, 1)
+                .halt()
+                .to_bytes(),
+        );
+        vm.run().unwrap();
+
+        let state = vm.state();
+        assert_eq!(state.registers[2], 3);
+        assert_eq!(state.pc, 21);
+        assert_eq!(state.remainder, 1);
+        assert!(state.cmp);
+        assert_eq!(state.cycles, 7);
+        assert_eq!(state.stack, [3]);
+        assert_eq!(state.memory[2..10], 7i64.to_le_bytes());
+    }
+
+    #[test]
+    fn nonzero_registers() {
+        let mut vm = VM::with_program(ProgramBuilder::new().load(0, 7).load(5, -3).to_bytes());
+        vm.run().unwrap();
+
+        assert_eq!(vm.nonzero_registers(), vec![(0, 7), (5, -3)]);
+    }
+
+    #[test]
+    fn hex_registers() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, -1)
+                .load(3, 256)
+                .load(4, i16::MIN)
+                .to_bytes(),
+        );
+        vm.run().unwrap();
+
+        assert_eq!(
+            vm.registers_hex(),
+            "$0 = 0xFFFFFFFFFFFFFFFF\n$3 = 0x0000000000000100\n$4 = 0xFFFFFFFFFFFF8000\n"
+        );
+    }
+
+    #[test]
+    fn zero_register() {
+        let program = ProgramBuilder::new()
+            .load(0, 5)
+            .add(0, 0, 1)
+            .load(2, 3)
+            .add(2, 0, 3)
+            .to_bytes();
+
+        let mut vm = VM::with_program(program.clone()).with_zero_register(true);
+        vm.registers[4] = 7;
+        vm.run().unwrap();
+        assert_eq!(vm.registers[..5], [0, 0, 3, 3, 7]);
+
+        // Even a value the host put there reads as 0.
+        let mut vm = VM::with_program(ProgramBuilder::new().add(0, 0, 1).to_bytes())
+            .with_zero_register(true);
+        vm.registers[0] = 9;
+        vm.run().unwrap();
+        assert_eq!(vm.registers[..2], [0, 0]);
+
+        let mut vm = VM::with_program(program);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[..4], [5, 10, 3, 8]);
+    }
+
+    #[test]
+    fn register_in_all_bases() {
+        let mut vm = VM::default();
+        vm.registers[0] = -1;
+        vm.registers[2] = 255;
+
+        assert_eq!(
+            vm.register_bases(0),
+            format!("$0 = -1  0xFFFFFFFFFFFFFFFF  0b{}", "1".repeat(64))
+        );
+        assert_eq!(
+            vm.register_bases(1),
+            format!("$1 = 0  0x0000000000000000  0b{}", "0".repeat(64))
+        );
+        assert_eq!(
+            vm.register_bases(2),
+            format!("$2 = 255  0x00000000000000FF  0b{}11111111", "0".repeat(56))
+        );
+    }
+
+    #[test]
+    fn next_instruction() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 7)
+                .load(1, 3)
+                .sub(0, 1, 2)
+                .to_bytes(),
+        );
+        assert_eq!(vm.next_instr(), Some(Instr::Load(0, 7)));
+
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 8);
+        assert_eq!(vm.next_instr(), Some(Instr::Subtract(0, 1, 2)));
+        assert_eq!(vm.registers[2], 0);
+
+        vm.step().unwrap();
+        assert_eq!(vm.next_instr(), None);
+    }
+
+    #[test]
+    fn listing_window() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 1)
+                .load(1, 2)
+                .add(0, 1, 2)
+                .out(2)
+                .not()
+                .out(0)
+                .halt()
+                .to_bytes(),
+        );
+
+        assert_eq!(
+            vm.listing(2),
+            "-> 0000  LOAD $0 #1\n   0004  LOAD $1 #2\n   0008  ADD $0 $1 $2\n"
+        );
+
+        vm.pc = 12;
+        assert_eq!(
+            vm.listing(1),
+            "   0008  ADD $0 $1 $2\n-> 0012  OUT $2\n   0014  NOT\n"
+        );
+
+        vm.pc = 17;
+        assert_eq!(vm.listing(1), "   0015  OUT $0\n-> 0017  HLT\n");
+
+        vm.pc = 18;
+        assert_eq!(vm.listing(1), "   0017  HLT\n");
+    }
+
+    #[test]
+    fn grab_byte() {
+        let mut vm = VM::with_program(vec![20, 66]);
+
+        assert_eq!(vm.next_byte(), Ok(20u8));
+        assert_eq!(vm.pc, 1);
+
+        assert_eq!(vm.next_byte(), Ok(66u8));
+        assert_eq!(vm.pc, 2);
+    }
+
+    #[test]
+    fn grab_2_bytes() {
+        let mut vm = VM::with_program(vec![20, 66, 4, 8]);
+
+        assert_eq!(vm.next_value(), Ok(5186));
+        assert_eq!(vm.pc, 2);
+
+        assert_eq!(vm.next_value(), Ok(1032));
+        assert_eq!(vm.pc, 4);
+    }
+
+    #[test]
+    fn immediate_round_trip() {
+        for value in [0, 1, 0x0102, 255, 256, -1, -12345, i16::MIN, i16::MAX] {
+            let bytes = Instr::Load(0, value).to_bytes();
+            let mut vm = VM::with_program(bytes[2..].to_vec());
+
+            assert_eq!(vm.next_value(), Ok(value as Reg));
+        }
+
+        assert_eq!(Instr::Load(0, 0x0102).to_bytes(), vec![1, 0, 0x01, 0x02]);
+    }
+    #[test]
+    fn opcode_load() {
+        let mut vm = VM::with_program(vec![
+            Opcode::LOAD.into(),
+            0,
+            0b0000000,
+            0b00000001,
+            Opcode::LOAD.into(),
+            1,
+            1,
+            0,
+            Opcode::LOAD.into(),
+            2,
+            255,
+            255,
+        ]);
+        vm.run().unwrap();
+        dbg!(&vm);
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(vm.registers[1], 256);
+        assert_eq!(vm.registers[2], -1);
+    }
+
+    #[test]
+    fn opcode_add() {
+        let mut vm = VM::with_program(asm("LOAD $0 #1\nLOAD $1 #2\nADD $0 $1 $2\nHLT"));
+
+        vm.run().unwrap();
+
+        dbg!(&vm);
+        assert_eq!(vm.registers[2], 3);
+    }
+
+    #[test]
+    fn opcode_mov() {
+        let mut vm = VM::with_program(asm("LOAD $0 #-42\nMOV $1 $0\nHLT"));
+        vm.run().unwrap();
+        assert_eq!(vm.registers[..2], [-42, -42]);
+    }
+
+    #[test]
+    fn opcode_inc_dec() {
+        let mut vm = VM::with_program(asm("LOAD $0 #5\nINC $0\nDEC $1\nDEC $1"));
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!((vm.state().pc, vm.registers[0]), (6, 6));
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!((vm.state().pc, vm.registers[1]), (10, -2));
+
+        vm.registers[2] = Reg::MAX;
+        vm.program.extend([Opcode::INC.into(), 2]);
+        assert_eq!(vm.run(), Err(VmError::Overflow));
+    }
+
+    #[test]
+    fn opcode_sub() {
+        let mut vm = VM::with_program(asm("LOAD $0 #1\nLOAD $1 #2\nSUB $0 $1 $2\nHLT"));
+
+        vm.run().unwrap();
+
+        dbg!(&vm);
+        assert_eq!(vm.registers[2], -1);
+    }
+
+    #[test]
+    fn opcode_mul() {
+        let mut vm = VM::with_program(asm("LOAD $0 #3\nLOAD $1 #2\nMUL $0 $1 $2\nHLT"));
+
+        vm.run().unwrap();
+
+        dbg!(&vm);
+        assert_eq!(vm.registers[2], 6);
+
+        // 100000 * 100000 doesn't fit in 32 bits.
+        let mut vm = VM::with_program(asm(
+            "LOAD $0 #1000\nLOAD $1 #100\nMUL $0 $1 $2\nMUL $2 $2 $3\nHLT",
+        ));
+        vm.run().unwrap();
+        assert_eq!(vm.registers[3], 10_000_000_000);
+    }
+
+    #[test]
+    fn opcode_div() {
+        let mut vm = VM::with_program(asm("LOAD $0 #3\nLOAD $1 #2\nDIV $0 $1 $2\nHLT"));
+
+        vm.run().unwrap();
+
+        dbg!(&vm);
+        assert_eq!(vm.registers[2], 1);
+        assert_eq!(vm.remainder, 1);
+    }
+
+    #[test]
+    fn opcode_mod() {
+        let mut vm = VM::with_program(asm("LOAD $0 #-7\nLOAD $1 #3\nMOD $0 $1 $2\nHLT"));
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], -1);
+        // Unlike DIV, MOD leaves the remainder register alone.
+        assert_eq!(vm.remainder, 0);
+
+        let mut vm = VM::with_program(asm("LOAD $0 #7\nMOD $0 $1 $2"));
+        assert_eq!(vm.run(), Err(VmError::DivideByZero));
+    }
+
+    #[test]
+    fn opcode_loadm_storem() {
+        let mut vm = VM::with_program(asm(
+            "LOAD $0 #300\nLOAD $1 #1000\nSTOREM $0 $1\nLOADM $2 $1\nLOADM $3 $0\nHLT",
+        ));
+        vm.run().unwrap();
+
+        // Only the low byte of 300 is stored, and untouched memory reads as 0.
+        assert_eq!(vm.registers[2], 44);
+        assert_eq!(vm.registers[3], 0);
+        assert_eq!(vm.memory[1000], 44);
+
+        for src in [
+            "LOAD $1 #-1\nLOADM $0 $1",
+            "LOAD $1 #1\nLOAD $2 #16\nSHL $1 $2 $1\nSTOREM $0 $1",
+        ] {
+            let mut vm = VM::with_program(asm(src));
+            assert!(matches!(
+                vm.run(),
+                Err(VmError::MemoryOutOfBounds(-1 | 65536))
+            ));
+        }
+    }
+
+    #[test]
+    fn opcode_rdrem() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, -7)
+                .load(1, 2)
+                .div(0, 1, 2)
+                .rdrem(3)
+                .to_bytes(),
+        );
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], -3);
+        assert_eq!(vm.registers[3], -1);
+    }
+
+    #[test]
+    fn opcode_rload() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(5, 42)
+                .load(0, 5)
+                .rload(0, 1)
+                .load(2, 261)
+                .rload(2, 3)
+                .to_bytes(),
+        );
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[1], 42);
+        assert_eq!(vm.registers[3], 42);
+    }
+
+    #[test]
+    fn opcode_jmp() {
+        let mut vm = VM::with_program(vec![Opcode::LOAD.into(), 1, 0, 0, Opcode::JMP.into(), 1]);
+
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 4);
+
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 0)
+    }
+
+    #[test]
+    fn opcode_jmpif() {
+        let mut vm = VM::with_program(asm("LOAD $0 #3\nLOAD $1 #2\nGT $0 $1\nJMPIF $0"));
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 4);
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 8);
+
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 11);
+        assert!(vm.cmp());
+
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 3);
+    }
+
+    #[test]
+    fn opcode_push_pop_peek() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 1)
+                .load(1, 2)
+                .push_reg(0)
+                .push_reg(1)
+                .peek(2)
+                .to_bytes(),
+        );
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], 2);
+        assert_eq!(vm.stack, vec![1, 2]);
+
+        vm.program
+            .extend(ProgramBuilder::new().pop(3).pop(4).pop(5).to_bytes());
+        assert_eq!(vm.run(), Err(VmError::StackUnderflow));
+        assert_eq!(vm.registers[3], 2);
+        assert_eq!(vm.registers[4], 1);
+
+        let mut vm = VM::with_program(ProgramBuilder::new().peek(0).to_bytes());
+        assert_eq!(vm.run(), Err(VmError::StackUnderflow));
+    }
+
+    #[test]
+    fn stack_manipulation() {
+        let stack_after = |builder: ProgramBuilder| {
+            let mut vm = VM::with_program(
+                ProgramBuilder::new()
+                    .load(0, 1)
+                    .load(1, 2)
+                    .push_reg(0)
+                    .push_reg(1)
+                    .to_bytes(),
+            );
+            vm.program.extend(builder.to_bytes());
+            vm.run().map(|_| vm.stack)
+        };
+        assert_eq!(stack_after(ProgramBuilder::new().drop()), Ok(vec![1]));
+        assert_eq!(stack_after(ProgramBuilder::new().sdup()), Ok(vec![1, 2, 2]));
+        assert_eq!(stack_after(ProgramBuilder::new().sswap()), Ok(vec![2, 1]));
+        assert_eq!(
+            stack_after(ProgramBuilder::new().drop().drop().drop()),
+            Err(VmError::StackUnderflow)
+        );
+
+        for program in [
+            ProgramBuilder::new().drop(),
+            ProgramBuilder::new().sdup(),
+            ProgramBuilder::new().sswap(),
+            ProgramBuilder::new().push_reg(0).sswap(),
+        ] {
+            let mut vm = VM::with_program(program.to_bytes());
+            assert_eq!(vm.run(), Err(VmError::StackUnderflow));
+        }
+    }
+
+    #[test]
+    fn jmpif_on_set_flag() {
+        let program = ProgramBuilder::new()
+            .load(0, 8)
+            .jmpif(0)
+            .load(1, 1)
+            .to_bytes();
+
+        let mut vm = VM::with_program(program.clone());
+        vm.set_cmp(true);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1], 0);
+
+        let mut vm = VM::with_program(program);
+        vm.set_cmp(true);
+        vm.set_cmp(false);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[1], 1);
+    }
+
+    #[test]
+    fn weighted_cost() {
+        let program = ProgramBuilder::new()
+            .load(0, 3)
+            .load(1, 2)
+            .add(0, 1, 2)
+            .mul(0, 1, 2)
+            .div(0, 1, 2)
+            .pow(0, 1, 2)
+            .halt()
+            .to_bytes();
+
+        let mut vm = VM::with_program(program.clone());
+        vm.run().unwrap();
+        assert_eq!(vm.total_cost(), 1 + 1 + 1 + 3 + 5 + 10 + 1);
+
+        let model = CostModel::uniform(0)
+            .with(Opcode::LOAD, 2)
+            .with(Opcode::POW, 7);
+        let mut vm = VM::with_program(program).with_cost_model(model);
+        vm.run().unwrap();
+        assert_eq!(vm.total_cost(), 2 + 2 + 7);
+        assert_eq!(vm.cycles(), 7);
+    }
+
+    #[test]
+    fn bitwise_opcodes() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 0b1100)
+                .load(1, 0b1010)
+                .and(0, 1, 2)
+                .or(0, 1, 3)
+                .xor(0, 1, 4)
+                .load(5, 1)
+                .load(6, 4)
+                .shl(5, 6, 7)
+                .load(8, -16)
+                .shr(8, 6, 9)
+                .load(10, 65)
+                .shl(5, 10, 11)
+                .to_bytes(),
+        );
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], 0b1000);
+        assert_eq!(vm.registers[3], 0b1110);
+        assert_eq!(vm.registers[4], 0b0110);
+        assert_eq!(vm.registers[7], 16);
+        assert_eq!(vm.registers[9], 0x0FFF_FFFF_FFFF_FFFF);
+        assert_eq!(vm.registers[11], 2);
+    }
+
+    #[test]
+    fn fault_leaves_pc_on_instruction() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 12)
+                .load(1, 0)
+                .div(0, 1, 2)
+                .to_bytes(),
+        );
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.run(), Err(VmError::DivideByZero));
+        assert_eq!(vm.pc, 8);
+        assert_eq!(vm.last_error(), Some(&VmError::DivideByZero));
+
+        // Fixing the divisor and retrying carries on from the DIV.
+        vm.registers[1] = 4;
+        vm.step().unwrap();
+        assert_eq!(vm.registers[2], 3);
+        assert_eq!(vm.pc, 12);
+        assert_eq!(vm.last_error(), Some(&VmError::DivideByZero));
+
+        vm.reset();
+        assert_eq!(vm.last_error(), None);
+        vm.program = vec![1, 0, 0, 5, 200];
+        assert_eq!(vm.run_compiled(), Err(VmError::IllegalOpcode(200)));
+        assert_eq!(vm.pc, 4);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        let program = ProgramBuilder::new().load(0, 1).out(0).to_bytes();
+        let mut vm = VM::with_program(program.clone()).with_decode_cache();
+        vm.capture_output();
+        vm.run().unwrap();
+
+        // LOAD $0 #1 becomes LOAD $0 #7. No opcode stores into the program, so this is the
+        // host writing to it.
+        assert_eq!(
+            vm.write_code(3, &[7]),
+            Err(VmError::SelfModifyingCode { at: 3 })
+        );
+        assert_eq!(vm.program, program);
+
+        vm.set_code_write_policy(CodeWritePolicy::Invalidate);
+        vm.write_code(3, &[7]).unwrap();
+        assert!(vm.decode_cached().is_empty());
+        vm.reset();
+        vm.run().unwrap();
+        assert_eq!(vm.take_output(), "1\n7\n");
+
+        assert_eq!(
+            vm.write_code(5, &[0, 0]),
+            Err(VmError::CodeWriteOutOfBounds { at: 5, len: 2 })
+        );
+        assert_eq!(
+            vm.write_code(usize::MAX, &[0]),
+            Err(VmError::CodeWriteOutOfBounds {
+                at: usize::MAX,
+                len: 1
+            })
+        );
+    }
+
+    #[test]
+    fn decode_cache_in_loop() {
+        // Counts $0 up to 10, looping back to the ADD at offset 12.
+        let program = ProgramBuilder::new()
+            .load(1, 1)
+            .load(2, 10)
+            .load(3, 12)
+            .add(0, 1, 0)
+            .gt(2, 0)
+            .jmpif(3)
+            .to_bytes();
+
+        let mut plain = VM::with_program(program.clone());
+        plain.run().unwrap();
+        let mut cached = VM::with_program(program).with_decode_cache();
+        cached.run().unwrap();
 
-    #[test]
-    fn init_vm() {
-        let vm = VM::default();
+        assert_eq!(cached.registers[0], 10);
+        assert_eq!(cached.state(), plain.state());
+        assert_eq!(cached.decode_cached(), [0, 4, 8, 12, 16, 19]);
+        assert!(plain.decode_cached().is_empty());
 
-        assert_eq!(vm.registers, [0; 256]);
+        // A stale entry is used until the cache is invalidated.
+        cached.reset();
+        cached.program[3] = 5;
+        cached.run_for(1).unwrap();
+        assert_eq!(cached.registers[1], 1);
+        cached.reset();
+        cached.invalidate_decode_cache();
+        cached.run_for(1).unwrap();
+        assert_eq!(cached.registers[1], 5);
     }
 
     #[test]
-    fn grab_byte() {
-        let mut vm = VM::with_program(vec![20, 66]);
-
-        assert_eq!(vm.next_byte(), 20u8);
-        assert_eq!(vm.pc, 1);
+    fn opcode_clamp() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, -10)
+                .load(1, 5)
+                .load(2, 50)
+                .load(8, 0)
+                .load(9, 20)
+                .clamp(0, 8, 9)
+                .clamp(1, 8, 9)
+                .clamp(2, 8, 9)
+                .clamp(9, 9, 9)
+                .to_bytes(),
+        );
+        vm.run().unwrap();
+        assert_eq!(vm.registers[..3], [0, 5, 20]);
+        assert_eq!(vm.registers[9], 20);
 
-        assert_eq!(vm.next_byte(), 66u8);
-        assert_eq!(vm.pc, 2);
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 3)
+                .load(1, 2)
+                .load(2, 1)
+                .clamp(0, 1, 2)
+                .to_bytes(),
+        );
+        assert_eq!(vm.run(), Err(VmError::EmptyClampRange { lo: 2, hi: 1 }));
+        assert_eq!(vm.registers[0], 3);
     }
 
     #[test]
-    fn grab_2_bytes() {
-        let mut vm = VM::with_program(vec![20, 66, 4, 8]);
+    fn reset_state() {
+        let program = ProgramBuilder::new()
+            .load(0, 7)
+            .load(1, 2)
+            .div(0, 1, 2)
+            .push_reg(2)
+            .eq(0, 0)
+            .to_bytes();
+        let mut vm = VM::with_program(program.clone());
+        vm.set_breakpoint(8);
+        vm.run().unwrap();
+        vm.run().unwrap();
 
-        assert_eq!(vm.next_value(), 5186i32);
-        assert_eq!(vm.pc, 2);
+        vm.reset();
+        assert_eq!(vm.state(), VM::with_program(program.clone()).state());
+        assert!(vm.stack.is_empty());
+        assert_eq!(vm.coverage(), vec![]);
+        assert_eq!(vm.program, program);
+        assert_eq!(vm.run(), Ok(RunOutcome::Breakpoint));
+    }
 
-        assert_eq!(vm.next_value(), 1032i32);
-        assert_eq!(vm.pc, 4);
+    /// Steps `program` through both the byte interpreter and the predecoded one behind
+    /// `run_compiled`, failing at the first instruction after which they disagree.
+    fn assert_engines_agree(program: Vec<u8>) {
+        let predecoded = Predecoded::new(&program);
+        let mut bytes = VM::with_program(program.clone());
+        let mut compiled = VM::with_program(program);
+        bytes.capture_output();
+        compiled.capture_output();
+
+        for step in 0..10_000 {
+            let at = format!("step {}, pc {}, {:?}", step, bytes.pc, bytes.next_instr());
+            let outcome = bytes.execute_once();
+            assert_eq!(
+                outcome,
+                compiled.execute_predecoded_once(&predecoded),
+                "{}",
+                at
+            );
+            assert_eq!(bytes.state(), compiled.state(), "{}", at);
+            assert_eq!(bytes.take_output(), compiled.take_output(), "{}", at);
+            if outcome != Ok(None) {
+                return;
+            }
+        }
+        panic!("program didn't finish in 10000 steps");
     }
+
     #[test]
-    fn opcode_load() {
-        let mut vm = VM::with_program(vec![
-            Opcode::LOAD.into(),
-            0,
-            0b0000000,
-            0b00000001,
-            Opcode::LOAD.into(),
-            1,
-            1,
-            0,
-            Opcode::LOAD.into(),
-            2,
-            255,
-            255,
-        ]);
-        vm.run();
-        dbg!(&vm);
-        assert_eq!(vm.registers[0], 1i32);
-        assert_eq!(vm.registers[1], 256i32);
-        assert_eq!(vm.registers[2], -1i32);
+    fn engines_agree() {
+        let programs = [
+            // Sums 10 down to 1 in a loop.
+            ProgramBuilder::new()
+                .load(0, 10)
+                .load(1, 0)
+                .load(2, 1)
+                .load(3, 0)
+                .load(4, 20)
+                .add(1, 0, 1)
+                .sub(0, 2, 0)
+                .gt(0, 3)
+                .jmpif(4)
+                .out(1)
+                .halt(),
+            // Calls the function at 9, which sets $1.
+            ProgramBuilder::new()
+                .load(0, 9)
+                .call(0)
+                .out(1)
+                .halt()
+                .load(1, 42)
+                .ret(),
+            // Jumps into the middle of the first LOAD.
+            ProgramBuilder::new().load(0, 0x00FF).load(1, 3).jmp(1),
+            ProgramBuilder::new().load(0, 1).trap().load(1, 2).trap(),
+            ProgramBuilder::new()
+                .load(0, 7)
+                .load(1, 2)
+                .div(0, 1, 2)
+                .rdrem(3)
+                .mul(2, 3, 4)
+                .pow(1, 1, 5)
+                .load(6, 0)
+                .div(0, 6, 7),
+            ProgramBuilder::new()
+                .load(0, 5)
+                .load(1, 6)
+                .push_reg(0)
+                .push_reg(1)
+                .sswap()
+                .sdup()
+                .peek(2)
+                .pop(3)
+                .drop()
+                .pop(4)
+                .pop(5),
+            ProgramBuilder::new()
+                .load(0, 0b1100)
+                .load(1, 3)
+                .and(0, 1, 2)
+                .or(0, 1, 3)
+                .xor(0, 1, 4)
+                .shl(0, 1, 5)
+                .shr(0, 1, 6)
+                .seq(0, 1, 7)
+                .sgt(0, 1, 8)
+                .slt(0, 1, 9)
+                .gtq(0, 1)
+                .not()
+                .getf(10)
+                .cmov(0, 1, 11)
+                .rload(1, 12)
+                .assert_eq(0, 0)
+                .cycles(13)
+                .assert_eq(0, 1),
+            ProgramBuilder::new()
+                .fload(0, 1.5)
+                .fload(1, 0.0)
+                .fadd(0, 0, 2)
+                .fdiv(2, 1, 3)
+                .load(0, 3)
+                .itof(0, 4)
+                .ftoi(2, 1),
+        ];
+
+        for program in programs {
+            assert_engines_agree(program.to_bytes());
+        }
+        assert_engines_agree(vec![Opcode::LOAD.into(), 0]);
+        assert_engines_agree(vec![200]);
     }
 
     #[test]
-    fn opcode_add() {
-        let mut vm = VM::with_program(vec![
-            Opcode::LOAD.into(),
-            0,
-            0,
-            1,
-            Opcode::LOAD.into(),
-            1,
-            0,
-            2,
-            Opcode::ADD.into(),
-            0,
-            1,
-            2,
-            Opcode::HLT.into(),
-        ]);
+    fn run_compiled_matches_run() {
+        let program = ProgramBuilder::new()
+            .load(0, 10)
+            .load(1, 0)
+            .load(2, 1)
+            .load(3, 0)
+            .load(4, 20)
+            .add(1, 0, 1)
+            .sub(0, 2, 0)
+            .gt(0, 3)
+            .jmpif(4)
+            .to_bytes();
 
-        vm.run();
-
-        dbg!(&vm);
-        assert_eq!(vm.registers[2], 3);
+        let mut vm = VM::with_program(program);
+        assert_eq!(vm.run_compiled(), Ok(RunOutcome::FellOffEnd));
+        assert_eq!(vm.registers[1], 55);
+        assert_eq!(vm.cycles(), 5 + 4 * 10);
     }
 
     #[test]
-    fn opcode_sub() {
-        let mut vm = VM::with_program(vec![
-            Opcode::LOAD.into(),
-            0,
-            0,
-            1,
-            Opcode::LOAD.into(),
-            1,
-            0,
-            2,
-            Opcode::SUB.into(),
-            0,
-            1,
-            2,
-            Opcode::HLT.into(),
-        ]);
+    fn bad_jump_targets() {
+        let mut vm = VM::with_program(ProgramBuilder::new().load(0, 100).jmp(0).to_bytes());
+        assert_eq!(vm.run(), Err(VmError::BadJumpTarget { target: 100, pc: 4 }));
 
-        vm.run();
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, -1)
+                .jmpif(0)
+                .not()
+                .jmpif(0)
+                .to_bytes(),
+        );
+        assert_eq!(vm.run(), Err(VmError::BadJumpTarget { target: -1, pc: 7 }));
 
-        dbg!(&vm);
-        assert_eq!(vm.registers[2], -1);
+        let mut vm = VM::with_program(ProgramBuilder::new().load(0, 8).call(0).to_bytes());
+        assert_eq!(vm.run(), Err(VmError::BadJumpTarget { target: 8, pc: 4 }));
+
+        let mut vm = VM::with_program(ProgramBuilder::new().load(0, 6).jmp(0).to_bytes());
+        assert_eq!(vm.run(), Ok(RunOutcome::FellOffEnd));
     }
 
     #[test]
-    fn opcode_mul() {
-        let mut vm = VM::with_program(vec![
-            Opcode::LOAD.into(),
-            0,
-            0,
-            3,
-            Opcode::LOAD.into(),
-            1,
-            0,
-            2,
-            Opcode::MUL.into(),
-            0,
-            1,
-            2,
-            Opcode::HLT.into(),
-        ]);
+    fn opcode_jmpf_jmpb() {
+        // Skips the LOAD of $1, landing on the HLT.
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 4)
+                .jmpf(0)
+                .load(1, 1)
+                .halt()
+                .to_bytes(),
+        );
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.state().pc, 10);
+        assert_eq!(vm.run(), Ok(RunOutcome::Halted));
+        assert_eq!(vm.registers[1], 0);
 
-        vm.run();
+        // Counts $0 down from 3, jumping back 11 bytes to the SUB until it reaches 0.
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 3)
+                .load(1, 1)
+                .load(2, 0)
+                .load(3, 11)
+                .load(4, 31)
+                .sub(0, 1, 0)
+                .eq(0, 2)
+                .jmpif(4)
+                .jmpb(3)
+                .halt()
+                .to_bytes(),
+        );
+        assert_eq!(vm.run(), Ok(RunOutcome::Halted));
+        assert_eq!((vm.state().pc, vm.registers[0]), (32, 0));
 
-        dbg!(&vm);
-        assert_eq!(vm.registers[2], 6);
+        let mut vm = VM::with_program(ProgramBuilder::new().load(0, 1).jmpf(0).to_bytes());
+        assert_eq!(vm.run(), Err(VmError::BadJumpTarget { target: 7, pc: 4 }));
+        let mut vm = VM::with_program(ProgramBuilder::new().load(0, 7).jmpb(0).to_bytes());
+        assert_eq!(vm.run(), Err(VmError::BadJumpTarget { target: -1, pc: 4 }));
     }
 
     #[test]
-    fn opcode_div() {
-        let mut vm = VM::with_program(vec![
-            Opcode::LOAD.into(),
-            0,
-            0,
-            3,
-            Opcode::LOAD.into(),
-            1,
-            0,
-            2,
-            Opcode::DIV.into(),
-            0,
-            1,
-            2,
-            Opcode::HLT.into(),
-        ]);
+    fn opcode_call_ret() {
+        // Calls the function at byte 11, which sets $1 and returns to the LOAD before the HLT.
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 11)
+                .call(0)
+                .load(2, 1)
+                .halt()
+                .load(1, 7)
+                .ret()
+                .to_bytes(),
+        );
+        vm.run().unwrap();
 
-        vm.run();
-
-        dbg!(&vm);
+        assert_eq!(vm.registers[1], 7);
         assert_eq!(vm.registers[2], 1);
-        assert_eq!(vm.remainder, 1);
+        assert!(vm.call_stack.is_empty());
     }
 
     #[test]
-    fn opcode_jmp() {
-        let mut vm = VM::with_program(vec![Opcode::LOAD.into(), 1, 0, 0, Opcode::JMP.into(), 1]);
-
-        vm.step();
-        assert_eq!(vm.pc, 4);
+    fn call_into_halting_routine() {
+        // The routine at byte 10 halts rather than returning, so the call is never popped.
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 10)
+                .call(0)
+                .load(2, 1)
+                .load(1, 7)
+                .halt()
+                .to_bytes(),
+        );
+        let trail: Vec<_> = vm.steps().map(|step| step.unwrap().0.pc).collect();
 
-        vm.step();
-        assert_eq!(vm.pc, 0)
+        assert_eq!(trail, vec![4, 10, 14, 15]);
+        assert_eq!(vm.call_stack, vec![6]);
+        assert_eq!((vm.registers[1], vm.registers[2]), (7, 0));
     }
 
     #[test]
-    fn opcode_jmpif() {
-        let mut vm = VM::with_program(vec![
-            Opcode::LOAD.into(),
-            0,
-            0,
-            3,
-            Opcode::LOAD.into(),
-            1,
-            0,
-            2,
-            Opcode::GT.into(),
-            0,
-            1,
-            Opcode::JMPIF.into(),
-            0,
-        ]);
-        vm.step();
-        assert_eq!(vm.pc, 4);
-        vm.step();
-        assert_eq!(vm.pc, 8);
+    fn call_depth_limit() {
+        // A function that unconditionally calls itself.
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 4)
+                .load(1, 1)
+                .call(0)
+                .to_bytes(),
+        );
+        vm.set_max_call_depth(16);
 
-        vm.step();
-        assert_eq!(vm.pc, 11);
-        assert!(vm.cmp);
+        assert_eq!(
+            vm.run_untrusted(Budget::default()),
+            Err(VmError::CallStackOverflow)
+        );
+        assert_eq!(vm.call_stack.len(), 16);
 
-        vm.step();
-        assert_eq!(vm.pc, 3);
+        let mut vm = VM::with_program(ProgramBuilder::new().ret().to_bytes());
+        assert_eq!(
+            vm.run_untrusted(Budget::default()),
+            Err(VmError::CallStackUnderflow)
+        );
     }
 
     #[test]
@@ -376,24 +2267,35 @@ mod tests {
             0,
             1,
         ]);
-        vm.step();
-        vm.step();
-        assert_eq!(vm.cmp, false);
-        vm.step();
-        assert_eq!(vm.cmp, true);
-        vm.step();
-        vm.step();
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.cmp(), false);
+        vm.step().unwrap();
+        assert_eq!(vm.cmp(), true);
+        vm.step().unwrap();
+        vm.step().unwrap();
         dbg!(&vm);
-        vm.step();
-        assert_eq!(vm.cmp, false);
+        vm.step().unwrap();
+        assert_eq!(vm.cmp(), false);
     }
 
     #[test]
     fn opcode_not() {
         let mut vm = VM::with_program(vec![Opcode::NOT.into()]);
 
-        vm.step();
-        assert_eq!(vm.cmp, true)
+        vm.step().unwrap();
+        assert_eq!(vm.cmp(), true)
+    }
+
+    #[test]
+    fn opcode_nop() {
+        let mut vm = VM::with_program(ProgramBuilder::new().load(0, 5).nop().to_bytes());
+        vm.step().unwrap();
+        let registers = vm.registers;
+
+        assert_eq!(vm.step().unwrap(), None);
+        assert_eq!(vm.state().pc, 5);
+        assert_eq!(vm.registers, registers);
     }
 
     #[test]
@@ -422,15 +2324,75 @@ mod tests {
             0,
             1,
         ]);
-        vm.step();
-        vm.step();
-        assert_eq!(vm.cmp, false);
-        vm.step();
-        assert_eq!(vm.cmp, true);
-        vm.step();
-        vm.step();
-        vm.step();
-        assert_eq!(vm.cmp, false);
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.cmp(), false);
+        vm.step().unwrap();
+        assert_eq!(vm.cmp(), true);
+        vm.step().unwrap();
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.cmp(), false);
+    }
+
+    #[test]
+    fn opcode_getf() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 2)
+                .load(1, 1)
+                .gt(0, 1)
+                .getf(2)
+                .eq(0, 1)
+                .getf(3)
+                .to_bytes(),
+        );
+        vm.registers[3] = 7;
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], 1);
+        assert_eq!(vm.registers[3], 0);
+    }
+
+    #[test]
+    fn opcode_set_comparisons() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 1)
+                .load(1, 2)
+                .seq(0, 0, 10)
+                .seq(0, 1, 11)
+                .sgt(1, 0, 12)
+                .sgt(0, 1, 13)
+                .sgt(0, 0, 14)
+                .slt(0, 1, 15)
+                .slt(1, 0, 16)
+                .slt(0, 0, 17)
+                .to_bytes(),
+        );
+        vm.registers[11] = 7;
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[10..18], [1, 0, 1, 0, 0, 1, 0, 0]);
+        assert_eq!(vm.cmp(), false);
+    }
+
+    #[test]
+    fn opcode_cmov() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 10)
+                .load(1, 20)
+                .gt(0, 1)
+                .cmov(0, 1, 2)
+                .not()
+                .cmov(0, 1, 3)
+                .to_bytes(),
+        );
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[2], 20);
+        assert_eq!(vm.registers[3], 10);
     }
 
     #[test]
@@ -459,14 +2421,522 @@ mod tests {
             0,
             1,
         ]);
-        vm.step();
-        vm.step();
-        assert_eq!(vm.cmp, false);
-        vm.step();
-        assert_eq!(vm.cmp, true);
-        vm.step();
-        vm.step();
-        vm.step();
-        assert_eq!(vm.cmp, false);
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.cmp(), false);
+        vm.step().unwrap();
+        assert_eq!(vm.cmp(), true);
+        vm.step().unwrap();
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.cmp(), false);
+    }
+
+    #[test]
+    fn opcode_fadd() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .fload(0, 1.5)
+                .fload(1, -0.25)
+                .fadd(0, 1, 2)
+                .halt()
+                .to_bytes(),
+        );
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.fregisters[2], 1.25);
+    }
+
+    #[test]
+    fn opcode_fdiv_by_zero() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .fload(0, 1.0)
+                .fdiv(0, 1, 2)
+                .fdiv(1, 1, 3)
+                .to_bytes(),
+        );
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.fregisters[2], f32::INFINITY);
+        assert!(vm.fregisters[3].is_nan());
+    }
+
+    #[test]
+    fn int_float_round_trip() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, -1234)
+                .itof(0, 5)
+                .ftoi(5, 1)
+                .to_bytes(),
+        );
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.fregisters[5], -1234.0);
+        assert_eq!(vm.registers[1], -1234);
+    }
+
+    #[test]
+    fn opcode_cycles() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 1)
+                .cycles(1)
+                .add(0, 0, 0)
+                .add(0, 0, 0)
+                .mul(0, 0, 0)
+                .cycles(2)
+                .sub(2, 1, 3)
+                .halt()
+                .to_bytes(),
+        );
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[1], 2);
+        // The first CYCLES, then the three arithmetic instructions.
+        assert_eq!(vm.registers[3], 4);
+        assert_eq!(vm.cycles(), 8);
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let program = asm("RND $0\nIN $1\nRND $2\nIN $3\nADD $1 $3 $4\nOUT $4\nHLT");
+        let mut recorded = VM::with_program(program.clone());
+        recorded.capture_output();
+        recorded.provide_input([40, 2]);
+        recorded.start_recording();
+        recorded.run().unwrap();
+        let recording = recorded.take_recording();
+        assert_eq!(recording.values.len(), 4);
+        assert_eq!(recording.values[1], 40);
+
+        // Without any input, and with different random numbers, replaying still gives exactly
+        // the same run.
+        let mut replayed = VM::with_program(program).with_recording(recording);
+        replayed.capture_output();
+        replayed.provide_input([]);
+        replayed.seed_random(12345);
+        replayed.run().unwrap();
+        assert_eq!(replayed.state(), recorded.state());
+        assert_eq!(replayed.take_output(), recorded.take_output());
+
+        replayed.reset();
+        assert_eq!(replayed.run(), Err(VmError::RecordingExhausted));
+        let mut live = VM::with_program(asm("IN $0"));
+        live.provide_input([]);
+        assert_eq!(live.run(), Err(VmError::NoInput));
+    }
+
+    #[test]
+    fn negative_immediate_end_to_end() {
+        use crate::parsing::assemble;
+        use chumsky::Parser;
+
+        let instrs = assemble().parse("LOAD $0 #-12345\nOUT $0").unwrap();
+        assert_eq!(instrs, vec![Instr::Load(0, -12345), Instr::Out(0)]);
+
+        let bytes: Vec<u8> = instrs.into_iter().flat_map(Instr::to_bytes).collect();
+        assert_eq!(bytes, vec![1, 0, 0xCF, 0xC7, 21, 0]);
+
+        let mut vm = VM::with_program(bytes);
+        vm.capture_output();
+        vm.run().unwrap();
+
+        assert_eq!(vm.registers[0], -12345);
+        assert_eq!(vm.take_output(), "-12345\n");
+    }
+
+    #[test]
+    fn run_outcomes() {
+        let mut vm = VM::with_program(ProgramBuilder::new().load(0, 1).halt().out(0).to_bytes());
+        assert_eq!(vm.run(), Ok(RunOutcome::Halted));
+        assert_eq!(vm.pc, 5);
+
+        let mut vm = VM::with_program(ProgramBuilder::new().load(0, 1).out(0).to_bytes());
+        vm.capture_output();
+        assert_eq!(vm.run(), Ok(RunOutcome::FellOffEnd));
+        assert_eq!(vm.take_output(), "1\n");
+
+        let mut vm = VM::with_program(ProgramBuilder::new().load(0, 0).jmp(0).to_bytes());
+        assert_eq!(vm.run_for(10), Ok(RunOutcome::LimitReached));
+        assert_eq!(vm.cycles(), 10);
+        assert_eq!(vm.run_for(5), Ok(RunOutcome::LimitReached));
+        assert_eq!(vm.cycles(), 15);
+    }
+
+    #[test]
+    fn run_to_trap() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 1)
+                .trap()
+                .load(1, 2)
+                .to_bytes(),
+        );
+
+        assert_eq!(vm.run(), Ok(RunOutcome::Trap));
+        assert_eq!(vm.pc, 4);
+        assert_eq!(vm.registers[0], 1);
+        assert_eq!(vm.next_instr(), Some(Instr::Trap));
+
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 5);
+        assert_eq!(vm.run(), Ok(RunOutcome::FellOffEnd));
+        assert_eq!(vm.registers[1], 2);
+
+        // Each TRAP is counted once, when it stops the run, not again when it's resumed.
+        assert_eq!(vm.cycles, 3);
+        assert_eq!(vm.total_cost(), 3);
+
+        let mut vm = VM::with_program(ProgramBuilder::new().trap().trap().halt().to_bytes())
+            .with_cost_model(CostModel::uniform(5));
+        assert_eq!(vm.run(), Ok(RunOutcome::Trap));
+        assert_eq!(vm.run(), Ok(RunOutcome::Trap));
+        assert_eq!(vm.pc, 1);
+        assert_eq!(vm.run(), Ok(RunOutcome::Halted));
+        assert_eq!(vm.cycles, 3);
+        assert_eq!(vm.total_cost(), 15);
+    }
+
+    #[test]
+    fn opcode_asserteq() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 7)
+                .load(1, 7)
+                .assert_eq(0, 1)
+                .load(1, 8)
+                .assert_eq(0, 1)
+                .halt()
+                .to_bytes(),
+        );
+        assert_eq!(vm.run(), Err(VmError::AssertionFailed { pc: 15 }));
+        assert_eq!(vm.registers[1], 8);
+    }
+
+    #[test]
+    fn coverage_of_untaken_branch() {
+        // 0: LOAD $0 #1, 4: LOAD $1 #2, 8: LOAD $2 #18, 12: EQ $0 $1, 15: JMPIF $2,
+        // 17: HLT, 18: OUT $0, 20: HLT
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 1)
+                .load(1, 2)
+                .load(2, 18)
+                .eq(0, 1)
+                .jmpif(2)
+                .halt()
+                .out(0)
+                .halt()
+                .to_bytes(),
+        );
+        assert_eq!(vm.coverage(), vec![]);
+
+        vm.run().unwrap();
+        assert_eq!(vm.coverage(), vec![0, 4, 8, 12, 15, 17]);
+        assert_eq!(vm.uncovered(), vec![18, 20]);
+    }
+
+    #[test]
+    fn run_to_breakpoint() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 1)
+                .load(1, 2)
+                .add(0, 1, 2)
+                .to_bytes(),
+        );
+        vm.set_breakpoint(4);
+
+        assert_eq!(vm.run(), Ok(RunOutcome::Breakpoint));
+        assert_eq!(vm.pc, 4);
+        assert_eq!(vm.registers[1], 0);
+
+        assert_eq!(vm.run(), Ok(RunOutcome::FellOffEnd));
+        assert_eq!(vm.registers[2], 3);
+    }
+
+    #[test]
+    fn step_trace() {
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 2)
+                .load(1, 3)
+                .mul(0, 1, 0)
+                .halt()
+                .load(0, 9)
+                .to_bytes(),
+        );
+        let trace: Vec<_> = vm
+            .steps()
+            .map(|step| {
+                let (state, outcome) = step.unwrap();
+                (state.pc, state.registers[0], state.registers[1], outcome)
+            })
+            .collect();
+
+        assert_eq!(
+            trace,
+            vec![
+                (4, 2, 0, StepOutcome::Continued),
+                (8, 2, 3, StepOutcome::Continued),
+                (12, 6, 3, StepOutcome::Continued),
+                (13, 6, 3, StepOutcome::Halted),
+            ]
+        );
+
+        let mut vm = VM::with_program(ProgramBuilder::new().load(0, 2).div(0, 1, 2).to_bytes());
+        let mut steps = vm.steps();
+        assert!(steps.next().unwrap().is_ok());
+        assert_eq!(steps.next(), Some(Err(VmError::DivideByZero)));
+        assert_eq!(steps.next(), None);
+    }
+
+    #[test]
+    fn step_returns_errors() {
+        let mut vm = VM::with_program(vec![1, 0, 0, 7, 200, 0]);
+        assert_eq!(vm.step(), Ok(None));
+        assert_eq!(vm.step(), Err(VmError::IllegalOpcode(200)));
+        assert_eq!(vm.last_error(), Some(&VmError::IllegalOpcode(200)));
+        assert_eq!(vm.registers[0], 7);
+    }
+
+    #[test]
+    fn arithmetic_modes() {
+        // 2^63, then Reg::MAX + 1 and Reg::MIN / -1 all overflow.
+        let program = ProgramBuilder::new()
+            .load(0, 2)
+            .load(1, 63)
+            .pow(0, 1, 2)
+            .load(3, -1)
+            .add(2, 3, 2)
+            .load(4, 1)
+            .add(2, 4, 5)
+            .div(5, 3, 6)
+            .to_bytes();
+
+        let mut vm = VM::with_program(program.clone());
+        assert_eq!(vm.run(), Err(VmError::Overflow));
+
+        let mut vm = VM::with_program(program.clone());
+        vm.set_arithmetic(ArithmeticMode::Wrapping);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[5], Reg::MIN);
+        assert_eq!(vm.registers[6], Reg::MIN);
+
+        let mut vm = VM::with_program(program);
+        vm.set_arithmetic(ArithmeticMode::Saturating);
+        vm.run().unwrap();
+        assert_eq!(vm.registers[5], Reg::MAX);
+        assert_eq!(vm.registers[6], -Reg::MAX);
+    }
+
+    #[test]
+    fn untrusted_runaway_loop() {
+        let mut vm = VM::with_program(ProgramBuilder::new().load(0, 0).jmp(0).to_bytes());
+
+        assert_eq!(
+            vm.run_untrusted(Budget {
+                max_cycles: 100,
+                ..Budget::default()
+            }),
+            Err(VmError::CycleLimitExceeded(100))
+        );
+        assert_eq!(vm.cycles(), 100);
+    }
+
+    #[test]
+    fn untrusted_budget_counts_from_now() {
+        let mut program = ProgramBuilder::new();
+        for r in 0..8 {
+            program = program.load(r, 1);
+        }
+        let mut vm = VM::with_program(program.to_bytes());
+        vm.run_for(3).unwrap();
+
+        let budget = Budget {
+            max_cycles: 3,
+            ..Budget::default()
+        };
+        assert_eq!(
+            vm.run_untrusted(budget),
+            Err(VmError::CycleLimitExceeded(3))
+        );
+        assert_eq!(vm.cycles, 6);
+        assert_eq!(vm.registers[..8], [1, 1, 1, 1, 1, 1, 0, 0]);
+        assert_eq!(vm.run_untrusted(budget), Ok(RunOutcome::FellOffEnd));
+        assert_eq!(vm.registers[..8], [1; 8]);
+    }
+
+    #[test]
+    fn untrusted_stack_limits() {
+        // Pushes forever, well within the cycle budget for a long time.
+        let pushing = ProgramBuilder::new()
+            .load(0, 4)
+            .push_reg(1)
+            .jmp(0)
+            .to_bytes();
+        let budget = Budget {
+            max_stack: 100,
+            ..Budget::default()
+        };
+        let mut vm = VM::with_program(pushing);
+        assert_eq!(vm.run_untrusted(budget), Err(VmError::StackOverflow));
+        assert_eq!(vm.stack.len(), 100);
+        // The VM's own limit is back to none afterwards.
+        assert_eq!(vm.max_stack, usize::MAX);
+
+        let mut vm = VM::with_program(ProgramBuilder::new().push_reg(0).sdup().to_bytes());
+        vm.set_max_stack(1);
+        assert_eq!(vm.run(), Err(VmError::StackOverflow));
+
+        // CALLs itself forever.
+        let calling = ProgramBuilder::new().load(0, 4).call(0).to_bytes();
+        let budget = Budget {
+            max_call_depth: 10,
+            ..Budget::default()
+        };
+        let mut vm = VM::with_program(calling);
+        assert_eq!(vm.run_untrusted(budget), Err(VmError::CallStackOverflow));
+        assert_eq!(vm.call_stack.len(), 10);
+        assert_eq!(vm.max_call_depth, 1024);
+    }
+
+    #[test]
+    fn untrusted_rejects_malformed_programs() {
+        let mut vm = VM::with_program(vec![Opcode::LOAD.into(), 0, 1]);
+        assert_eq!(
+            vm.run_untrusted(Budget::default()),
+            Err(VmError::ProgramCounterOutOfBounds(3))
+        );
+        assert_eq!(vm.cycles(), 0);
+
+        let mut vm = VM::with_program(vec![Opcode::NOT.into(), 200]);
+        assert_eq!(
+            vm.run_untrusted(Budget::default()),
+            Err(VmError::IllegalOpcode(200))
+        );
+    }
+
+    #[test]
+    fn untrusted_jump_into_garbage() {
+        // Jumps onto the last operand byte of the first LOAD, which decodes as IGL.
+        let mut vm = VM::with_program(
+            ProgramBuilder::new()
+                .load(0, 0x00FF)
+                .load(1, 3)
+                .jmp(1)
+                .to_bytes(),
+        );
+
+        assert_eq!(
+            vm.run_untrusted(Budget::default()),
+            Err(VmError::IllegalOpcode(0xFF))
+        );
+    }
+
+    #[test]
+    fn untrusted_arithmetic_faults() {
+        let programs = [
+            (
+                ProgramBuilder::new().load(0, 1).div(0, 1, 2),
+                VmError::DivideByZero,
+            ),
+            (
+                ProgramBuilder::new().load(0, 2).load(1, -1).pow(0, 1, 2),
+                VmError::NegativeExponent,
+            ),
+            (
+                ProgramBuilder::new().load(0, 100).pow(0, 0, 1),
+                VmError::Overflow,
+            ),
+            (
+                ProgramBuilder::new()
+                    .load(0, i16::MAX)
+                    .mul(0, 0, 0)
+                    .mul(0, 0, 0)
+                    .mul(0, 0, 0),
+                VmError::Overflow,
+            ),
+        ];
+
+        for (program, err) in programs {
+            let mut vm = VM::with_program(program.to_bytes());
+            assert_eq!(vm.run_untrusted(Budget::default()), Err(err));
+        }
+    }
+
+    #[test]
+    fn program_size_limit() {
+        let mut vm = VM::default();
+        vm.set_max_program_size(8);
+        assert_eq!(
+            vm.load_program(vec![0; 9]),
+            Err(VmError::ProgramTooLarge { len: 9, max: 8 })
+        );
+        assert!(vm.program.is_empty());
+
+        vm.load_program(vec![1, 0, 0, 7, 0]).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 7);
+    }
+
+    #[test]
+    fn config_from_env() {
+        let vars = |pairs: &'static [(&str, &str)]| {
+            move |var: &str| {
+                pairs
+                    .iter()
+                    .find(|(k, _)| *k == var)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+
+        let vm = VM::from_vars(vars(&[])).unwrap();
+        assert_eq!(vm.arithmetic, ArithmeticMode::Checked);
+        assert_eq!(vm.max_call_depth, 1024);
+        assert_eq!(vm.max_stack, usize::MAX);
+        assert_eq!(vm.max_program_size, 16 * 1024 * 1024);
+
+        let vm = VM::from_vars(vars(&[
+            ("HALIDE_ARITHMETIC", "saturating"),
+            ("HALIDE_MAX_CALL_DEPTH", " 16 "),
+            ("HALIDE_MAX_STACK", "100"),
+            ("HALIDE_MAX_PROGRAM_SIZE", "4096"),
+        ]))
+        .unwrap();
+        assert_eq!(vm.arithmetic, ArithmeticMode::Saturating);
+        assert_eq!(vm.max_call_depth, 16);
+        assert_eq!(vm.max_stack, 100);
+        assert_eq!(vm.max_program_size, 4096);
+
+        let err = VM::from_vars(vars(&[("HALIDE_ARITHMETIC", "Wrap")])).unwrap_err();
+        assert_eq!(err.var, "HALIDE_ARITHMETIC");
+        assert_eq!(
+            err.to_string(),
+            "HALIDE_ARITHMETIC is set to `Wrap`, expected `checked`, `wrapping` or `saturating`"
+        );
+        for name in [
+            "HALIDE_MAX_CALL_DEPTH",
+            "HALIDE_MAX_STACK",
+            "HALIDE_MAX_PROGRAM_SIZE",
+        ] {
+            for limit in ["0", "-3", "lots"] {
+                let err = VM::from_vars(|var| (var == name).then(|| limit.into())).unwrap_err();
+                assert_eq!(err.var, name);
+                assert_eq!(err.value, limit);
+            }
+        }
+
+        // Nothing else reads this one, so the real environment is safe to use here.
+        std::env::set_var("HALIDE_ARITHMETIC", "wrapping");
+        let vm = VM::from_env();
+        std::env::remove_var("HALIDE_ARITHMETIC");
+        assert_eq!(vm.unwrap().arithmetic, ArithmeticMode::Wrapping);
     }
 }
@@ -1,6 +1,13 @@
 use chumsky::Parser;
-use std::{error::Error, fs, num::ParseIntError, path::PathBuf};
-use vm::{opcode::instructions::Instr, parsing::assemble, VM};
+use std::{error::Error, fmt, fs, num::ParseIntError, path::PathBuf};
+use vm::{
+    opcode::{
+        instructions::{disassemble, disassemble_text, Instr},
+        Opcode,
+    },
+    parsing::{assemble, assemble_bytes, render_errors},
+    Reg, VM,
+};
 
 use clap::Parser as ArgParser;
 
@@ -13,6 +20,11 @@ struct Args {
 
     #[arg(short, long)]
     raw_hex: bool,
+
+    /// File to keep REPL history in.
+    /// Defaults to $HALIDE_VM_HISTORY, or halide-vm.history if that isn't set.
+    #[arg(long, value_name = "PATH")]
+    history: Option<PathBuf>,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -25,11 +37,17 @@ fn main() -> Result<(), Box<dyn Error>> {
 }
 
 fn repl(args: Args) -> Result<(), Box<dyn Error>> {
-    let mut rl = rustyline::config()?;
+    let history = rustyline::history_path(
+        args.history.clone(),
+        std::env::var_os("HALIDE_VM_HISTORY"),
+        "halide-vm.history",
+    );
+    let mut rl = rustyline::config(&history, !args.raw_hex)?;
 
     println!("halide-vm repl v0.0.1");
     let mut input = rl.readline(">> ");
     let mut vm = VM::default();
+    let mut show_hex = false;
     loop {
         match input {
             Ok(ref line) => {
@@ -39,8 +57,21 @@ fn repl(args: Args) -> Result<(), Box<dyn Error>> {
                 }
 
                 match line.as_str() {
-                    ".step" => vm.step(),
-                    ".run" => vm.run(),
+                    ".step" => {
+                        if let Some(Err(err)) = vm.steps().next() {
+                            eprintln!("error: {}", err);
+                        }
+                    }
+                    ".next" => match vm.next_instr() {
+                        Some(Instr::Illegal) => println!("Next: illegal opcode"),
+                        Some(instr) => println!("Next: {}", instr),
+                        None => println!("Next: nothing, end of program"),
+                    },
+                    ".list" => print!("{}", vm.listing(3)),
+                    ".run" | ".continue" => match vm.run() {
+                        Ok(outcome) => println!("Stopped: {:?}", outcome),
+                        Err(err) => eprintln!("error: {}", err),
+                    },
                     ".clear" => vm.program = vec![],
 
                     ".dbg" => {
@@ -51,14 +82,60 @@ fn repl(args: Args) -> Result<(), Box<dyn Error>> {
                         print!("Registers: ");
                         println!("{:?}", vm.registers)
                     }
+                    ".regs" if show_hex => print!("{}", vm.registers_hex()),
+                    ".regs" => {
+                        for (i, v) in vm.nonzero_registers() {
+                            println!("${} = {}", i, v);
+                        }
+                    }
+                    ".hex" => {
+                        show_hex = !show_hex;
+                        println!(
+                            "Registers shown in {}",
+                            if show_hex { "hex" } else { "decimal" }
+                        );
+                    }
                     ".program" => {
                         print!("Program: ");
                         println!("{:#?}", vm.program)
                     }
+                    ".disasm" => print!("{}", disasm(&vm.program)),
                     ".quit" => {
                         println!("buh-bye!");
                         std::process::exit(0);
                     }
+                    ".setflag 0" => vm.set_cmp(false),
+                    ".setflag 1" => vm.set_cmp(true),
+                    cmd if cmd.starts_with(".reg ") => {
+                        match cmd[".reg ".len()..].trim().trim_start_matches('$').parse() {
+                            Ok(r) => println!("{}", vm.register_bases(r)),
+                            Err(_) => eprintln!("usage: .reg <register>"),
+                        }
+                    }
+                    cmd if cmd.starts_with(".set ") => match parse_set(&cmd[".set ".len()..]) {
+                        Some((r, v)) => vm.registers[r as usize] = v,
+                        None => eprintln!("usage: .set <register> <value>"),
+                    },
+                    cmd if cmd.starts_with(".explain ") => {
+                        match explain(cmd.trim_start_matches(".explain ").trim()) {
+                            Ok(description) => println!("{}", description),
+                            Err(err) => eprintln!("{}", err),
+                        }
+                    }
+                    cmd if cmd.starts_with(".include ") => {
+                        let path = cmd.trim_start_matches(".include ").trim();
+                        match include(&mut vm, path) {
+                            Ok(len) => println!("Included {} bytes from {}", len, path),
+                            Err(err) => eprintln!("couldn't include {}: {}", path, err),
+                        }
+                    }
+                    cmd if cmd.starts_with(".dump-asm ") => {
+                        let path = cmd.trim_start_matches(".dump-asm ").trim();
+                        match fs::write(path, disassemble_text(&vm.program)) {
+                            Ok(()) => println!("Wrote the program to {}", path),
+                            Err(err) => eprintln!("couldn't write {}: {}", path, err),
+                        }
+                    }
                     input => match parse_input_to_bytes(input, &args) {
                         Ok(mut hex) => {
                             print!("Loading hex: ");
@@ -69,12 +146,12 @@ fn repl(args: Args) -> Result<(), Box<dyn Error>> {
                             vm.program.append(&mut hex);
                         }
                         Err(err) => {
-                            eprintln!("invalid input: {:?}", err);
+                            eprintln!("invalid input: {}", err);
                         }
                     },
                 }
                 rl.add_history_entry(line.as_str())?;
-                rl.save_history("halide-vm.history")?;
+                rl.save_history(&history)?;
 
                 input = rl.readline(">> ");
             }
@@ -93,7 +170,9 @@ fn parse_input_to_bytes(input: &str, args: &Args) -> Result<Vec<u8>, Box<dyn Err
     if args.raw_hex {
         Ok(parse_hex(input)?)
     } else {
-        let instr = assemble().parse(input).unwrap();
+        let instr = assemble()
+            .parse(input)
+            .map_err(|errs| render_errors(input, &errs))?;
 
         dbg!(&instr);
         let mapped: Vec<_> = instr.into_iter().map(Instr::to_bytes).collect();
@@ -102,15 +181,93 @@ fn parse_input_to_bytes(input: &str, args: &Args) -> Result<Vec<u8>, Box<dyn Err
     }
 }
 
-fn parse_hex(input: &str) -> Result<Vec<u8>, ParseIntError> {
+/// Assembles the file at `path` and appends it to the VM's program, returning how many bytes
+/// were added. Nothing is appended if any of the file fails to parse.
+fn include(vm: &mut VM, path: &str) -> Result<usize, Box<dyn Error>> {
+    let src = fs::read_to_string(path)?;
+    let mut bytes = assemble_bytes(&src).map_err(|errs| render_errors(&src, &errs))?;
+    let len = bytes.len();
+    vm.program.append(&mut bytes);
+    Ok(len)
+}
+
+/// The whole program for `.disasm`, one instruction per line with its byte offset. Bytes left
+/// over that are too short to be an instruction are shown as `<truncated>`.
+fn disasm(program: &[u8]) -> String {
+    disassemble(program)
+        .into_iter()
+        .map(
+            |(offset, instr)| match Instr::from_bytes(&program[offset..]) {
+                Some(_) => format!("{:04}  {}\n", offset, instr),
+                None => format!("{:04}  <truncated>\n", offset),
+            },
+        )
+        .collect()
+}
+
+/// Parses the arguments of `.set`, a register number (optionally written `$n`) and a value.
+fn parse_set(args: &str) -> Option<(u8, Reg)> {
+    let mut args = args.split_whitespace();
+    let register = args.next()?.trim_start_matches('$').parse().ok()?;
+    let value = args.next()?.parse().ok()?;
+    args.next().is_none().then_some((register, value))
+}
+
+/// Describes the opcode with this mnemonic, ignoring case.
+fn explain(mnemonic: &str) -> Result<&'static str, String> {
+    match Opcode::from_mnemonic(&mnemonic.to_uppercase()) {
+        Some(op) => Ok(op.description()),
+        None => {
+            let known: Vec<_> = Opcode::ALL.iter().map(Opcode::to_string).collect();
+            Err(format!(
+                "unknown mnemonic `{}`, expected one of: {}",
+                mnemonic,
+                known.join(", ")
+            ))
+        }
+    }
+}
+
+/// A token in `--raw-hex` input that isn't a hex byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HexError {
+    token: String,
+    /// Byte offset of `token` in the input.
+    offset: usize,
+    source: ParseIntError,
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` at offset {} isn't a hex byte: {}",
+            self.token, self.offset, self.source
+        )
+    }
+}
+
+impl Error for HexError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+fn parse_hex(input: &str) -> Result<Vec<u8>, HexError> {
     let mut results: Vec<u8> = vec![];
+    let mut offset = 0;
     for hex_string in input.split(' ') {
         match u8::from_str_radix(hex_string, 16) {
             Ok(result) => results.push(result),
-            Err(e) => {
-                return Err(e);
+            Err(source) => {
+                return Err(HexError {
+                    token: hex_string.to_string(),
+                    offset,
+                    source,
+                });
             }
         }
+        offset += hex_string.len() + 1;
     }
     Ok(results)
 }
@@ -119,38 +276,273 @@ fn run_bytecode(file: PathBuf) -> Result<(), Box<dyn Error>> {
     let str = fs::read_to_string(file)?;
     let hex = str.into_bytes();
     let mut vm = VM::default();
-    vm.program = hex;
-    vm.run();
+    vm.load_program(hex)?;
+    vm.run()?;
     Ok(())
 }
 
 mod rustyline {
     use rustyline::{
-        error::ReadlineError, highlight::MatchingBracketHighlighter, history::FileHistory,
-        validate::MatchingBracketValidator, Editor,
+        error::ReadlineError,
+        highlight::{Highlighter, MatchingBracketHighlighter},
+        history::FileHistory,
+        validate::MatchingBracketValidator,
+        Editor,
+    };
+    use rustyline_derive::{Completer, Helper, Hinter, Validator};
+    use std::{
+        borrow::Cow,
+        ffi::OsString,
+        ops::Range,
+        path::{Path, PathBuf},
     };
-    use rustyline_derive::{Completer, Helper, Highlighter, Hinter, Validator};
+    use vm::opcode::Opcode;
 
-    #[derive(Completer, Helper, Highlighter, Hinter, Validator)]
+    #[derive(Completer, Helper, Hinter, Validator)]
     pub struct Config {
         #[rustyline(Validator)]
         brackets: MatchingBracketValidator,
-        #[rustyline(Highlighter)]
         highlighting: MatchingBracketHighlighter,
+        /// Whether input is assembly, rather than raw hex, and should be highlighted as such.
+        assembly: bool,
     }
 
-    pub fn config() -> Result<Editor<Config, FileHistory>, ReadlineError> {
+    impl Highlighter for Config {
+        fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+            if !self.assembly {
+                return self.highlighting.highlight(line, pos);
+            }
+
+            let mut highlighted = String::with_capacity(line.len());
+            let mut end = 0;
+            for (range, token) in classify(line) {
+                highlighted.push_str(&line[end..range.start]);
+                match token.colour() {
+                    Some(colour) => highlighted.push_str(&format!(
+                        "\x1b[{}m{}\x1b[0m",
+                        colour,
+                        &line[range.clone()]
+                    )),
+                    None => highlighted.push_str(&line[range.clone()]),
+                }
+                end = range.end;
+            }
+            highlighted.push_str(&line[end..]);
+            Cow::Owned(highlighted)
+        }
+
+        fn highlight_char(&self, line: &str, pos: usize) -> bool {
+            self.assembly || self.highlighting.highlight_char(line, pos)
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Token {
+        Mnemonic,
+        Register,
+        Immediate,
+        /// A REPL command such as `.run`.
+        Command,
+        Unknown,
+    }
+
+    impl Token {
+        /// The ANSI SGR code to draw this kind of token in.
+        fn colour(self) -> Option<&'static str> {
+            match self {
+                Token::Mnemonic => Some("1;34"),
+                Token::Register => Some("33"),
+                Token::Immediate => Some("32"),
+                Token::Command => Some("35"),
+                Token::Unknown => None,
+            }
+        }
+    }
+
+    /// Splits a line of assembly into whitespace separated tokens, with the byte range of each.
+    pub fn classify(line: &str) -> Vec<(Range<usize>, Token)> {
+        let mut tokens = vec![];
+        let mut start = None;
+        for (i, c) in line.char_indices().chain([(line.len(), ' ')]) {
+            match (start, c.is_whitespace()) {
+                (None, false) => start = Some(i),
+                (Some(s), true) => {
+                    tokens.push((s..i, classify_token(&line[s..i])));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        tokens
+    }
+
+    fn classify_token(token: &str) -> Token {
+        let is_number = |s: &str| {
+            let s = s.strip_prefix('-').unwrap_or(s);
+            !s.is_empty() && s.chars().all(|c| c.is_ascii_digit() || c == '.')
+        };
+
+        if Opcode::from_mnemonic(token).is_some() {
+            Token::Mnemonic
+        } else if token.strip_prefix('$').is_some_and(is_number) {
+            Token::Register
+        } else if token.strip_prefix('#').is_some_and(is_number) {
+            Token::Immediate
+        } else if token.starts_with('.') {
+            Token::Command
+        } else {
+            Token::Unknown
+        }
+    }
+
+    /// The history file to use: the `--history` flag, then the environment variable, then the
+    /// REPL's default.
+    pub fn history_path(flag: Option<PathBuf>, env: Option<OsString>, default: &str) -> PathBuf {
+        flag.or_else(|| env.map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from(default))
+    }
+
+    pub fn config(
+        history: &Path,
+        assembly: bool,
+    ) -> Result<Editor<Config, FileHistory>, ReadlineError> {
         let config = Config {
             brackets: MatchingBracketValidator::new(),
             highlighting: MatchingBracketHighlighter::new(),
+            assembly,
         };
         let mut rl = Editor::new()?;
         rl.set_helper(Some(config));
-        match rl.load_history("halide-vm.history") {
-            Ok(()) => println!("history loaded from halide-vm.history"),
-            Err(err) => eprint!("error loading history from halide-vm.history: {}", err),
+        match rl.load_history(history) {
+            Ok(()) => println!("history loaded from {}", history.display()),
+            Err(err) => eprint!("error loading history from {}: {}", history.display(), err),
         }
 
         Ok(rl)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hex_reports_bad_token() {
+        assert_eq!(parse_hex("01 ff 03"), Ok(vec![1, 255, 3]));
+
+        let err = parse_hex("01 zz 03").unwrap_err();
+        assert_eq!(err.token, "zz");
+        assert_eq!(err.offset, 3);
+        assert_eq!(
+            err.to_string(),
+            "`zz` at offset 3 isn't a hex byte: invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn disasm_with_offsets() {
+        let program = vm::builder::ProgramBuilder::new()
+            .load(0, 5)
+            .add(0, 0, 1)
+            .halt()
+            .to_bytes();
+        assert_eq!(
+            disasm(&program),
+            "0000  LOAD $0 #5\n0004  ADD $0 $0 $1\n0008  HLT\n"
+        );
+        assert_eq!(
+            disasm(&[0, 255, 1, 0]),
+            "0000  HLT\n0001  IGL\n0002  <truncated>\n"
+        );
+    }
+
+    #[test]
+    fn parse_set_arguments() {
+        assert_eq!(parse_set("1 4"), Some((1, 4)));
+        assert_eq!(parse_set(" $255  -7 "), Some((255, -7)));
+        assert_eq!(parse_set("256 1"), None);
+        assert_eq!(parse_set("1"), None);
+        assert_eq!(parse_set("1 2 3"), None);
+    }
+
+    #[test]
+    fn include_appends_file() {
+        let path = std::env::temp_dir().join("halide-include-test.asm");
+        fs::write(&path, "LOAD $0 #2\nOUT $0\n").unwrap();
+
+        let mut vm = VM::with_program(vec![0]);
+        let len = include(&mut vm, path.to_str().unwrap()).unwrap();
+
+        assert_eq!(len, 6);
+        assert_eq!(vm.program, vec![0, 1, 0, 0, 2, 21, 0]);
+
+        fs::write(&path, "LOAD $0 #2\nNOPE\n").unwrap();
+        assert!(include(&mut vm, path.to_str().unwrap()).is_err());
+        assert_eq!(vm.program.len(), 7);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resolve_history_path() {
+        use crate::rustyline::history_path;
+
+        assert_eq!(
+            history_path(
+                Some("flag.history".into()),
+                Some("env.history".into()),
+                "default.history"
+            ),
+            PathBuf::from("flag.history")
+        );
+        assert_eq!(
+            history_path(None, Some("env.history".into()), "default.history"),
+            PathBuf::from("env.history")
+        );
+        assert_eq!(
+            history_path(None, None, "default.history"),
+            PathBuf::from("default.history")
+        );
+    }
+
+    #[test]
+    fn classify_assembly_tokens() {
+        use crate::rustyline::{classify, Token};
+
+        assert_eq!(
+            classify("ADD $0 $1 $2"),
+            vec![
+                (0..3, Token::Mnemonic),
+                (4..6, Token::Register),
+                (7..9, Token::Register),
+                (10..12, Token::Register),
+            ]
+        );
+        assert_eq!(
+            classify("  LOAD $10  #-5 x"),
+            vec![
+                (2..6, Token::Mnemonic),
+                (7..10, Token::Register),
+                (12..15, Token::Immediate),
+                (16..17, Token::Unknown),
+            ]
+        );
+        assert_eq!(classify(".run"), vec![(0..4, Token::Command)]);
+    }
+
+    #[test]
+    fn explain_opcodes() {
+        assert_eq!(explain("ADD"), Ok("ADD $a $b $d — d = a + b"));
+        assert_eq!(
+            explain("jmpif"),
+            Ok("JMPIF $t — jump to the byte offset in t if the comparison flag is set")
+        );
+        assert_eq!(
+            explain("RDREM"),
+            Ok("RDREM $d — d = the remainder of the last DIV")
+        );
+
+        let err = explain("MOVE").unwrap_err();
+        assert!(err.starts_with("unknown mnemonic `MOVE`, expected one of: HLT, LOAD, ADD"));
+    }
+}
@@ -1,126 +1,897 @@
-#[allow(clippy::upper_case_acronyms)]
-#[repr(u8)]
-#[derive(Clone, Copy)]
-pub enum Opcode {
-    HLT = 0,
+/// Generates `Opcode` from a single `NAME = byte => "description"` table, along with its byte
+/// conversions, `Display` mnemonic, `Opcode::ALL` and `Opcode::description`, so adding an opcode
+/// is a one line change.
+///
+/// There is deliberately no `From<u8>`: not every byte is an opcode, so decoding goes through
+/// `TryFrom<u8>`, which returns the offending byte on failure.
+macro_rules! opcodes {
+    ($($name:ident = $value:literal => $description:literal,)+) => {
+        #[allow(clippy::upper_case_acronyms)]
+        #[repr(u8)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum Opcode {
+            $($name = $value,)+
+        }
+
+        impl Opcode {
+            pub const ALL: &'static [Opcode] = &[$(Opcode::$name,)+];
+
+            /// The operand layout and a one line summary of what the opcode does.
+            pub fn description(self) -> &'static str {
+                match self {
+                    $(Opcode::$name => $description,)+
+                }
+            }
+
+            pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+                match mnemonic {
+                    $(stringify!($name) => Some(Opcode::$name),)+
+                    _ => None,
+                }
+            }
+        }
+
+        impl TryFrom<u8> for Opcode {
+            type Error = u8;
+
+            fn try_from(v: u8) -> Result<Self, Self::Error> {
+                match v {
+                    $($value => Ok(Opcode::$name),)+
+                    _ => Err(v),
+                }
+            }
+        }
+
+        impl From<Opcode> for u8 {
+            fn from(val: Opcode) -> Self {
+                val as u8
+            }
+        }
+
+        impl std::fmt::Display for Opcode {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(match self {
+                    $(Opcode::$name => stringify!($name),)+
+                })
+            }
+        }
+    };
+}
+
+opcodes! {
+    HLT = 0 => "HLT — stop the program",
+
+    LOAD = 1 => "LOAD $d #v — d = v, a 16-bit signed immediate",
+
+    ADD = 2 => "ADD $a $b $d — d = a + b",
+    SUB = 3 => "SUB $a $b $d — d = a - b",
+    MUL = 4 => "MUL $a $b $d — d = a * b",
+    DIV = 5 => "DIV $a $b $d — d = a / b, keeping a % b as the remainder",
+    POW = 6 => "POW $a $b $d — d = a to the power of b",
+
+    JMP = 7 => "JMP $t — jump to the byte offset in t",
+    JMPIF = 8 => "JMPIF $t — jump to the byte offset in t if the comparison flag is set",
+
+    EQ = 9 => "EQ $a $b — set the comparison flag to a == b",
+    NOT = 10 => "NOT — invert the comparison flag",
+
+    GT = 11 => "GT $a $b — set the comparison flag to a > b",
+    GTQ = 12 => "GTQ $a $b — set the comparison flag to a >= b",
+
+    FLOAD = 13 => "FLOAD $d #v — float register d = v, a 32-bit float immediate",
+    FADD = 14 => "FADD $a $b $d — float registers d = a + b",
+    FSUB = 15 => "FSUB $a $b $d — float registers d = a - b",
+    FMUL = 16 => "FMUL $a $b $d — float registers d = a * b",
+    FDIV = 17 => "FDIV $a $b $d — float registers d = a / b",
+    ITOF = 18 => "ITOF $s $d — float register d = integer register s",
+    FTOI = 19 => "FTOI $s $d — integer register d = float register s, truncated",
+
+    CYCLES = 20 => "CYCLES $d — d = the number of instructions executed so far",
+    OUT = 21 => "OUT $s — print s",
+    GETF = 22 => "GETF $d — d = 1 if the comparison flag is set, otherwise 0",
+
+    SEQ = 23 => "SEQ $a $b $d — d = 1 if a == b, otherwise 0",
+    SGT = 24 => "SGT $a $b $d — d = 1 if a > b, otherwise 0",
+    SLT = 25 => "SLT $a $b $d — d = 1 if a < b, otherwise 0",
+
+    RDREM = 26 => "RDREM $d — d = the remainder of the last DIV",
+
+    CALL = 27 => "CALL $t — push the return address and jump to the byte offset in t",
+    RET = 28 => "RET — jump back to the address pushed by the last CALL",
+
+    CMOV = 29 => "CMOV $a $b $d — d = a if the comparison flag is set, otherwise b",
+
+    TRAP = 30 => "TRAP — stop the run here, resuming continues past it",
+    ASSERTEQ = 31 => "ASSERTEQ $a $b — fault unless a == b",
+
+    RLOAD = 32 => "RLOAD $i $d — d = the register numbered by i, masked to 0-255",
+
+    PUSH = 33 => "PUSH $s — push s onto the stack",
+    POP = 34 => "POP $d — pop the top of the stack into d",
+    PEEK = 35 => "PEEK $d — d = the top of the stack, leaving it there",
+    DROP = 36 => "DROP — discard the top of the stack",
+    SDUP = 37 => "SDUP — push another copy of the top of the stack",
+    SSWAP = 38 => "SSWAP — swap the top two values on the stack",
+
+    AND = 39 => "AND $a $b $d — d = a & b",
+    OR = 40 => "OR $a $b $d — d = a | b",
+    XOR = 41 => "XOR $a $b $d — d = a ^ b",
+    SHL = 42 => "SHL $a $b $d — d = a << (b & 63)",
+    SHR = 43 => "SHR $a $b $d — d = a >> (b & 63), filling with zeroes",
+
+    CLAMP = 44 => "CLAMP $v $lo $hi — v = v clamped to lo..=hi, faulting if lo > hi",
+
+    IN = 45 => "IN $d — d = the next input value",
+    RND = 46 => "RND $d — d = a random number",
 
-    LOAD = 1,
+    MOD = 47 => "MOD $a $b $d — d = a % b, with the sign of a",
 
-    ADD = 2,
-    SUB = 3,
-    MUL = 4,
-    DIV = 5,
-    POW = 6,
+    LOADM = 48 => "LOADM $d $a — d = the memory byte at the address in a",
+    STOREM = 49 => "STOREM $s $a — the memory byte at the address in a = the low byte of s",
 
-    JMP = 7,
-    JMPIF = 8,
+    JMPF = 50 => "JMPF $o — jump o bytes forward from the next instruction",
+    JMPB = 51 => "JMPB $o — jump o bytes back from the next instruction",
 
-    EQ = 9,
-    NOT = 10,
+    NOP = 52 => "NOP — do nothing",
 
-    GT = 11,
-    GTQ = 12,
+    INC = 53 => "INC $r — r = r + 1",
+    DEC = 54 => "DEC $r — r = r - 1",
 
-    IGL = 255,
+    MOV = 55 => "MOV $d $s — d = s",
+
+    IGL = 255 => "IGL — illegal instruction, always faults",
 }
 
-impl From<u8> for Opcode {
-    fn from(v: u8) -> Self {
-        use Opcode::*;
-        match v {
-            0 => HLT,
-            1 => LOAD,
-            2 => ADD,
-            3 => SUB,
-            4 => MUL,
-            5 => DIV,
-            6 => POW,
-            7 => JMP,
-            8 => JMPIF,
-            9 => EQ,
-            10 => NOT,
-            11 => GT,
-            12 => GTQ,
-
-            _ => IGL,
+#[cfg(test)]
+mod tests {
+    use super::Opcode;
+
+    #[test]
+    fn opcode_byte_round_trip() {
+        for &op in Opcode::ALL {
+            assert_eq!(Opcode::try_from(u8::from(op)), Ok(op));
         }
+        for byte in 0..=u8::MAX {
+            if let Ok(op) = Opcode::try_from(byte) {
+                assert_eq!(u8::from(op), byte);
+            }
+        }
+        assert_eq!(Opcode::try_from(255), Ok(Opcode::IGL));
+        assert_eq!(Opcode::try_from(200), Err(200));
     }
-}
 
-impl From<Opcode> for u8 {
-    fn from(val: Opcode) -> Self {
-        val as u8
+    #[test]
+    fn opcode_values_unchanged() {
+        assert_eq!(u8::from(Opcode::HLT), 0);
+        assert_eq!(u8::from(Opcode::GTQ), 12);
+        assert_eq!(u8::from(Opcode::CYCLES), 20);
+    }
+
+    #[test]
+    fn opcode_mnemonics() {
+        assert_eq!(Opcode::LOAD.to_string(), "LOAD");
+        assert_eq!(Opcode::JMPIF.to_string(), "JMPIF");
+        for &op in Opcode::ALL {
+            assert_eq!(Opcode::from_mnemonic(&op.to_string()), Some(op));
+            assert!(op.description().starts_with(&op.to_string()));
+        }
+        assert_eq!(Opcode::from_mnemonic("load"), None);
     }
 }
 
 pub mod instructions {
+    use super::Opcode;
+
     type Register = u8;
     type Value = i16;
 
-    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    /// Float instructions operate on the separate float register bank, except for the
+    /// conversions: `IntToFloat(src, dest)` reads an integer register into a float register and
+    /// `FloatToInt(src, dest)` does the reverse.
+    ///
+    /// `SetEqual`, `SetGreaterThan` and `SetLessThan` compare their first two registers like
+    /// `EQ` and `GT`, but write `1` or `0` to the third instead of setting the comparison flag.
+    ///
+    /// `ConditionalMove(a, b, dest)` copies `a` into `dest` if the comparison flag is set, and `b`
+    /// otherwise.
+    ///
+    /// `FLoad` is encoded as `[FLOAD, reg, b3, b2, b1, b0]`, the IEEE 754 bits of the `f32`
+    /// with the most significant byte first, like the 16-bit `Load` immediate.
+    #[derive(Clone, Copy, Debug, PartialEq)]
     pub enum Instr {
         Halt,
+        Nop,
         Load(Register, Value),
         Add(Register, Register, Register),
         Subtract(Register, Register, Register),
         Multiply(Register, Register, Register),
         Divide(Register, Register, Register),
+        Mod(Register, Register, Register),
+        Inc(Register),
+        Dec(Register),
         Power(Register, Register, Register),
+        And(Register, Register, Register),
+        Or(Register, Register, Register),
+        Xor(Register, Register, Register),
+        Shl(Register, Register, Register),
+        Shr(Register, Register, Register),
+        Clamp(Register, Register, Register),
         Jump(Register),
         JumpIf(Register),
+        JumpForward(Register),
+        JumpBack(Register),
         Equal(Register, Register),
+        AssertEqual(Register, Register),
         Not,
         GreaterThan(Register, Register),
         GreaterThanEqual(Register, Register),
+        FLoad(Register, f32),
+        FAdd(Register, Register, Register),
+        FSubtract(Register, Register, Register),
+        FMultiply(Register, Register, Register),
+        FDivide(Register, Register, Register),
+        IntToFloat(Register, Register),
+        FloatToInt(Register, Register),
+        Cycles(Register),
+        Out(Register),
+        In(Register),
+        Random(Register),
+        Push(Register),
+        Pop(Register),
+        Peek(Register),
+        Drop,
+        StackDup,
+        StackSwap,
+        GetFlag(Register),
+        SetEqual(Register, Register, Register),
+        SetGreaterThan(Register, Register, Register),
+        SetLessThan(Register, Register, Register),
+        ReadRemainder(Register),
+        RegLoad(Register, Register),
+        Mov(Register, Register),
+        LoadMem(Register, Register),
+        StoreMem(Register, Register),
+        Call(Register),
+        Return,
+        Trap,
+        ConditionalMove(Register, Register, Register),
         Illegal,
     }
 
+    /// What a program is being encoded for, see `Instr::try_to_bytes_for`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Target {
+        /// How many integer and float registers there are. Registers from this number up are
+        /// rejected.
+        pub registers: usize,
+    }
+
+    impl Default for Target {
+        fn default() -> Self {
+            Self { registers: 256 }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum EncodeError {
+        /// `Instr::Illegal` has no encoding of its own, it only comes out of decoding.
+        Illegal,
+        RegisterOutOfRange {
+            register: u8,
+            registers: usize,
+        },
+    }
+
+    impl std::fmt::Display for EncodeError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                EncodeError::Illegal => write!(f, "can't encode an illegal instruction"),
+                EncodeError::RegisterOutOfRange {
+                    register,
+                    registers,
+                } => write!(
+                    f,
+                    "register {} is out of range, the target has {}",
+                    register, registers
+                ),
+            }
+        }
+    }
+
+    impl std::error::Error for EncodeError {}
+
     impl Instr {
+        /// `to_bytes`, failing for instructions the VM could never have decoded.
+        pub fn try_to_bytes(self) -> Result<Vec<u8>, EncodeError> {
+            self.try_to_bytes_for(&Target::default())
+        }
+
+        /// `to_bytes`, failing for instructions that aren't valid on `target`.
+        pub fn try_to_bytes_for(self, target: &Target) -> Result<Vec<u8>, EncodeError> {
+            if self == Instr::Illegal {
+                return Err(EncodeError::Illegal);
+            }
+            let out_of_range = self
+                .registers()
+                .into_iter()
+                .find(|&r| r as usize >= target.registers);
+            if let Some(register) = out_of_range {
+                return Err(EncodeError::RegisterOutOfRange {
+                    register,
+                    registers: target.registers,
+                });
+            }
+            Ok(self.to_bytes())
+        }
+
         pub fn to_bytes(self) -> Vec<u8> {
             use super::Opcode::*;
             use Instr::*;
             match self {
                 Halt => vec![HLT.into()],
-                Load(r, v) => vec![LOAD.into(), r, to_le_bytes(v).0, to_le_bytes(v).1],
+                Nop => vec![NOP.into()],
+                Load(r, v) => {
+                    let (hi, lo) = to_be_bytes(v);
+                    vec![LOAD.into(), r, hi, lo]
+                }
                 Add(r1, r2, dr) => vec![ADD.into(), r1, r2, dr],
                 Subtract(r1, r2, dr) => vec![SUB.into(), r1, r2, dr],
                 Multiply(r1, r2, dr) => vec![MUL.into(), r1, r2, dr],
                 Divide(r1, r2, dr) => vec![DIV.into(), r1, r2, dr],
+                Mod(r1, r2, dr) => vec![MOD.into(), r1, r2, dr],
+                Inc(r1) => vec![INC.into(), r1],
+                Dec(r1) => vec![DEC.into(), r1],
                 Power(r1, r2, dr) => vec![POW.into(), r1, r2, dr],
+                And(r1, r2, dr) => vec![AND.into(), r1, r2, dr],
+                Or(r1, r2, dr) => vec![OR.into(), r1, r2, dr],
+                Xor(r1, r2, dr) => vec![XOR.into(), r1, r2, dr],
+                Shl(r1, r2, dr) => vec![SHL.into(), r1, r2, dr],
+                Shr(r1, r2, dr) => vec![SHR.into(), r1, r2, dr],
+                Clamp(r, lo, hi) => vec![CLAMP.into(), r, lo, hi],
                 Jump(r1) => vec![JMP.into(), r1],
                 JumpIf(r1) => vec![JMPIF.into(), r1],
+                JumpForward(r1) => vec![JMPF.into(), r1],
+                JumpBack(r1) => vec![JMPB.into(), r1],
                 Equal(r1, r2) => vec![EQ.into(), r1, r2],
+                AssertEqual(r1, r2) => vec![ASSERTEQ.into(), r1, r2],
                 Not => vec![NOT.into()],
                 GreaterThan(r1, r2) => vec![GT.into(), r1, r2],
                 GreaterThanEqual(r1, r2) => vec![GTQ.into(), r1, r2],
+                FLoad(r, v) => {
+                    let mut bytes = vec![FLOAD.into(), r];
+                    bytes.extend(v.to_bits().to_be_bytes());
+                    bytes
+                }
+                FAdd(r1, r2, dr) => vec![FADD.into(), r1, r2, dr],
+                FSubtract(r1, r2, dr) => vec![FSUB.into(), r1, r2, dr],
+                FMultiply(r1, r2, dr) => vec![FMUL.into(), r1, r2, dr],
+                FDivide(r1, r2, dr) => vec![FDIV.into(), r1, r2, dr],
+                IntToFloat(r1, r2) => vec![ITOF.into(), r1, r2],
+                FloatToInt(r1, r2) => vec![FTOI.into(), r1, r2],
+                Cycles(r) => vec![CYCLES.into(), r],
+                Out(r) => vec![OUT.into(), r],
+                In(r) => vec![IN.into(), r],
+                Random(r) => vec![RND.into(), r],
+                Push(r) => vec![PUSH.into(), r],
+                Pop(r) => vec![POP.into(), r],
+                Peek(r) => vec![PEEK.into(), r],
+                Drop => vec![DROP.into()],
+                StackDup => vec![SDUP.into()],
+                StackSwap => vec![SSWAP.into()],
+                GetFlag(r) => vec![GETF.into(), r],
+                SetEqual(r1, r2, dr) => vec![SEQ.into(), r1, r2, dr],
+                SetGreaterThan(r1, r2, dr) => vec![SGT.into(), r1, r2, dr],
+                SetLessThan(r1, r2, dr) => vec![SLT.into(), r1, r2, dr],
+                ReadRemainder(r) => vec![RDREM.into(), r],
+                RegLoad(r1, r2) => vec![RLOAD.into(), r1, r2],
+                LoadMem(r1, r2) => vec![LOADM.into(), r1, r2],
+                Mov(r1, r2) => vec![MOV.into(), r1, r2],
+                StoreMem(r1, r2) => vec![STOREM.into(), r1, r2],
+                Call(r) => vec![CALL.into(), r],
+                Return => vec![RET.into()],
+                Trap => vec![TRAP.into()],
+                ConditionalMove(r1, r2, dr) => vec![CMOV.into(), r1, r2, dr],
                 Illegal => vec![IGL.into()],
             }
         }
+
+        /// Decodes the instruction at the start of `bytes`, returning it and its length in bytes.
+        ///
+        /// Returns `None` if `bytes` is empty or ends partway through the instruction's
+        /// operands. A byte that isn't a known opcode decodes to a one byte `Illegal`.
+        pub fn from_bytes(bytes: &[u8]) -> Option<(Instr, usize)> {
+            use super::Opcode::*;
+            use Instr::*;
+
+            let (&opcode, operands) = bytes.split_first()?;
+            let Ok(opcode) = super::Opcode::try_from(opcode) else {
+                return Some((Illegal, 1));
+            };
+            let byte = |i: usize| operands.get(i).copied();
+
+            let instr = match opcode {
+                HLT => Halt,
+                NOP => Nop,
+                LOAD => Load(byte(0)?, i16::from_be_bytes([byte(1)?, byte(2)?])),
+                ADD => Add(byte(0)?, byte(1)?, byte(2)?),
+                SUB => Subtract(byte(0)?, byte(1)?, byte(2)?),
+                MUL => Multiply(byte(0)?, byte(1)?, byte(2)?),
+                DIV => Divide(byte(0)?, byte(1)?, byte(2)?),
+                MOD => Mod(byte(0)?, byte(1)?, byte(2)?),
+                INC => Inc(byte(0)?),
+                DEC => Dec(byte(0)?),
+                POW => Power(byte(0)?, byte(1)?, byte(2)?),
+                AND => And(byte(0)?, byte(1)?, byte(2)?),
+                OR => Or(byte(0)?, byte(1)?, byte(2)?),
+                XOR => Xor(byte(0)?, byte(1)?, byte(2)?),
+                SHL => Shl(byte(0)?, byte(1)?, byte(2)?),
+                SHR => Shr(byte(0)?, byte(1)?, byte(2)?),
+                CLAMP => Clamp(byte(0)?, byte(1)?, byte(2)?),
+                JMP => Jump(byte(0)?),
+                JMPIF => JumpIf(byte(0)?),
+                JMPF => JumpForward(byte(0)?),
+                JMPB => JumpBack(byte(0)?),
+                EQ => Equal(byte(0)?, byte(1)?),
+                ASSERTEQ => AssertEqual(byte(0)?, byte(1)?),
+                NOT => Not,
+                GT => GreaterThan(byte(0)?, byte(1)?),
+                GTQ => GreaterThanEqual(byte(0)?, byte(1)?),
+                FLOAD => FLoad(
+                    byte(0)?,
+                    f32::from_bits(u32::from_be_bytes([byte(1)?, byte(2)?, byte(3)?, byte(4)?])),
+                ),
+                FADD => FAdd(byte(0)?, byte(1)?, byte(2)?),
+                FSUB => FSubtract(byte(0)?, byte(1)?, byte(2)?),
+                FMUL => FMultiply(byte(0)?, byte(1)?, byte(2)?),
+                FDIV => FDivide(byte(0)?, byte(1)?, byte(2)?),
+                ITOF => IntToFloat(byte(0)?, byte(1)?),
+                FTOI => FloatToInt(byte(0)?, byte(1)?),
+                CYCLES => Cycles(byte(0)?),
+                OUT => Out(byte(0)?),
+                IN => In(byte(0)?),
+                RND => Random(byte(0)?),
+                PUSH => Push(byte(0)?),
+                POP => Pop(byte(0)?),
+                PEEK => Peek(byte(0)?),
+                DROP => Drop,
+                SDUP => StackDup,
+                SSWAP => StackSwap,
+                GETF => GetFlag(byte(0)?),
+                SEQ => SetEqual(byte(0)?, byte(1)?, byte(2)?),
+                SGT => SetGreaterThan(byte(0)?, byte(1)?, byte(2)?),
+                SLT => SetLessThan(byte(0)?, byte(1)?, byte(2)?),
+                RDREM => ReadRemainder(byte(0)?),
+                RLOAD => RegLoad(byte(0)?, byte(1)?),
+                LOADM => LoadMem(byte(0)?, byte(1)?),
+                MOV => Mov(byte(0)?, byte(1)?),
+                STOREM => StoreMem(byte(0)?, byte(1)?),
+                CALL => Call(byte(0)?),
+                RET => Return,
+                TRAP => Trap,
+                CMOV => ConditionalMove(byte(0)?, byte(1)?, byte(2)?),
+                IGL => Illegal,
+            };
+            Some((instr, instr.to_bytes().len()))
+        }
+
+        pub fn opcode(self) -> Opcode {
+            use super::Opcode::*;
+            use Instr::*;
+            match self {
+                Halt => HLT,
+                Nop => NOP,
+                Load(..) => LOAD,
+                Add(..) => ADD,
+                Subtract(..) => SUB,
+                Multiply(..) => MUL,
+                Divide(..) => DIV,
+                Mod(..) => MOD,
+                Inc(..) => INC,
+                Dec(..) => DEC,
+                Power(..) => POW,
+                And(..) => AND,
+                Or(..) => OR,
+                Xor(..) => XOR,
+                Shl(..) => SHL,
+                Shr(..) => SHR,
+                Clamp(..) => CLAMP,
+                Jump(..) => JMP,
+                JumpIf(..) => JMPIF,
+                JumpForward(..) => JMPF,
+                JumpBack(..) => JMPB,
+                Equal(..) => EQ,
+                AssertEqual(..) => ASSERTEQ,
+                Not => NOT,
+                GreaterThan(..) => GT,
+                GreaterThanEqual(..) => GTQ,
+                FLoad(..) => FLOAD,
+                FAdd(..) => FADD,
+                FSubtract(..) => FSUB,
+                FMultiply(..) => FMUL,
+                FDivide(..) => FDIV,
+                IntToFloat(..) => ITOF,
+                FloatToInt(..) => FTOI,
+                Cycles(..) => CYCLES,
+                Out(..) => OUT,
+                In(..) => IN,
+                Random(..) => RND,
+                Push(..) => PUSH,
+                Pop(..) => POP,
+                Peek(..) => PEEK,
+                Drop => DROP,
+                StackDup => SDUP,
+                StackSwap => SSWAP,
+                GetFlag(..) => GETF,
+                SetEqual(..) => SEQ,
+                SetGreaterThan(..) => SGT,
+                SetLessThan(..) => SLT,
+                ReadRemainder(..) => RDREM,
+                RegLoad(..) => RLOAD,
+                LoadMem(..) => LOADM,
+                Mov(..) => MOV,
+                StoreMem(..) => STOREM,
+                Call(..) => CALL,
+                Return => RET,
+                Trap => TRAP,
+                ConditionalMove(..) => CMOV,
+                Illegal => IGL,
+            }
+        }
+
+        pub fn registers(self) -> Vec<Register> {
+            use Instr::*;
+            match self {
+                Halt | Nop | Not | Return | Trap | Drop | StackDup | StackSwap | Illegal => vec![],
+                Load(r, _)
+                | FLoad(r, _)
+                | Jump(r)
+                | JumpIf(r)
+                | JumpForward(r)
+                | JumpBack(r)
+                | Call(r)
+                | Inc(r)
+                | Dec(r)
+                | Cycles(r)
+                | Out(r)
+                | In(r)
+                | Random(r)
+                | Push(r)
+                | Pop(r)
+                | Peek(r)
+                | GetFlag(r)
+                | ReadRemainder(r) => {
+                    vec![r]
+                }
+                Equal(r1, r2)
+                | AssertEqual(r1, r2)
+                | GreaterThan(r1, r2)
+                | GreaterThanEqual(r1, r2)
+                | IntToFloat(r1, r2)
+                | RegLoad(r1, r2)
+                | LoadMem(r1, r2)
+                | StoreMem(r1, r2)
+                | Mov(r1, r2)
+                | FloatToInt(r1, r2) => vec![r1, r2],
+                Add(r1, r2, dr)
+                | Subtract(r1, r2, dr)
+                | Multiply(r1, r2, dr)
+                | Divide(r1, r2, dr)
+                | Mod(r1, r2, dr)
+                | Power(r1, r2, dr)
+                | And(r1, r2, dr)
+                | Or(r1, r2, dr)
+                | Xor(r1, r2, dr)
+                | Shl(r1, r2, dr)
+                | Shr(r1, r2, dr)
+                | Clamp(r1, r2, dr)
+                | SetEqual(r1, r2, dr)
+                | SetGreaterThan(r1, r2, dr)
+                | SetLessThan(r1, r2, dr)
+                | ConditionalMove(r1, r2, dr)
+                | FAdd(r1, r2, dr)
+                | FSubtract(r1, r2, dr)
+                | FMultiply(r1, r2, dr)
+                | FDivide(r1, r2, dr) => vec![r1, r2, dr],
+            }
+        }
+    }
+
+    /// Formats the instruction in the assembler's syntax, e.g. `LOAD $0 #5`.
+    impl std::fmt::Display for Instr {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.opcode())?;
+            for r in self.registers() {
+                write!(f, " ${}", r)?;
+            }
+            match self {
+                Instr::Load(_, v) => write!(f, " #{}", v),
+                Instr::FLoad(_, v) => write!(f, " #{}", v),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    /// Decodes a whole program, pairing each instruction with its byte offset. Trailing bytes
+    /// too short to be an instruction come out as a single `Illegal` at their offset.
+    pub fn disassemble(bytes: &[u8]) -> Vec<(usize, Instr)> {
+        let mut instrs = vec![];
+        let mut offset = 0;
+        while offset < bytes.len() {
+            match Instr::from_bytes(&bytes[offset..]) {
+                Some((instr, len)) => {
+                    instrs.push((offset, instr));
+                    offset += len;
+                }
+                None => {
+                    instrs.push((offset, Instr::Illegal));
+                    break;
+                }
+            }
+        }
+        instrs
+    }
+
+    /// `disassemble` as assembly source, one instruction per line, which assembles back to the
+    /// same bytes with three exceptions: a byte that isn't an opcode, or a program that ends
+    /// partway through an instruction, comes back as `IGL`'s byte, and every NaN in an `FLOAD`
+    /// comes back as the same NaN.
+    pub fn disassemble_text(bytes: &[u8]) -> String {
+        disassemble(bytes)
+            .into_iter()
+            .map(|(_, instr)| format!("{}\n", instr))
+            .collect()
+    }
+
+    #[derive(Debug)]
+    pub enum DecodeError {
+        Io(std::io::Error),
+        /// The stream ended partway through the instruction starting at this byte offset.
+        Truncated {
+            offset: usize,
+        },
+    }
+
+    impl std::fmt::Display for DecodeError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                DecodeError::Io(err) => write!(f, "{}", err),
+                DecodeError::Truncated { offset } => {
+                    write!(
+                        f,
+                        "instruction at {} is cut off by the end of input",
+                        offset
+                    )
+                }
+            }
+        }
     }
 
-    pub(super) fn to_le_bytes(v: i16) -> (u8, u8) {
-        let first = ((v & 0xFF00u16 as i16) >> 8) as u8;
-        let second = (v & 0x00FF) as u8;
-        (first, second)
+    impl std::error::Error for DecodeError {}
+
+    /// Decodes instructions one at a time as they're read, like `disassemble` without needing
+    /// the whole program in memory. Stops after the first error.
+    pub fn decode_stream<R: std::io::Read>(
+        reader: R,
+    ) -> impl Iterator<Item = Result<Instr, DecodeError>> {
+        let mut bytes = std::io::Read::bytes(std::io::BufReader::new(reader));
+        let mut offset = 0;
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            // No instruction is longer than FLOAD's opcode, register and 4 byte immediate.
+            let mut buf = Vec::with_capacity(6);
+            loop {
+                match bytes.next() {
+                    Some(Ok(byte)) => buf.push(byte),
+                    Some(Err(err)) => {
+                        done = true;
+                        return Some(Err(DecodeError::Io(err)));
+                    }
+                    None if buf.is_empty() => return None,
+                    None => {
+                        done = true;
+                        return Some(Err(DecodeError::Truncated { offset }));
+                    }
+                }
+                if let Some((instr, len)) = Instr::from_bytes(&buf) {
+                    offset += len;
+                    return Some(Ok(instr));
+                }
+            }
+        })
+    }
+
+    /// Splits a 16-bit immediate into its bytes, most significant first. All multi-byte
+    /// immediates are stored big-endian, and the VM's `next_value` reads them back the same way.
+    pub(super) fn to_be_bytes(v: i16) -> (u8, u8) {
+        let [hi, lo] = v.to_be_bytes();
+        (hi, lo)
     }
 
     #[cfg(test)]
     mod tests {
-        use crate::opcode::instructions::to_le_bytes;
-        use crate::opcode::instructions::Instr;
+        use crate::opcode::instructions::to_be_bytes;
+        use crate::opcode::instructions::{
+            decode_stream, disassemble, disassemble_text, DecodeError, EncodeError, Instr, Target,
+        };
+        use crate::opcode::Opcode;
+
+        #[test]
+        fn decode_from_bytes() {
+            assert_eq!(
+                Instr::from_bytes(&[1, 3, 0xFF, 0xFE, 0]),
+                Some((Instr::Load(3, -2), 4))
+            );
+            assert_eq!(Instr::from_bytes(&[7, 1]), Some((Instr::Jump(1), 2)));
+            assert_eq!(Instr::from_bytes(&[200, 1]), Some((Instr::Illegal, 1)));
+            assert_eq!(Instr::from_bytes(&[2, 0, 1]), None);
+            assert_eq!(Instr::from_bytes(&[]), None);
+        }
+
+        #[test]
+        fn bitwise_round_trip() {
+            for instr in [Instr::And(1, 2, 3), Instr::Or(4, 5, 6), Instr::Xor(7, 8, 9)] {
+                let bytes = instr.to_bytes();
+                assert_eq!(Instr::from_bytes(&bytes), Some((instr, 4)));
+                let text = disassemble_text(&bytes);
+                assert_eq!(crate::parsing::assemble_bytes(&text).unwrap(), bytes);
+            }
+        }
+
+        #[test]
+        fn every_instruction_round_trips() {
+            // The operand bytes are arbitrary, so whatever an opcode decodes them to has to
+            // encode back to exactly the bytes it consumed.
+            for &op in Opcode::ALL {
+                let bytes = [u8::from(op), 1, 2, 3, 4, 5];
+                let (instr, len) = Instr::from_bytes(&bytes).unwrap();
+                assert_eq!(instr.opcode(), op);
+                assert_eq!(instr.to_bytes(), bytes[..len], "{}", op);
+                assert_eq!(Instr::from_bytes(&instr.to_bytes()), Some((instr, len)));
+            }
+        }
+
+        #[test]
+        fn disassemble_with_offsets() {
+            assert_eq!(
+                disassemble(&[1, 0, 0, 5, 7, 0, 0, 2, 0]),
+                vec![
+                    (0, Instr::Load(0, 5)),
+                    (4, Instr::Jump(0)),
+                    (6, Instr::Halt),
+                    (7, Instr::Illegal),
+                ]
+            );
+        }
+
+        #[test]
+        fn decode_from_stream() {
+            let instrs = [
+                Instr::Load(0, -300),
+                Instr::FLoad(1, 2.5),
+                Instr::Add(0, 0, 2),
+                Instr::Jump(2),
+                Instr::Not,
+                Instr::Illegal,
+            ];
+            let mut bytes: Vec<u8> = instrs.iter().flat_map(|i| i.to_bytes()).collect();
+            let decoded: Vec<Instr> = decode_stream(&bytes[..]).map(Result::unwrap).collect();
+            assert_eq!(decoded, instrs);
+
+            // Half of a LOAD's immediate.
+            bytes.extend([1, 0, 0x12]);
+            let mut stream = decode_stream(&bytes[..]).skip(instrs.len());
+            assert!(matches!(
+                stream.next(),
+                Some(Err(DecodeError::Truncated { offset: 18 }))
+            ));
+            assert!(stream.next().is_none());
+        }
+
+        #[test]
+        fn disassemble_to_text() {
+            let program = crate::builder::ProgramBuilder::new()
+                .load(0, -5)
+                .load(1, 10)
+                .add(0, 1, 2)
+                .out(2)
+                .halt()
+                .build();
+            let bytes: Vec<u8> = program.into_iter().flat_map(Instr::to_bytes).collect();
+
+            let text = disassemble_text(&bytes);
+            assert_eq!(
+                text,
+                "LOAD $0 #-5\nLOAD $1 #10\nADD $0 $1 $2\nOUT $2\nHLT\n"
+            );
+            assert_eq!(crate::parsing::assemble_bytes(&text).unwrap(), bytes);
+        }
+
+        #[test]
+        fn disassembled_text_assembles_back() {
+            // One of every opcode, with arbitrary operands as in `every_instruction_round_trips`.
+            let mut program: Vec<Instr> = Opcode::ALL
+                .iter()
+                .map(|&op| Instr::from_bytes(&[u8::from(op), 1, 2, 3, 4, 5]).unwrap().0)
+                .collect();
+            program.extend([
+                Instr::FLoad(0, 1.5),
+                Instr::FLoad(1, f32::INFINITY),
+                Instr::FLoad(2, f32::NEG_INFINITY),
+                Instr::FLoad(3, f32::NAN),
+                Instr::FLoad(4, f32::from_bits(1)),
+            ]);
+            for instr in program {
+                let bytes = instr.to_bytes();
+                let text = disassemble_text(&bytes);
+                assert_eq!(
+                    crate::parsing::assemble_bytes(&text).ok(),
+                    Some(bytes),
+                    "{}",
+                    text
+                );
+            }
+
+            // Bytes that aren't instructions can't come back as themselves, only as IGL.
+            let text = disassemble_text(&[200, 0, 1, 0]);
+            assert_eq!(text, "IGL\nHLT\nIGL\n");
+            assert_eq!(
+                crate::parsing::assemble_bytes(&text).unwrap(),
+                [255, 0, 255]
+            );
+        }
+
+        #[test]
+        fn checked_encoding() {
+            let small = Target { registers: 16 };
+
+            assert_eq!(
+                Instr::Add(0, 1, 15).try_to_bytes_for(&small),
+                Ok(vec![2, 0, 1, 15])
+            );
+            assert_eq!(
+                Instr::Shl(0, 16, 2).try_to_bytes_for(&small),
+                Err(EncodeError::RegisterOutOfRange {
+                    register: 16,
+                    registers: 16
+                })
+            );
+            assert_eq!(
+                Instr::Load(255, -1).try_to_bytes(),
+                Ok(vec![1, 255, 0xFF, 0xFF])
+            );
+            assert_eq!(Instr::Illegal.try_to_bytes(), Err(EncodeError::Illegal));
+        }
+
+        #[test]
+        fn display_instructions() {
+            assert_eq!(Instr::Load(0, -5).to_string(), "LOAD $0 #-5");
+            assert_eq!(Instr::Add(0, 1, 2).to_string(), "ADD $0 $1 $2");
+            assert_eq!(Instr::FLoad(1, 1.5).to_string(), "FLOAD $1 #1.5");
+            assert_eq!(Instr::Halt.to_string(), "HLT");
+        }
 
         #[test]
         fn byte_splitting() {
             let value = 0b00000010_00000011;
-            assert_eq!(to_le_bytes(value), (0b00000010u8, 0b00000011u8));
+            assert_eq!(to_be_bytes(value), (0b00000010u8, 0b00000011u8));
             let value = 2;
-            assert_eq!(to_le_bytes(value), (0u8, 2));
+            assert_eq!(to_be_bytes(value), (0u8, 2));
         }
 
         #[test]
         fn byte_splitting_two_bytes() {
             let value = 16;
-            assert_eq!(to_le_bytes(value), (0u8, 16));
+            assert_eq!(to_be_bytes(value), (0u8, 16));
+        }
+
+        #[test]
+        fn byte_splitting_is_big_endian() {
+            assert_eq!(to_be_bytes(0x0102), (0x01, 0x02));
+            assert_eq!(to_be_bytes(-2), (0xFF, 0xFE));
+            assert_eq!(to_be_bytes(i16::MIN), (0x80, 0x00));
         }
 
         macro_rules! byte_check {
@@ -138,14 +909,45 @@ pub mod instructions {
         fn instructions_to_bytes() {
             use Instr::*;
             byte_check!(Halt => 0);
+            byte_check!(Nop => 52);
             byte_check!(Not => 10);
             byte_check!(Illegal => 255);
 
             byte_check!(Jump(0) => [7, 0]);
             byte_check!(JumpIf(1) => [8, 1]);
+            byte_check!(JumpForward(2) => [50, 2]);
+            byte_check!(JumpBack(3) => [51, 3]);
+            byte_check!(Cycles(3) => [20, 3]);
+            byte_check!(Out(3) => [21, 3]);
+            byte_check!(In(3) => [45, 3]);
+            byte_check!(Random(4) => [46, 4]);
+            byte_check!(Push(3) => [33, 3]);
+            byte_check!(Pop(4) => [34, 4]);
+            byte_check!(Peek(5) => [35, 5]);
+            byte_check!(Drop => 36);
+            byte_check!(StackDup => 37);
+            byte_check!(StackSwap => 38);
+            byte_check!(GetFlag(3) => [22, 3]);
+            byte_check!(ReadRemainder(3) => [26, 3]);
+            byte_check!(RegLoad(4, 5) => [32, 4, 5]);
+            byte_check!(LoadMem(1, 2) => [48, 1, 2]);
+            byte_check!(Mov(5, 6) => [55, 5, 6]);
+            byte_check!(StoreMem(3, 4) => [49, 3, 4]);
+            byte_check!(Call(3) => [27, 3]);
+            byte_check!(Return => 28);
+            byte_check!(Trap => 30);
+            byte_check!(AssertEqual(3, 4) => [31, 3, 4]);
+            byte_check!(ConditionalMove(0, 1, 2) => [29, 0, 1, 2]);
 
             byte_check!(Load(0, 2) => [1, 0, 0, 2]);
             byte_check!(Load(1, 19) => [1, 1, 0, 19]);
+            // Both bytes of the immediate are kept whole, not just the low nibble.
+            byte_check!(Load(0, 300) => [1, 0, 0x01, 0x2C]);
+            byte_check!(Load(0, 0x1234) => [1, 0, 0x12, 0x34]);
+            assert_eq!(
+                Instr::from_bytes(&[1, 0, 0x01, 0x2C]),
+                Some((Load(0, 300), 4))
+            );
 
             byte_check!(Equal(0, 2) => [9, 0, 2]);
             byte_check!(GreaterThan(0, 2) => [11, 0, 2]);
@@ -155,7 +957,27 @@ pub mod instructions {
             byte_check!(Subtract(0, 1, 2) => [3, 0, 1, 2]);
             byte_check!(Multiply(0, 1, 2) => [4, 0, 1, 2]);
             byte_check!(Divide(0, 1, 2) => [5, 0, 1, 2]);
+            byte_check!(Mod(0, 1, 2) => [47, 0, 1, 2]);
+            byte_check!(Inc(3) => [53, 3]);
+            byte_check!(Dec(4) => [54, 4]);
             byte_check!(Power(0, 3, 2) => [6, 0, 3, 2]);
+            byte_check!(And(0, 1, 2) => [39, 0, 1, 2]);
+            byte_check!(Or(0, 1, 2) => [40, 0, 1, 2]);
+            byte_check!(Xor(0, 1, 2) => [41, 0, 1, 2]);
+            byte_check!(Shl(0, 1, 2) => [42, 0, 1, 2]);
+            byte_check!(Shr(0, 1, 2) => [43, 0, 1, 2]);
+            byte_check!(Clamp(0, 1, 2) => [44, 0, 1, 2]);
+            byte_check!(SetEqual(0, 1, 2) => [23, 0, 1, 2]);
+            byte_check!(SetGreaterThan(0, 1, 2) => [24, 0, 1, 2]);
+            byte_check!(SetLessThan(0, 1, 2) => [25, 0, 1, 2]);
+
+            byte_check!(FLoad(0, 1.5) => [13, 0, 0x3F, 0xC0, 0, 0]);
+            byte_check!(FAdd(0, 1, 2) => [14, 0, 1, 2]);
+            byte_check!(FSubtract(0, 1, 2) => [15, 0, 1, 2]);
+            byte_check!(FMultiply(0, 1, 2) => [16, 0, 1, 2]);
+            byte_check!(FDivide(0, 1, 2) => [17, 0, 1, 2]);
+            byte_check!(IntToFloat(0, 1) => [18, 0, 1]);
+            byte_check!(FloatToInt(1, 0) => [19, 1, 0]);
         }
     }
 }
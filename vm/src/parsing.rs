@@ -1,30 +1,209 @@
-use chumsky::prelude::*;
+use std::{collections::HashMap, ops::Range};
+
+use chumsky::{error, prelude::*};
 
 use crate::opcode::instructions::Instr;
 
-pub fn assemble() -> impl Parser<char, Vec<Instr>, Error = Simple<char>> {
-    let register = just(" $").ignore_then(
-        text::digits::<char, Simple<char>>(10)
-            .from_str::<u8>()
-            .unwrapped(),
-    );
-    let value = just(" #").ignore_then(
+/// The register branches load their target into, see `Line::Branch`.
+pub const BRANCH_REGISTER: u8 = 255;
+
+/// A line of assembly that may use labels, see `assemble_lines`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    Instr(Instr),
+    /// `name:`, marking the offset of whatever comes next.
+    Label {
+        name: String,
+        span: Range<usize>,
+    },
+    /// `BGT $a $b label`, which compares `a` with `b` and jumps to `label` if the comparison
+    /// holds. Expands to the comparison, a `LOAD` of the label's offset into `BRANCH_REGISTER`,
+    /// and a `JMPIF`, so it overwrites the flag and that register whether or not it jumps.
+    Branch {
+        cond: Branch,
+        a: u8,
+        b: u8,
+        label: String,
+        span: Range<usize>,
+    },
+    /// `JMP label`, `JMPIF label` or `CALL label`. Expands to a `LOAD` of the label's offset
+    /// into `BRANCH_REGISTER` and the jump on that register.
+    Jump {
+        kind: JumpKind,
+        label: String,
+        span: Range<usize>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Branch {
+    Gt,
+    Lt,
+    Eq,
+    Ne,
+}
+
+impl Branch {
+    /// The instructions that set the flag when `a` and `b` satisfy the condition.
+    fn compare(self, a: u8, b: u8) -> Vec<Instr> {
+        match self {
+            Branch::Gt => vec![Instr::GreaterThan(a, b)],
+            Branch::Lt => vec![Instr::GreaterThan(b, a)],
+            Branch::Eq => vec![Instr::Equal(a, b)],
+            Branch::Ne => vec![Instr::Equal(a, b), Instr::Not],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JumpKind {
+    Jmp,
+    JmpIf,
+    Call,
+}
+
+impl JumpKind {
+    fn instr(self, r: u8) -> Instr {
+        match self {
+            JumpKind::Jmp => Instr::Jump(r),
+            JumpKind::JmpIf => Instr::JumpIf(r),
+            JumpKind::Call => Instr::Call(r),
+        }
+    }
+}
+
+/// A register operand, ` $n`. Like an immediate, one that's out of range is reported but
+/// still parses, as 0.
+fn register() -> impl Parser<char, u8, Error = Simple<char>> + Copy {
+    just(" $").ignore_then(text::digits::<char, Simple<char>>(10).validate(
+        |digits: String, span, emit| {
+            digits.parse::<u8>().unwrap_or_else(|_| {
+                emit(Simple::custom(
+                    span,
+                    format!("register {} out of range for u8", digits),
+                ));
+                0
+            })
+        },
+    ))
+}
+
+/// A single instruction, in the syntax `Instr` is displayed in.
+fn instruction() -> impl Parser<char, Instr, Error = Simple<char>> {
+    let register = register();
+    // Out of range numbers are reported but still parse, as 0, so the rest of the line is
+    // checked too. The sign is parsed along with the digits so that `#-32768` fits, and a
+    // `0x`, `0b` or `0o` prefix after it gives the digits in hex, binary or octal.
+    let prefixed = |prefix: &'static str, radix| {
+        just(prefix)
+            .ignore_then(text::digits(radix))
+            .map(move |digits: String| (prefix, radix, digits))
+    };
+    let number = just('-')
+        .or_not()
+        .then(choice((
+            prefixed("0x", 16),
+            prefixed("0b", 2),
+            prefixed("0o", 8),
+            text::digits(10).map(|digits| ("", 10, digits)),
+        )))
+        .validate(|(minus, (prefix, radix, digits)), span, emit| {
+            let sign = if minus.is_some() { "-" } else { "" };
+            i16::from_str_radix(&format!("{sign}{digits}"), radix).unwrap_or_else(|_| {
+                emit(Simple::custom(
+                    span,
+                    format!("immediate {sign}{prefix}{digits} out of range for i16"),
+                ));
+                0
+            })
+        });
+    // A character like `'A'` or `'\n'` is its code point.
+    let escape = just('\\').ignore_then(choice((
+        just('n').to('\n'),
+        just('t').to('\t'),
+        just('0').to('\0'),
+        just('\\'),
+        just('\''),
+    )));
+    let character = escape
+        .or(filter(|c: &char| *c != '\'' && *c != '\\' && *c != '\n'))
+        .delimited_by(just('\''), just('\''))
+        .validate(|c, span, emit| {
+            i16::try_from(u32::from(c)).unwrap_or_else(|_| {
+                emit(Simple::custom(
+                    span,
+                    format!("character {:?} out of range for i16", c),
+                ));
+                0
+            })
+        });
+    let value = just(" #").ignore_then(character.or(number));
+
+    // `inf`, `-inf` and `NaN` are accepted too, since that's how they're displayed.
+    let float = just(" #").ignore_then(choice((
+        just("NaN").to(f32::NAN),
         just('-')
             .or_not()
-            .then(
-                text::digits::<char, Simple<char>>(10)
-                    .from_str::<i16>()
-                    .unwrapped(),
-            )
-            .foldr(|_, b| -b),
-    );
+            .then_ignore(just("inf"))
+            .map(|minus| match minus {
+                Some(_) => f32::NEG_INFINITY,
+                None => f32::INFINITY,
+            }),
+        just('-')
+            .or_not()
+            .chain::<char, _, _>(text::digits(10))
+            .chain::<char, _, _>(just('.').chain(text::digits(10)).or_not().flatten())
+            .collect::<String>()
+            .from_str::<f32>()
+            .unwrapped(),
+    )));
 
     let op_halt = just("HLT").to(Instr::Halt);
     let op_not = just("NOT").to(Instr::Not);
+    let op_nop = just("NOP").to(Instr::Nop);
+    let op_ret = just("RET").to(Instr::Return);
+    let op_trap = just("TRAP").to(Instr::Trap);
+    let op_igl = just("IGL").to(Instr::Illegal);
 
     let op_jmp = just("JMP").ignore_then(register).map(Instr::Jump);
     let op_jmpif = just("JMPIF").ignore_then(register).map(Instr::JumpIf);
+    let op_jmpf = just("JMPF").ignore_then(register).map(Instr::JumpForward);
+    let op_jmpb = just("JMPB").ignore_then(register).map(Instr::JumpBack);
+    let op_call = just("CALL").ignore_then(register).map(Instr::Call);
+    let op_cycles = just("CYCLES").ignore_then(register).map(Instr::Cycles);
+    let op_out = just("OUT").ignore_then(register).map(Instr::Out);
+    let op_in = just("IN").ignore_then(register).map(Instr::In);
+    let op_rnd = just("RND").ignore_then(register).map(Instr::Random);
 
+    let op_push = just("PUSH").ignore_then(register).map(Instr::Push);
+    let op_pop = just("POP").ignore_then(register).map(Instr::Pop);
+    let op_peek = just("PEEK").ignore_then(register).map(Instr::Peek);
+    let op_drop = just("DROP").to(Instr::Drop);
+    let op_sdup = just("SDUP").to(Instr::StackDup);
+    let op_sswap = just("SSWAP").to(Instr::StackSwap);
+    let op_getf = just("GETF").ignore_then(register).map(Instr::GetFlag);
+    let op_rdrem = just("RDREM")
+        .ignore_then(register)
+        .map(Instr::ReadRemainder);
+    let op_rload = just("RLOAD")
+        .ignore_then(register)
+        .then(register)
+        .map(|(r1, r2)| Instr::RegLoad(r1, r2));
+    let op_loadm = just("LOADM")
+        .ignore_then(register)
+        .then(register)
+        .map(|(rd, ra)| Instr::LoadMem(rd, ra));
+    let op_storem = just("STOREM")
+        .ignore_then(register)
+        .then(register)
+        .map(|(rs, ra)| Instr::StoreMem(rs, ra));
+
+    let op_mov = just("MOV")
+        .ignore_then(register)
+        .then(register)
+        .map(|(rd, rs)| Instr::Mov(rd, rs));
+    let op_inc = just("INC").ignore_then(register).map(Instr::Inc);
+    let op_dec = just("DEC").ignore_then(register).map(Instr::Dec);
     let op_add = just("ADD")
         .ignore_then(register)
         .then(register)
@@ -45,11 +224,25 @@ pub fn assemble() -> impl Parser<char, Vec<Instr>, Error = Simple<char>> {
         .then(register)
         .then(register)
         .map(|((r1, r2), rd)| Instr::Divide(r1, r2, rd));
+    let op_mod = just("MOD")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::Mod(r1, r2, rd));
+    let op_pow = just("POW")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::Power(r1, r2, rd));
 
     let op_eq = just("EQ")
         .ignore_then(register)
         .then(register)
         .map(|(r1, r2)| Instr::Equal(r1, r2));
+    let op_asserteq = just("ASSERTEQ")
+        .ignore_then(register)
+        .then(register)
+        .map(|(r1, r2)| Instr::AssertEqual(r1, r2));
     let op_gt = just("GT")
         .ignore_then(register)
         .then(register)
@@ -58,18 +251,413 @@ pub fn assemble() -> impl Parser<char, Vec<Instr>, Error = Simple<char>> {
         .ignore_then(register)
         .then(register)
         .map(|(r1, r2)| Instr::GreaterThanEqual(r1, r2));
+    let op_seq = just("SEQ")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::SetEqual(r1, r2, rd));
+    let op_sgt = just("SGT")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::SetGreaterThan(r1, r2, rd));
+    let op_slt = just("SLT")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::SetLessThan(r1, r2, rd));
+
+    let op_cmov = just("CMOV")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::ConditionalMove(r1, r2, rd));
+
+    let op_and = just("AND")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::And(r1, r2, rd));
+    let op_or = just("OR")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::Or(r1, r2, rd));
+    let op_xor = just("XOR")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::Xor(r1, r2, rd));
+    let op_shl = just("SHL")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::Shl(r1, r2, rd));
+    let op_shr = just("SHR")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::Shr(r1, r2, rd));
+    let op_clamp = just("CLAMP")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r, lo), hi)| Instr::Clamp(r, lo, hi));
 
     let op_load = just("LOAD")
         .ignore_then(register)
         .then(value)
         .map(|(r, v)| Instr::Load(r, v));
 
-    let opcodes = choice((
-        op_halt, op_not, op_jmp, op_jmpif, op_add, op_sub, op_mul, op_div, op_eq, op_gt, op_gtq,
-        op_load,
+    let op_fload = just("FLOAD")
+        .ignore_then(register)
+        .then(float)
+        .map(|(r, v)| Instr::FLoad(r, v));
+    let op_fadd = just("FADD")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::FAdd(r1, r2, rd));
+    let op_fsub = just("FSUB")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::FSubtract(r1, r2, rd));
+    let op_fmul = just("FMUL")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::FMultiply(r1, r2, rd));
+    let op_fdiv = just("FDIV")
+        .ignore_then(register)
+        .then(register)
+        .then(register)
+        .map(|((r1, r2), rd)| Instr::FDivide(r1, r2, rd));
+    let op_itof = just("ITOF")
+        .ignore_then(register)
+        .then(register)
+        .map(|(r1, r2)| Instr::IntToFloat(r1, r2));
+    let op_ftoi = just("FTOI")
+        .ignore_then(register)
+        .then(register)
+        .map(|(r1, r2)| Instr::FloatToInt(r1, r2));
+
+    // Grouped because `choice` only takes so many alternatives at once.
+    let integer_ops = choice((
+        op_load, op_mov, op_inc, op_dec, op_add, op_sub, op_mul, op_div, op_mod, op_pow, op_seq,
+        op_sgt, op_slt, op_rdrem, op_rload, op_cmov, op_clamp,
+    ));
+    let float_ops = choice((
+        op_fload, op_fadd, op_fsub, op_fmul, op_fdiv, op_itof, op_ftoi,
+    ));
+    let bitwise_ops = choice((op_and, op_or, op_xor, op_shl, op_shr));
+    let stack_ops = choice((op_push, op_pop, op_peek, op_drop, op_sdup, op_sswap));
+    let memory_ops = choice((op_loadm, op_storem));
+    let jump_ops = choice((op_jmp, op_jmpif, op_jmpf, op_jmpb, op_call));
+    choice((
+        op_halt,
+        op_not,
+        op_nop,
+        op_ret,
+        op_trap,
+        op_igl,
+        jump_ops,
+        op_eq,
+        op_asserteq,
+        op_gt,
+        op_gtq,
+        op_cycles,
+        op_out,
+        op_in,
+        op_rnd,
+        op_getf,
+        integer_ops,
+        float_ops,
+        stack_ops,
+        bitwise_ops,
+        memory_ops,
     ))
-    .then_ignore(just('\n').or_not());
-    opcodes.padded().repeated()
+}
+
+pub fn assemble() -> impl Parser<char, Vec<Instr>, Error = Simple<char>> {
+    // A line that doesn't parse is reported and skipped, so the errors on later lines are
+    // found in the same pass.
+    commented(instruction())
+        .then_ignore(just('\n').or_not())
+        .recover_with(skip_until(['\n'], |_| None))
+        .padded()
+        .repeated()
+        .flatten()
+}
+
+/// `item`, optionally followed by a comment running from `;` to the end of the line. A line
+/// that's only a comment gives `None`.
+fn commented<T: Clone>(
+    item: impl Parser<char, T, Error = Simple<char>>,
+) -> impl Parser<char, Option<T>, Error = Simple<char>> {
+    let comment = just(';')
+        .then(filter(|c: &char| *c != '\n').repeated())
+        .ignored();
+    item.map(Some)
+        .then_ignore(just(' ').repeated().then(comment).or_not())
+        .or(comment.to(None))
+}
+
+/// Like `assemble`, but also accepting label definitions and branches to them. See `expand`.
+// `Simple` is what every parser here fails with, however big it is.
+#[allow(clippy::result_large_err)]
+pub fn assemble_lines() -> impl Parser<char, Vec<Line>, Error = Simple<char>> {
+    // Mnemonics and label names are parsed as whole identifiers before being checked. If the
+    // check fails, the error is put back on the identifier's first character, where it merges
+    // with the error for the line not being an instruction either.
+    let unexpected = |name: &str, span: Range<usize>| {
+        Simple::expected_input_found(span.start..span.start + 1, [], name.chars().next())
+    };
+    let label = text::ident()
+        .then(just(':').or_not())
+        .try_map(move |(name, colon), span| match colon {
+            Some(_) => Ok(Line::Label { name, span }),
+            None => Err(unexpected(&name, span)),
+        });
+    let cond = text::ident().try_map(move |mnemonic: String, span| match mnemonic.as_str() {
+        "BGT" => Ok(Branch::Gt),
+        "BLT" => Ok(Branch::Lt),
+        "BEQ" => Ok(Branch::Eq),
+        "BNE" => Ok(Branch::Ne),
+        _ => Err(unexpected(&mnemonic, span)),
+    });
+    let target = just(' ').ignore_then(text::ident().map_with_span(|label, span| (label, span)));
+    let branch = cond.then(register()).then(register()).then(target).map(
+        |(((cond, a), b), (label, span))| Line::Branch {
+            cond,
+            a,
+            b,
+            label,
+            span,
+        },
+    );
+
+    let kind = text::ident().try_map(move |mnemonic: String, span| match mnemonic.as_str() {
+        "JMP" => Ok(JumpKind::Jmp),
+        "JMPIF" => Ok(JumpKind::JmpIf),
+        "CALL" => Ok(JumpKind::Call),
+        _ => Err(unexpected(&mnemonic, span)),
+    });
+    let jump = kind
+        .then(target)
+        .map(|(kind, (label, span))| Line::Jump { kind, label, span });
+
+    commented(choice((
+        label,
+        branch,
+        jump,
+        instruction().map(Line::Instr),
+    )))
+    .then_ignore(just('\n').or_not())
+    .recover_with(skip_until(['\n'], |_| None))
+    .padded()
+    .repeated()
+    .flatten()
+}
+
+/// Lays out `lines` and replaces their labels with byte offsets. Fails with the span of each
+/// reference to a label that isn't defined, or whose offset doesn't fit in a `LOAD`, and of each
+/// definition of a label after its first.
+pub fn expand(lines: &[Line]) -> Result<Vec<Instr>, Vec<Simple<char>>> {
+    let mut labels = HashMap::new();
+    let mut errors = vec![];
+    let mut offset = 0;
+    for line in lines {
+        match line {
+            Line::Instr(instr) => offset += instr.to_bytes().len(),
+            Line::Label { name, span } => {
+                if labels.insert(name.as_str(), offset).is_some() {
+                    errors.push(Simple::custom(
+                        span.clone(),
+                        format!("label `{}` defined twice", name),
+                    ));
+                }
+            }
+            Line::Branch { cond, a, b, .. } => {
+                let compare: usize = cond
+                    .compare(*a, *b)
+                    .iter()
+                    .map(|i| i.to_bytes().len())
+                    .sum();
+                // Then a LOAD and a JMPIF.
+                offset += compare + 4 + 2;
+            }
+            Line::Jump { .. } => offset += 4 + 2,
+        }
+    }
+
+    let mut target = |label: &str, span: &Range<usize>| match labels.get(label) {
+        Some(&target) => match i16::try_from(target) {
+            Ok(target) => Some(target),
+            Err(_) => {
+                errors.push(Simple::custom(
+                    span.clone(),
+                    format!("label `{}` is too far into the program to jump to", label),
+                ));
+                None
+            }
+        },
+        None => {
+            errors.push(Simple::custom(
+                span.clone(),
+                format!("undefined label `{}`", label),
+            ));
+            None
+        }
+    };
+
+    let mut instrs = vec![];
+    for line in lines {
+        match line {
+            Line::Instr(instr) => instrs.push(*instr),
+            Line::Label { .. } => {}
+            Line::Branch {
+                cond,
+                a,
+                b,
+                label,
+                span,
+            } => {
+                if let Some(target) = target(label, span) {
+                    instrs.extend(cond.compare(*a, *b));
+                    instrs.push(Instr::Load(BRANCH_REGISTER, target));
+                    instrs.push(Instr::JumpIf(BRANCH_REGISTER));
+                }
+            }
+            Line::Jump { kind, label, span } => {
+                if let Some(target) = target(label, span) {
+                    instrs.push(Instr::Load(BRANCH_REGISTER, target));
+                    instrs.push(kind.instr(BRANCH_REGISTER));
+                }
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(instrs)
+    } else {
+        Err(errors)
+    }
+}
+
+/// `Simple`'s `Display` leaves out custom messages, like the ones for out of range numbers, so
+/// this shows those instead.
+pub fn describe_error(err: &Simple<char>) -> String {
+    match err.reason() {
+        error::SimpleReason::Custom(msg) => msg.clone(),
+        _ => err.to_string(),
+    }
+}
+
+/// Reports each of `errors` from parsing `src`, with the line it's on and carets under where it
+/// went wrong:
+///
+/// ```text
+/// error: immediate 99999 out of range for i16
+///  --> 2:10
+///   |
+/// 2 | LOAD $0 #99999
+///   |          ^^^^^
+/// ```
+pub fn render_errors(src: &str, errors: &[Simple<char>]) -> String {
+    let src: Vec<char> = src.chars().collect();
+    errors
+        .iter()
+        .map(|err| render_error(&src, err))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+fn render_error(src: &[char], err: &Simple<char>) -> String {
+    // Spans count chars, and one at the very end points just past the last of them.
+    let start = err.span().start.min(src.len());
+    let line_start = src[..start]
+        .iter()
+        .rposition(|&c| c == '\n')
+        .map_or(0, |i| i + 1);
+    let line_end = src[start..]
+        .iter()
+        .position(|&c| c == '\n')
+        .map_or(src.len(), |i| start + i);
+    let line_number = src[..start].iter().filter(|&&c| c == '\n').count() + 1;
+    let width = err.span().end.min(line_end).saturating_sub(start).max(1);
+
+    let line: String = src[line_start..line_end].iter().collect();
+    // Tabs are kept so that the carets line up however wide they're shown.
+    let indent: String = src[line_start..start]
+        .iter()
+        .map(|&c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let gutter = " ".repeat(line_number.to_string().len());
+    format!(
+        "error: {}\n{gutter}--> {}:{}\n{gutter} |\n{} | {}\n{gutter} | {}{}",
+        describe_error(err),
+        line_number,
+        start - line_start + 1,
+        line_number,
+        line,
+        indent,
+        "^".repeat(width),
+    )
+}
+
+/// Assembles a whole source text to bytecode, failing if any of it doesn't parse. Labels and
+/// branches are allowed, see `assemble_lines`.
+pub fn assemble_bytes(src: &str) -> Result<Vec<u8>, Vec<Simple<char>>> {
+    let lines = assemble_lines().padded().then_ignore(end()).parse(src)?;
+    Ok(expand(&lines)?
+        .into_iter()
+        .flat_map(Instr::to_bytes)
+        .collect())
+}
+
+/// Assembles `src` into the text of a Rust array literal, for embedding a program in Rust
+/// source as a `[u8; N]`.
+pub fn assemble_to_rust_array(src: &str) -> Result<String, Vec<Simple<char>>> {
+    let bytes = assemble_bytes(src)?;
+    let bytes: Vec<_> = bytes.iter().map(u8::to_string).collect();
+    Ok(format!("[{}]", bytes.join(", ")))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub instr: usize,
+    pub register: u8,
+    pub reg_count: usize,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "instruction {} uses ${}, but the target only has {} registers",
+            self.instr, self.register, self.reg_count
+        )
+    }
+}
+
+/// Flags every register operand that would be out of range on a VM with `reg_count` registers.
+pub fn validate_registers(instrs: &[Instr], reg_count: usize) -> Vec<Warning> {
+    instrs
+        .iter()
+        .enumerate()
+        .flat_map(|(i, instr)| {
+            instr
+                .registers()
+                .into_iter()
+                .filter(|&r| r as usize >= reg_count)
+                .map(move |register| Warning {
+                    instr: i,
+                    register,
+                    reg_count,
+                })
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -83,6 +671,12 @@ mod tests {
         assert_eq!(result, vec![Instr::Halt]);
         let result = parser.parse("NOT".to_string()).unwrap();
         assert_eq!(result, vec![Instr::Not]);
+        let result = parser.parse("RET".to_string()).unwrap();
+        assert_eq!(result, vec![Instr::Return]);
+        let result = parser.parse("DROP\nSDUP\nSSWAP".to_string()).unwrap();
+        assert_eq!(result, vec![Instr::Drop, Instr::StackDup, Instr::StackSwap]);
+        let result = parser.parse("TRAP".to_string()).unwrap();
+        assert_eq!(result, vec![Instr::Trap]);
     }
 
     #[test]
@@ -91,6 +685,34 @@ mod tests {
 
         let result = parser.parse("HLT NOT".to_string()).unwrap();
         assert_eq!(result, vec![Instr::Halt, Instr::Not]);
+        let result = parser.parse("NOP".to_string()).unwrap();
+        assert_eq!(result, vec![Instr::Nop]);
+    }
+
+    #[test]
+    fn parse_comments() {
+        let parser = assemble();
+
+        let result = parser.parse("LOAD $0 #5 ; init counter").unwrap();
+        assert_eq!(result, vec![Instr::Load(0, 5)]);
+        let result = parser.parse("; just a comment\nHLT\n  ;another\n").unwrap();
+        assert_eq!(result, vec![Instr::Halt]);
+        let result = parser.parse("NOT ;HLT\n; JMP $0").unwrap();
+        assert_eq!(result, vec![Instr::Not]);
+
+        let lines = assemble_lines()
+            .parse("loop: ; top\nJMP $0 ; back")
+            .unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                Line::Label {
+                    name: "loop".into(),
+                    span: 0..5,
+                },
+                Line::Instr(Instr::Jump(0))
+            ]
+        );
     }
 
     #[test]
@@ -101,6 +723,42 @@ mod tests {
         assert_eq!(result, vec![Instr::Jump(0)]);
         let result = parser.parse("JMPIF $1").unwrap();
         assert_eq!(result, vec![Instr::JumpIf(1)]);
+        let result = parser.parse("JMPF $2").unwrap();
+        assert_eq!(result, vec![Instr::JumpForward(2)]);
+        let result = parser.parse("JMPB $3").unwrap();
+        assert_eq!(result, vec![Instr::JumpBack(3)]);
+        let result = parser.parse("INC $5").unwrap();
+        assert_eq!(result, vec![Instr::Inc(5)]);
+        let result = parser.parse("DEC $6").unwrap();
+        assert_eq!(result, vec![Instr::Dec(6)]);
+        let result = parser.parse("CALL $6").unwrap();
+        assert_eq!(result, vec![Instr::Call(6)]);
+        let result = parser.parse("CYCLES $4").unwrap();
+        assert_eq!(result, vec![Instr::Cycles(4)]);
+        let result = parser.parse("OUT $2").unwrap();
+        assert_eq!(result, vec![Instr::Out(2)]);
+        let result = parser.parse("IN $7").unwrap();
+        assert_eq!(result, vec![Instr::In(7)]);
+        let result = parser.parse("RND $8").unwrap();
+        assert_eq!(result, vec![Instr::Random(8)]);
+        let result = parser.parse("PUSH $1").unwrap();
+        assert_eq!(result, vec![Instr::Push(1)]);
+        let result = parser.parse("POP $2").unwrap();
+        assert_eq!(result, vec![Instr::Pop(2)]);
+        let result = parser.parse("PEEK $3").unwrap();
+        assert_eq!(result, vec![Instr::Peek(3)]);
+        let result = parser.parse("GETF $3").unwrap();
+        assert_eq!(result, vec![Instr::GetFlag(3)]);
+        let result = parser.parse("RDREM $5").unwrap();
+        assert_eq!(result, vec![Instr::ReadRemainder(5)]);
+        let result = parser.parse("RLOAD $1 $2").unwrap();
+        assert_eq!(result, vec![Instr::RegLoad(1, 2)]);
+        let result = parser.parse("LOADM $1 $2").unwrap();
+        assert_eq!(result, vec![Instr::LoadMem(1, 2)]);
+        let result = parser.parse("MOV $3 $4").unwrap();
+        assert_eq!(result, vec![Instr::Mov(3, 4)]);
+        let result = parser.parse("STOREM $3 $4").unwrap();
+        assert_eq!(result, vec![Instr::StoreMem(3, 4)]);
     }
 
     #[test]
@@ -130,6 +788,8 @@ mod tests {
         assert_eq!(result, vec![Instr::GreaterThan(1, 3)]);
         let result = parser.parse("GTQ $2 $0").unwrap();
         assert_eq!(result, vec![Instr::GreaterThanEqual(2, 0)]);
+        let result = parser.parse("ASSERTEQ $4 $5").unwrap();
+        assert_eq!(result, vec![Instr::AssertEqual(4, 5)]);
         let result = parser.parse("LOAD $2 #1").unwrap();
         assert_eq!(result, vec![Instr::Load(2, 1)]);
     }
@@ -167,14 +827,36 @@ mod tests {
                     "LOAD $0 #9
                      LOAD $1 #10
                      LOAD $2 #100
-                     LOAD $3 #-2"
+                     LOAD $3 #-2
+                     LOAD $4 #-32768
+                     LOAD $5 #32767"
                 )
                 .unwrap(),
             vec![
                 Instr::Load(0, 9),
                 Instr::Load(1, 10),
                 Instr::Load(2, 100),
-                Instr::Load(3, -2)
+                Instr::Load(3, -2),
+                Instr::Load(4, i16::MIN),
+                Instr::Load(5, i16::MAX),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_prefixed_immediates() {
+        let parser = assemble();
+
+        assert_eq!(
+            parser
+                .parse("LOAD $0 #0xFF\nLOAD $1 #0b1010\nLOAD $2 #0o17\nLOAD $3 #-0x10\nLOAD $4 #-0x8000")
+                .unwrap(),
+            vec![
+                Instr::Load(0, 255),
+                Instr::Load(1, 10),
+                Instr::Load(2, 15),
+                Instr::Load(3, -16),
+                Instr::Load(4, i16::MIN),
             ]
         );
     }
@@ -189,8 +871,33 @@ mod tests {
         assert_eq!(result, vec![Instr::Subtract(1, 0, 3)]);
         let result = parser.parse("DIV $2 $0 $1").unwrap();
         assert_eq!(result, vec![Instr::Divide(2, 0, 1)]);
+        let result = parser.parse("MOD $2 $0 $1").unwrap();
+        assert_eq!(result, vec![Instr::Mod(2, 0, 1)]);
         let result = parser.parse("MUL $2 $1 $3").unwrap();
         assert_eq!(result, vec![Instr::Multiply(2, 1, 3)]);
+        let result = parser.parse("SEQ $0 $1 $2").unwrap();
+        assert_eq!(result, vec![Instr::SetEqual(0, 1, 2)]);
+        let result = parser.parse("SGT $0 $1 $2").unwrap();
+        assert_eq!(result, vec![Instr::SetGreaterThan(0, 1, 2)]);
+        let result = parser.parse("SLT $0 $1 $2").unwrap();
+        assert_eq!(result, vec![Instr::SetLessThan(0, 1, 2)]);
+        let result = parser.parse("CMOV $0 $1 $2").unwrap();
+        assert_eq!(result, vec![Instr::ConditionalMove(0, 1, 2)]);
+        let result = parser.parse("CLAMP $0 $1 $2").unwrap();
+        assert_eq!(result, vec![Instr::Clamp(0, 1, 2)]);
+        let result = parser
+            .parse("AND $0 $1 $2\nOR $0 $1 $2\nXOR $0 $1 $2\nSHL $0 $1 $2\nSHR $0 $1 $2")
+            .unwrap();
+        assert_eq!(
+            result,
+            vec![
+                Instr::And(0, 1, 2),
+                Instr::Or(0, 1, 2),
+                Instr::Xor(0, 1, 2),
+                Instr::Shl(0, 1, 2),
+                Instr::Shr(0, 1, 2),
+            ]
+        );
     }
 
     #[test]
@@ -214,4 +921,251 @@ mod tests {
             ]
         )
     }
+
+    #[test]
+    fn assemble_whole_source() {
+        assert_eq!(
+            assemble_bytes("LOAD $0 #1\nOUT $0\n"),
+            Ok(vec![1, 0, 0, 1, 21, 0])
+        );
+        assert!(assemble_bytes("LOAD $0 #1\nBOGUS $0").is_err());
+    }
+
+    #[test]
+    fn assemble_rust_array() {
+        assert_eq!(
+            assemble_to_rust_array("LOAD $0 #-2\nOUT $0"),
+            Ok("[1, 0, 255, 254, 21, 0]".to_string())
+        );
+        assert_eq!(assemble_to_rust_array(""), Ok("[]".to_string()));
+    }
+
+    #[test]
+    fn report_every_bad_line() {
+        let errors =
+            assemble_bytes("LOAD $0 #1\nBOGUS $0\nADD $0 $0 $1\nLOAD $1 #x\nHLT").unwrap_err();
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].span(), 11..12);
+        assert_eq!(errors[1].span(), 42..43);
+    }
+
+    #[test]
+    fn out_of_range_operands() {
+        let errs = assemble().parse("LOAD $0 #40000").unwrap_err();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(
+            describe_error(&errs[0]),
+            "immediate 40000 out of range for i16"
+        );
+        assert_eq!(errs[0].span(), 9..14);
+
+        let errs = assemble().parse("LOAD $0 #-32769").unwrap_err();
+        assert_eq!(
+            describe_error(&errs[0]),
+            "immediate -32769 out of range for i16"
+        );
+
+        let errs = assemble().parse("LOAD $0 #0xFFFF").unwrap_err();
+        assert_eq!(
+            describe_error(&errs[0]),
+            "immediate 0xFFFF out of range for i16"
+        );
+
+        let errs = assemble().parse("OUT $256").unwrap_err();
+        assert_eq!(describe_error(&errs[0]), "register 256 out of range for u8");
+    }
+
+    #[test]
+    fn branches_to_labels() {
+        let lines = assemble_lines()
+            .parse("start:\nBGT $0 $1 end\nBNE $0 $1 start\nend:\nHLT")
+            .unwrap();
+        assert_eq!(
+            lines[0],
+            Line::Label {
+                name: "start".into(),
+                span: 0..6,
+            }
+        );
+        assert_eq!(
+            lines[1],
+            Line::Branch {
+                cond: Branch::Gt,
+                a: 0,
+                b: 1,
+                label: "end".into(),
+                span: 17..20,
+            }
+        );
+        assert_eq!(
+            expand(&lines).unwrap(),
+            vec![
+                Instr::GreaterThan(0, 1),
+                Instr::Load(BRANCH_REGISTER, 19),
+                Instr::JumpIf(BRANCH_REGISTER),
+                Instr::Equal(0, 1),
+                Instr::Not,
+                Instr::Load(BRANCH_REGISTER, 0),
+                Instr::JumpIf(BRANCH_REGISTER),
+                Instr::Halt,
+            ]
+        );
+
+        // $0 counts up until it's no longer below $1, skipping the OUT once it gets there.
+        let run = |src| {
+            let mut vm = crate::VM::with_program(assemble_bytes(src).unwrap());
+            vm.capture_output();
+            vm.run().unwrap();
+            (vm.registers[0], vm.take_output())
+        };
+        let src = "LOAD $1 #3\nLOAD $2 #1\nloop:\nADD $0 $2 $0\nBEQ $0 $1 done\nOUT $0\nBLT $0 $1 loop\ndone:\nHLT";
+        assert_eq!(run(src), (3, "1\n2\n".to_string()));
+
+        let errs = assemble_bytes("BGT $0 $1 nowhere\nHLT").unwrap_err();
+        assert_eq!(describe_error(&errs[0]), "undefined label `nowhere`");
+        assert_eq!(errs[0].span(), 10..17);
+
+        let errs = assemble_bytes("loop:\nHLT\nloop:\nBEQ $0 $0 loop").unwrap_err();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(describe_error(&errs[0]), "label `loop` defined twice");
+        assert_eq!(errs[0].span(), 10..15);
+    }
+
+    #[test]
+    fn jumps_to_labels() {
+        // Jumps forward over `half`, then loops back, calling it each time round.
+        let src = "JMP start\nhalf:\nOUT $0\nRET\nstart:\nLOAD $1 #1\nLOAD $2 #3\nloop:\nADD $0 $1 $0\nCALL half\nGT $2 $0\nJMPIF loop\nHLT";
+        let lines = assemble_lines().parse(src).unwrap();
+        assert_eq!(
+            lines[0],
+            Line::Jump {
+                kind: JumpKind::Jmp,
+                label: "start".into(),
+                span: 4..9,
+            }
+        );
+        assert_eq!(
+            expand(&lines).unwrap()[..2],
+            [
+                Instr::Load(BRANCH_REGISTER, 9),
+                Instr::Jump(BRANCH_REGISTER)
+            ]
+        );
+
+        let mut vm = crate::VM::with_program(assemble_bytes(src).unwrap());
+        vm.capture_output();
+        assert_eq!(vm.run(), Ok(crate::RunOutcome::Halted));
+        assert_eq!(vm.take_output(), "1\n2\n3\n");
+
+        let errs = assemble_bytes("JMP nowhere").unwrap_err();
+        assert_eq!(describe_error(&errs[0]), "undefined label `nowhere`");
+        assert_eq!(errs[0].span(), 4..11);
+    }
+
+    #[test]
+    fn validate_register_count() {
+        let parser = assemble();
+
+        let program = parser
+            .parse(
+                r#"LOAD $0 #1
+                   LOAD $100 #2
+                   ADD $0 $100 $3"#,
+            )
+            .unwrap();
+
+        assert_eq!(validate_registers(&program, 256), vec![]);
+        assert_eq!(
+            validate_registers(&program, 16),
+            vec![
+                Warning {
+                    instr: 1,
+                    register: 100,
+                    reg_count: 16
+                },
+                Warning {
+                    instr: 2,
+                    register: 100,
+                    reg_count: 16
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn render_error_reports() {
+        let src = "HLT\nLOAD $0 #99999\nNOT";
+        let errs = assemble_bytes(src).unwrap_err();
+        assert_eq!(
+            render_errors(src, &errs),
+            "\
+error: immediate 99999 out of range for i16
+ --> 2:10
+  |
+2 | LOAD $0 #99999
+  |          ^^^^^"
+        );
+
+        // An error at the very end points just past the last character.
+        let src = "ADD $0 $1";
+        let errs = assemble_bytes(src).unwrap_err();
+        let report = render_errors(src, &errs);
+        assert!(
+            report.ends_with("1 | ADD $0 $1\n  |          ^"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn parse_char_immediates() {
+        let parser = assemble();
+
+        let result = parser.parse("LOAD $0 #'A'\nLOAD $1 #'\\n'").unwrap();
+        assert_eq!(result, vec![Instr::Load(0, 65), Instr::Load(1, 10)]);
+        let result = parser.parse(r"LOAD $2 #'\t' LOAD $3 #'\0' LOAD $4 #'\'' LOAD $5 #' '");
+        assert_eq!(
+            result.unwrap(),
+            vec![
+                Instr::Load(2, 9),
+                Instr::Load(3, 0),
+                Instr::Load(4, 39),
+                Instr::Load(5, 32)
+            ]
+        );
+
+        assert!(parser.parse("LOAD $0 #'AB'").is_err());
+        assert!(parser.parse("LOAD $0 #'\u{1F600}'").is_err());
+    }
+
+    #[test]
+    fn parse_float_ops() {
+        let parser = assemble();
+
+        assert_eq!(
+            parser
+                .parse(
+                    r#"FLOAD $0 #1.5
+                       FLOAD $1 #-2
+                       FADD $0 $1 $2
+                       FSUB $0 $1 $2
+                       FMUL $0 $1 $2
+                       FDIV $0 $1 $2
+                       ITOF $3 $0
+                       FTOI $0 $3"#,
+                )
+                .unwrap(),
+            vec![
+                Instr::FLoad(0, 1.5),
+                Instr::FLoad(1, -2.0),
+                Instr::FAdd(0, 1, 2),
+                Instr::FSubtract(0, 1, 2),
+                Instr::FMultiply(0, 1, 2),
+                Instr::FDivide(0, 1, 2),
+                Instr::IntToFloat(3, 0),
+                Instr::FloatToInt(0, 3),
+            ]
+        );
+    }
 }
@@ -0,0 +1,452 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{arithmetic::ArithmeticMode, opcode::instructions::Instr, Reg};
+
+/// The integer register an instruction writes to, if any.
+fn written_register(instr: Instr) -> Option<u8> {
+    use Instr::*;
+    match instr {
+        Load(r, _)
+        | LoadMem(r, _)
+        | Mov(r, _)
+        | Inc(r)
+        | Dec(r)
+        | Cycles(r)
+        | In(r)
+        | Random(r)
+        | GetFlag(r)
+        | ReadRemainder(r)
+        | Pop(r)
+        | Peek(r)
+        | Clamp(r, _, _) => Some(r),
+        Add(_, _, r)
+        | Subtract(_, _, r)
+        | Multiply(_, _, r)
+        | Divide(_, _, r)
+        | Mod(_, _, r)
+        | Power(_, _, r)
+        | And(_, _, r)
+        | Or(_, _, r)
+        | Xor(_, _, r)
+        | Shl(_, _, r)
+        | Shr(_, _, r)
+        | SetEqual(_, _, r)
+        | SetGreaterThan(_, _, r)
+        | SetLessThan(_, _, r)
+        | ConditionalMove(_, _, r)
+        | RegLoad(_, r)
+        | FloatToInt(_, r) => Some(r),
+        Halt | Nop | Jump(_) | JumpIf(_) | JumpForward(_) | JumpBack(_) | Call(_) | Return
+        | Trap | Out(_) | Push(_) | Drop | StackDup | StackSwap | Equal(..) | AssertEqual(..)
+        | Not | GreaterThan(..) | GreaterThanEqual(..) | FLoad(..) | FAdd(..) | FSubtract(..)
+        | FMultiply(..) | FDivide(..) | IntToFloat(..) | StoreMem(..) | Illegal => None,
+    }
+}
+
+/// The integer registers an instruction reads. `RLOAD` also reads whichever register its index
+/// names, which can't be known statically, so only the index register is counted.
+fn read_registers(instr: Instr) -> Vec<u8> {
+    use Instr::*;
+    match instr {
+        Jump(r)
+        | JumpIf(r)
+        | JumpForward(r)
+        | JumpBack(r)
+        | Call(r)
+        | Inc(r)
+        | Dec(r)
+        | Out(r)
+        | Push(r)
+        | RegLoad(r, _)
+        | IntToFloat(r, _)
+        | LoadMem(_, r)
+        | Mov(_, r) => vec![r],
+        StoreMem(s, a) => vec![s, a],
+        Equal(a, b) | AssertEqual(a, b) | GreaterThan(a, b) | GreaterThanEqual(a, b) => vec![a, b],
+        Clamp(r, lo, hi) => vec![r, lo, hi],
+        Add(a, b, _)
+        | Subtract(a, b, _)
+        | Multiply(a, b, _)
+        | Divide(a, b, _)
+        | Mod(a, b, _)
+        | Power(a, b, _)
+        | And(a, b, _)
+        | Or(a, b, _)
+        | Xor(a, b, _)
+        | Shl(a, b, _)
+        | Shr(a, b, _)
+        | SetEqual(a, b, _)
+        | SetGreaterThan(a, b, _)
+        | SetLessThan(a, b, _)
+        | ConditionalMove(a, b, _) => vec![a, b],
+        Halt | Nop | Load(..) | Cycles(_) | In(_) | Random(_) | GetFlag(_) | ReadRemainder(_)
+        | Pop(_) | Peek(_) | Return | Trap | Drop | StackDup | StackSwap | Not | FLoad(..)
+        | FAdd(..) | FSubtract(..) | FMultiply(..) | FDivide(..) | FloatToInt(..) | Illegal => {
+            vec![]
+        }
+    }
+}
+
+/// Where each integer register is written and read, as indices into the instructions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DefUse {
+    pub writes: BTreeMap<u8, Vec<usize>>,
+    pub reads: BTreeMap<u8, Vec<usize>>,
+}
+
+impl DefUse {
+    pub fn writes(&self, register: u8) -> &[usize] {
+        self.writes.get(&register).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn reads(&self, register: u8) -> &[usize] {
+        self.reads.get(&register).map_or(&[], Vec::as_slice)
+    }
+
+    /// Registers that are written somewhere but never read.
+    pub fn unread(&self) -> BTreeSet<u8> {
+        self.writes
+            .keys()
+            .filter(|r| !self.reads.contains_key(r))
+            .copied()
+            .collect()
+    }
+}
+
+/// Collects which instructions write and read each integer register. An instruction whose
+/// destination is also one of its sources, like `ADD $0 $1 $0`, shows up in both.
+pub fn def_use(instrs: &[Instr]) -> DefUse {
+    let mut def_use = DefUse::default();
+    for (i, &instr) in instrs.iter().enumerate() {
+        let mut reads = read_registers(instr);
+        reads.dedup();
+        for r in reads {
+            def_use.reads.entry(r).or_default().push(i);
+        }
+        if let Some(r) = written_register(instr) {
+            def_use.writes.entry(r).or_default().push(i);
+        }
+    }
+    def_use
+}
+
+/// Appends `b` to `a`, moving jump targets in `b` along by the byte length of `a`.
+///
+/// Jumps go through a register, so the target that gets rebased is the `LOAD` which last wrote
+/// that register before the jump. Targets computed any other way (arithmetic, or a load that
+/// sits after the jump in a loop) can't be followed and are left as they are, as are targets
+/// that would no longer fit in the 16-bit immediate.
+pub fn concat_programs(mut a: Vec<Instr>, mut b: Vec<Instr>) -> Vec<Instr> {
+    let offset: usize = a.iter().map(|i| i.to_bytes().len()).sum();
+    relocate(&mut b, offset);
+    a.append(&mut b);
+    a
+}
+
+/// Moves the jump targets in `instrs` along by `offset` bytes, for code that's going to be
+/// placed that far into a program. Targets are found as in `concat_programs`.
+pub fn relocate(instrs: &mut [Instr], offset: usize) {
+    let Ok(offset) = i16::try_from(offset) else {
+        return;
+    };
+
+    let mut rebased = vec![false; instrs.len()];
+    for jump in 0..instrs.len() {
+        let (Instr::Jump(target) | Instr::JumpIf(target) | Instr::Call(target)) = instrs[jump]
+        else {
+            continue;
+        };
+        let load = instrs[..jump]
+            .iter()
+            .rposition(|&i| written_register(i) == Some(target));
+        if let Some(load) = load {
+            if let Instr::Load(r, v) = instrs[load] {
+                if !rebased[load] {
+                    if let Some(v) = v.checked_add(offset) {
+                        instrs[load] = Instr::Load(r, v);
+                        rebased[load] = true;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Replaces arithmetic on registers with known values by a `LOAD` of the result. Values are
+/// known from earlier `LOAD`s and folded instructions, and forgotten at every jump target and
+/// after `CALL`, `RET` and `TRAP`. `DIV` also writes the remainder, so it's never folded.
+///
+/// Every `LOAD` into a register that a jump goes through counts as a jump target, wherever it
+/// is, since control flow can reach the jump from any of them. If a jump register is written
+/// by anything other than a `LOAD`, or there are relative jumps, the program is returned as it
+/// is, since any instruction might be jumped to. Only
+/// instructions the same length as a `LOAD` are replaced, so no offsets move. Shorter ones like
+/// `INC` and `MOV` are kept, but their results are still known afterwards.
+pub fn propagate_constants(instrs: &[Instr]) -> Vec<Instr> {
+    let mut offsets = Vec::with_capacity(instrs.len());
+    let mut offset = 0;
+    for instr in instrs {
+        offsets.push(offset);
+        offset += instr.to_bytes().len();
+    }
+
+    let mut jump_registers = BTreeSet::new();
+    for &instr in instrs {
+        match instr {
+            Instr::Jump(r) | Instr::JumpIf(r) | Instr::Call(r) => jump_registers.insert(r),
+            Instr::JumpForward(_) | Instr::JumpBack(_) => return instrs.to_vec(),
+            _ => continue,
+        };
+    }
+    let mut targets = BTreeSet::new();
+    for &instr in instrs {
+        match instr {
+            Instr::Load(r, target) if jump_registers.contains(&r) => {
+                targets.insert(target as usize);
+            }
+            _ if written_register(instr).is_some_and(|r| jump_registers.contains(&r)) => {
+                return instrs.to_vec();
+            }
+            _ => {}
+        }
+    }
+
+    let mode = ArithmeticMode::Checked;
+    let mut known: [Option<Reg>; 256] = [None; 256];
+    let mut folded = Vec::with_capacity(instrs.len());
+    for (&instr, offset) in instrs.iter().zip(offsets) {
+        if targets.contains(&offset) {
+            known = [None; 256];
+        }
+        let value = |r: u8| known[r as usize];
+        let binop = |a, b| Some((value(a)?, value(b)?));
+        let result = match instr {
+            Instr::Load(_, v) => Some(v as Reg),
+            Instr::Add(a, b, _) => binop(a, b).and_then(|(a, b)| mode.add(a, b)),
+            Instr::Mov(_, s) => value(s),
+            Instr::Inc(r) => value(r).and_then(|v| mode.add(v, 1)),
+            Instr::Dec(r) => value(r).and_then(|v| mode.sub(v, 1)),
+            Instr::Subtract(a, b, _) => binop(a, b).and_then(|(a, b)| mode.sub(a, b)),
+            Instr::Multiply(a, b, _) => binop(a, b).and_then(|(a, b)| mode.mul(a, b)),
+            Instr::Mod(a, b, _) => {
+                binop(a, b).and_then(|(a, b)| mode.rem(a, (b != 0).then_some(b)?))
+            }
+            Instr::Power(a, b, _) => binop(a, b).and_then(|(a, b)| mode.pow(a, b.try_into().ok()?)),
+            Instr::And(a, b, _) => binop(a, b).map(|(a, b)| a & b),
+            Instr::Or(a, b, _) => binop(a, b).map(|(a, b)| a | b),
+            Instr::Xor(a, b, _) => binop(a, b).map(|(a, b)| a ^ b),
+            Instr::Shl(a, b, _) => binop(a, b).map(|(a, b)| a << (b & 63)),
+            Instr::Shr(a, b, _) => binop(a, b).map(|(a, b)| ((a as u64) >> (b & 63)) as Reg),
+            Instr::SetEqual(a, b, _) => binop(a, b).map(|(a, b)| (a == b) as Reg),
+            Instr::SetGreaterThan(a, b, _) => binop(a, b).map(|(a, b)| (a > b) as Reg),
+            Instr::SetLessThan(a, b, _) => binop(a, b).map(|(a, b)| (a < b) as Reg),
+            _ => None,
+        };
+
+        match (written_register(instr), result) {
+            (Some(dest), Some(v)) => {
+                known[dest as usize] = Some(v);
+                match i16::try_from(v) {
+                    Ok(v) if instr.to_bytes().len() == 4 => folded.push(Instr::Load(dest, v)),
+                    _ => folded.push(instr),
+                }
+            }
+            (Some(dest), None) => {
+                known[dest as usize] = None;
+                folded.push(instr);
+            }
+            (None, _) => folded.push(instr),
+        }
+        if matches!(instr, Instr::Call(_) | Instr::Return | Instr::Trap) {
+            known = [None; 256];
+        }
+    }
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{builder::ProgramBuilder, VM};
+
+    #[test]
+    fn concat_rebases_jumps() {
+        let a = ProgramBuilder::new()
+            .load(0, 1)
+            .load(1, 2)
+            .add(0, 1, 2)
+            .build();
+        // Jumps over the LOAD of 99, to byte 10 of this fragment.
+        let b = ProgramBuilder::new()
+            .load(5, 10)
+            .jmp(5)
+            .load(2, 99)
+            .load(4, 1)
+            .build();
+
+        let joined = concat_programs(a, b);
+        assert_eq!(joined[3], Instr::Load(5, 22));
+
+        let mut vm = VM::with_program(joined.into_iter().flat_map(Instr::to_bytes).collect());
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2], 3);
+        assert_eq!(vm.registers[4], 1);
+    }
+
+    #[test]
+    fn concat_leaves_computed_targets() {
+        let a = ProgramBuilder::new().load(0, 1).build();
+        let b = ProgramBuilder::new()
+            .load(0, 2)
+            .load(1, 2)
+            .add(0, 1, 2)
+            .jmp(2)
+            .build();
+
+        assert_eq!(concat_programs(a, b.clone())[1..], b[..]);
+    }
+
+    fn run(instrs: Vec<Instr>) -> [Reg; 256] {
+        let mut vm = VM::with_program(instrs.into_iter().flat_map(Instr::to_bytes).collect());
+        vm.run().unwrap();
+        vm.registers
+    }
+
+    #[test]
+    fn propagate_straight_line() {
+        let program = ProgramBuilder::new()
+            .load(0, 2)
+            .load(1, 3)
+            .add(0, 1, 2)
+            .mul(2, 2, 3)
+            .out(3)
+            .pop(0)
+            .sub(0, 1, 4)
+            .load(5, 300)
+            .mul(5, 5, 6)
+            .build();
+        let folded = propagate_constants(&program);
+
+        assert_eq!(folded[2], Instr::Load(2, 5));
+        assert_eq!(folded[3], Instr::Load(3, 25));
+        // Nothing is known about what POP gives back, or 90000 doesn't fit in a LOAD.
+        assert_eq!(folded[6], Instr::Subtract(0, 1, 4));
+        assert_eq!(folded[8], Instr::Multiply(5, 5, 6));
+
+        let straight = [program[..4].to_vec(), program[7..].to_vec()].concat();
+        assert_eq!(run(propagate_constants(&straight)), run(straight));
+    }
+
+    #[test]
+    fn propagate_stops_at_jump_targets() {
+        let program = ProgramBuilder::new()
+            .load(0, 2)
+            .load(1, 3)
+            .add(0, 1, 4)
+            .load(5, 16)
+            .add(0, 1, 2)
+            .jmpif(5)
+            .build();
+        let folded = propagate_constants(&program);
+
+        assert_eq!(folded[2], Instr::Load(4, 5));
+        assert_eq!(folded[4], Instr::Add(0, 1, 2));
+        assert_eq!(run(folded), run(program));
+
+        // The target of JMP $2 is computed, so it could be anywhere.
+        let computed = ProgramBuilder::new()
+            .load(0, 2)
+            .load(1, 3)
+            .add(0, 1, 2)
+            .jmp(2)
+            .build();
+        assert_eq!(propagate_constants(&computed), computed);
+    }
+
+    #[test]
+    fn propagate_keeps_offsets_after_short_instructions() {
+        // INC and MOV are shorter than a LOAD, so replacing them would move the JMP's target.
+        let program = ProgramBuilder::new()
+            .load(0, 1)
+            .inc(0)
+            .mov(5, 0)
+            .add(0, 5, 4)
+            .load(1, 23)
+            .jmp(1)
+            .load(2, 99)
+            .load(3, 7)
+            .halt()
+            .build();
+        let folded = propagate_constants(&program);
+
+        assert_eq!(folded[1], Instr::Inc(0));
+        assert_eq!(folded[2], Instr::Mov(5, 0));
+        assert_eq!(folded[3], Instr::Load(4, 4));
+        let registers = run(folded);
+        assert_eq!(registers, run(program));
+        assert_eq!(registers[..6], [2, 23, 0, 7, 4, 2]);
+    }
+
+    #[test]
+    fn propagate_sees_targets_loaded_on_other_paths() {
+        // The JMPIF goes to the JMP $5 while $5 still holds 12, so the ADD runs a second time
+        // with $0 = 5, even though the last LOAD into $5 before the JMP is of 41.
+        let program = ProgramBuilder::new()
+            .load(0, 1)
+            .load(9, 0)
+            .load(5, 12)
+            .add(0, 0, 1)
+            .load(0, 5)
+            .load(8, 0)
+            .eq(9, 8)
+            .inc(9)
+            .load(6, 39)
+            .jmpif(6)
+            .load(5, 41)
+            .jmp(5)
+            .halt()
+            .build();
+        let folded = propagate_constants(&program);
+
+        assert_eq!(folded[3], Instr::Add(0, 0, 1));
+        let registers = run(folded);
+        assert_eq!(registers, run(program));
+        assert_eq!(registers[1], 10);
+    }
+
+    #[test]
+    fn propagate_gives_up_on_computed_jump_registers() {
+        let program = ProgramBuilder::new()
+            .load(0, 2)
+            .load(1, 3)
+            .add(0, 1, 2)
+            .mul(2, 2, 3)
+            .load(4, 20)
+            .inc(4)
+            .jmp(4)
+            .build();
+
+        assert_eq!(propagate_constants(&program), program);
+    }
+
+    #[test]
+    fn def_use_of_small_program() {
+        let program = ProgramBuilder::new()
+            .load(0, 1)
+            .load(1, 2)
+            .add(0, 1, 0)
+            .mul(0, 0, 2)
+            .load(3, 7)
+            .out(2)
+            .build();
+        let du = def_use(&program);
+
+        assert_eq!(du.writes(0), [0, 2]);
+        assert_eq!(du.reads(0), [2, 3]);
+        assert_eq!(du.reads(1), [2]);
+        assert_eq!(du.writes(2), [3]);
+        assert_eq!(du.reads(2), [5]);
+        assert!(du.reads(4).is_empty());
+        assert_eq!(du.unread(), BTreeSet::from([3]));
+    }
+}